@@ -1,21 +1,79 @@
 mod ui;
 
-use shared::{ChatResponse, ChatCommand};
+use shared::{
+    mentions, parse_duration, ChatResponse, ChatCommand, ErrorCode, ErrorResponse, Message, ModerationAction,
+    ReadConsistency, Retention, RoomChange, RoomInfo, RoomRole,
+};
 use shared::channel::ChatClientChannel;
+use shared::fault::FaultPolicy;
 use tracing::{info, error};
 use eyre::Result;
 use tracing_subscriber::layer::SubscriberExt;
-use ui::{ChatUI, UIMessage, UIController};
+use ui::{reaction_counts, ChatUI, DeliveryStatus, UIMessage, UIController};
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::io::Write;
 use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tracing_appender::rolling::{RollingFileAppender, Rotation};
 use tracing_subscriber::{fmt, util::SubscriberInitExt};
 use std::fs;
-use chrono::Utc;
+
+// Node connected to unless --server says otherwise
+const DEFAULT_SERVER: &str = "127.0.0.1:8080";
+
+// Room joined on startup
+const DEFAULT_ROOM: &str = "general";
+
+// Messages requested per history page
+const HISTORY_PAGE_SIZE: usize = 50;
+
+// Messages shown leading up to a search result
+const CONTEXT_SIZE: usize = 20;
+
+// How long a sent message may wait for its Ack before it is marked failed
+const ACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Where the secret that proves our nicks are ours is kept, under the home directory
+const SECRET_FILE: &str = ".raft-chat-secret";
+
+// A message the server has not acknowledged yet
+struct PendingMessage {
+    // Room it was written in, which /retry sends it to even after we moved on
+    room: String,
+    reply_to: Option<u64>,
+    content: String,
+    sent_at: Instant,
+    failed: bool,
+}
 
 // Struct to hold the client state
 struct ChatClientState {
     client: ChatClientChannel,
     ui_controller: UIController,
+    nick: String,
+    // Sent along with every nick we take; whoever takes a nick first owns it
+    secret: String,
+    // Room that plain messages are posted to
+    room: String,
+    // Room asked for with /join, entered once the server says we are in it
+    joining: Option<String>,
+    // Oldest message ID fetched per room, used to page further back with /history
+    oldest_seen: HashMap<String, u64>,
+    // Message whose surrounding history was requested by jumping from a search result
+    awaiting_context: Option<u64>,
+    // Settings of the current room as last heard, so changes to them can be announced
+    room_info: Option<RoomInfo>,
+    // Set while /topic or /mode without arguments waits for the room's settings
+    awaiting_room_info: bool,
+    next_msg_id: u64,
+    pending: HashMap<u64, PendingMessage>,
+    // Consistency requested for history, search and other queries, set with /read
+    consistency: ReadConsistency,
+    // Newest log index we know of; reads ask for a view at least this fresh so our own
+    // writes are visible whichever node answers
+    seen_index: u64,
 }
 
 // Function to set up logging
@@ -47,37 +105,168 @@ fn setup_logging() -> Result<()> {
 
 // Function to handle server events
 async fn handle_server_event(state: &mut ChatClientState, event: ChatResponse) -> Result<bool> {
+    if let ChatResponse::History { applied_index, .. } | ChatResponse::SearchResults { applied_index, .. } = &event {
+        state.seen_index = state.seen_index.max(*applied_index);
+    }
+
     match event {
         ChatResponse::MessageReceived(msg) => {
-            let _ = state.ui_controller.send_message(UIMessage {
-                content: msg.content,
-                timestamp: Utc::now(),
-            }).await;
+            state.seen_index = state.seen_index.max(msg.id);
+            let mentions_me = mentions_me(state, &msg);
+            if mentions_me {
+                // Seen live, so it should not come back as unread on the next connect
+                mark_mentions_read(state, msg.id).await;
+            }
+            let _ = state.ui_controller.send_message(UIMessage { mentions_me, ..UIMessage::chat(&msg) }).await;
+        }
+        ChatResponse::NickChanged(nick) => {
+            if nick != state.nick {
+                let _ = state.ui_controller.send_message(UIMessage::new(format!("You are now known as {}", nick))).await;
+                state.nick = nick;
+            }
+        }
+        ChatResponse::MessageEdited { id, new_content, .. } => {
+            let _ = state.ui_controller.edit_message(id, new_content).await;
+        }
+        ChatResponse::MessageDeleted { id, .. } => {
+            let _ = state.ui_controller.delete_message(id).await;
+        }
+        ChatResponse::ReactionsUpdated { id, reactions, .. } => {
+            let _ = state.ui_controller.update_reactions(id, reaction_counts(&reactions)).await;
+        }
+        ChatResponse::History { thread: Some(root), messages, .. } => {
+            let page = chat_messages(state, &messages);
+            let _ = state.ui_controller.show_thread(root, page).await;
+        }
+        ChatResponse::History { thread: None, messages, .. } if state.awaiting_context.is_some() => {
+            let id = state.awaiting_context.take().unwrap_or_default();
+            let page = chat_messages(state, &messages);
+            let _ = state.ui_controller.show_context(id, page).await;
+        }
+        ChatResponse::History { room, thread: None, messages, has_more, .. } => {
+            if let Some(oldest) = messages.first() {
+                state.oldest_seen.insert(room.clone(), oldest.id);
+            }
+            let mut page = chat_messages(state, &messages);
+            if has_more {
+                page.insert(0, UIMessage::new(format!("-- older messages in {} available with /history --", room)));
+            }
+            let _ = state.ui_controller.show_history(page).await;
+        }
+        ChatResponse::Mentions { messages, unread } => {
+            if unread && let Some(newest) = messages.last() {
+                mark_mentions_read(state, newest.id).await;
+            }
+            let page = messages.iter().map(UIMessage::chat).collect();
+            let _ = state.ui_controller.show_mentions(page, unread).await;
         }
+        ChatResponse::SearchResults { query, messages, .. } => {
+            let page = chat_messages(state, &messages);
+            let _ = state.ui_controller.show_search_results(query, page).await;
+        }
+        ChatResponse::Members { room, members } => {
+            let text = format!("Members of {}: {}", room, members.join(", "));
+            let _ = state.ui_controller.send_message(UIMessage::new(text)).await;
+        }
+        ChatResponse::Moderated(entry) => {
+            let removed = matches!(entry.action, ModerationAction::Kick | ModerationAction::Ban { .. });
+            if removed && entry.nick == state.nick && entry.room == state.room && state.room != DEFAULT_ROOM {
+                enter_room(state, DEFAULT_ROOM).await;
+                let text = format!("{} -- you are back in {}", entry, DEFAULT_ROOM);
+                let _ = state.ui_controller.send_message(UIMessage::new(text)).await;
+                let query = ChatCommand::RoomInfo { room: DEFAULT_ROOM.to_string(), consistency: state.consistency };
+                if let Err(e) = state.client.send_command(query).await {
+                    error!("Failed to ask for the settings of {}: {}", DEFAULT_ROOM, e);
+                }
+            } else {
+                let _ = state.ui_controller.send_message(UIMessage::new(entry.to_string())).await;
+            }
+        }
+        // Admin commands are only taken on the admin port, so their answers never come here.
+        ChatResponse::Admin(_) => {}
+        ChatResponse::Kicked { reason } => {
+            error!("Kicked by an operator: {}", reason);
+            return Ok(false);
+        }
+        ChatResponse::ServerShutdown { reconnect_to: Some(addr) } => {
+            info!("Server is shutting down, reconnecting to {}", addr);
+            let _ = state.ui_controller.send_message(UIMessage::new(format!("Server shutting down, moving to {}", addr))).await;
+            if let Err(e) = reconnect(state, &addr).await {
+                error!("Failed to reconnect to {}: {}", addr, e);
+                let _ = state.ui_controller.send_message(UIMessage::new(format!("Could not reconnect: {}", e))).await;
+                return Ok(false);
+            }
+        }
+        ChatResponse::ServerShutdown { reconnect_to: None } => {
+            error!("Server is shutting down with nowhere to reconnect to");
+            return Ok(false);
+        }
+        // Announced only when asked for or when they change, not on joining
+        ChatResponse::RoomInfo(info) if info.room == state.room => {
+            let _ = state.ui_controller.set_topic(info.room.clone(), info.settings.topic.clone()).await;
+            let changed = state.room_info.as_ref().is_some_and(|old| old.settings != info.settings);
+            if std::mem::take(&mut state.awaiting_room_info) || changed {
+                let _ = state.ui_controller.send_message(UIMessage::new(describe_room(&info))).await;
+            }
+            state.room_info = Some(info);
+        }
+        ChatResponse::RoomInfo(_) => {}
         ChatResponse::Joined(user) => {
-            let _ = state.ui_controller.send_message(UIMessage {
-                content: format!("User {} joined the chat", user),
-                timestamp: Utc::now(),
-            }).await;
+            let _ = state.ui_controller.send_message(UIMessage::new(format!("User {} joined the chat", user))).await;
+            if user == state.nick {
+                if let Some(room) = state.joining.take() {
+                    enter_room(state, &room).await;
+                }
+                fetch_history(state, None).await;
+            }
         }
         ChatResponse::Left(user) => {
-            let _ = state.ui_controller.send_message(UIMessage {
-                content: format!("User {} left the chat", user),
-                timestamp: Utc::now(),
-            }).await;
+            let _ = state.ui_controller.send_message(UIMessage::new(format!("User {} left the chat", user))).await;
+        }
+        ChatResponse::Ack { client_msg_id, committed_index } => {
+            info!("Message {} committed at index {}", client_msg_id, committed_index);
+            state.seen_index = state.seen_index.max(committed_index);
+            if state.pending.remove(&client_msg_id).is_some() {
+                let _ = state.ui_controller.confirm_message(client_msg_id, committed_index).await;
+            }
         }
         ChatResponse::Error(e) => {
-            error!("Server error: {}", e);
-            return Ok(false); // Stop the loop on server error
+            // Errors other than those for messages do not say what they answer, so any of
+            // them may be the join being refused, which leaves us where we were
+            match e.client_msg_id {
+                Some(client_msg_id) => mark_failed(state, client_msg_id).await,
+                None => state.joining = None,
+            }
+            show_error(state, e).await;
         }
     }
     Ok(true) // Continue the loop
 }
 
+// Tells the user why a command failed and whether trying again may help. None of these
+// end the session: if the server is going away, the connection closing says so.
+async fn show_error(state: &mut ChatClientState, error: ErrorResponse) {
+    error!("Server error: {:?}", error);
+    let text = match error.code {
+        ErrorCode::NotLeader { .. } => {
+            format!("{}; unacknowledged messages can be resent with /retry", error.details)
+        }
+        ErrorCode::RateLimited { retry_after_ms } => {
+            format!("Slow down: try again in {:.1}s", retry_after_ms as f64 / 1000.0)
+        }
+        ErrorCode::Unauthorized
+        | ErrorCode::NoSuchRoom
+        | ErrorCode::Banned
+        | ErrorCode::InvalidCommand
+        | ErrorCode::Internal => error.details,
+    };
+    let _ = state.ui_controller.send_message(UIMessage::new(text)).await;
+}
+
 // Function to handle user messages/commands
 async fn handle_user_message(state: &mut ChatClientState, message: String) -> Result<bool> {
-    if message.starts_with('/') {
-        let parts: Vec<&str> = message[1..].splitn(2, ' ').collect();
+    if let Some(command_line) = message.strip_prefix('/') {
+        let parts: Vec<&str> = command_line.splitn(2, ' ').collect();
         let command = parts[0];
         let args = parts.get(1).unwrap_or(&"");
 
@@ -86,55 +275,462 @@ async fn handle_user_message(state: &mut ChatClientState, message: String) -> Re
                 if let Err(e) = state.client.send_command(ChatCommand::Join(args.to_string())).await {
                     error!("Failed to send join command: {}", e);
                     // Optionally notify the UI about the failure
-                    let _ = state.ui_controller.send_message(UIMessage {
-                        content: format!("Error joining: {}", e),
-                        timestamp: Utc::now(),
-                    }).await;
+                    let _ = state.ui_controller.send_message(UIMessage::new(format!("Error joining: {}", e))).await;
+                } else {
+                    state.joining = Some(args.to_string());
                 }
             }
             "leave" => {
                 if let Err(e) = state.client.send_command(ChatCommand::Leave(args.to_string())).await {
                     error!("Failed to send leave command: {}", e);
                      // Optionally notify the UI about the failure
-                    let _ = state.ui_controller.send_message(UIMessage {
-                        content: format!("Error leaving: {}", e),
-                        timestamp: Utc::now(),
-                    }).await;
+                    let _ = state.ui_controller.send_message(UIMessage::new(format!("Error leaving: {}", e))).await;
+                }
+            }
+            "nick" => {
+                let command = ChatCommand::Nick { nick: args.to_string(), secret: state.secret.clone() };
+                if let Err(e) = state.client.send_command(command).await {
+                    error!("Failed to send nick command: {}", e);
+                }
+            }
+            "edit" => {
+                let (id, new_content) = args.split_once(' ').unwrap_or((args, ""));
+                match id.parse::<u64>() {
+                    Ok(id) if !new_content.is_empty() => {
+                        let command = ChatCommand::EditMessage { id, new_content: new_content.to_string() };
+                        if let Err(e) = state.client.send_command(command).await {
+                            error!("Failed to send edit command: {}", e);
+                        }
+                    }
+                    _ => {
+                        let _ = state.ui_controller.send_message(UIMessage::new("Usage: /edit <message id> <new content>".to_string())).await;
+                    }
+                }
+            }
+            "delete" => {
+                match args.trim().parse::<u64>() {
+                    Ok(id) => {
+                        if let Err(e) = state.client.send_command(ChatCommand::DeleteMessage { id }).await {
+                            error!("Failed to send delete command: {}", e);
+                        }
+                    }
+                    Err(_) => {
+                        let _ = state.ui_controller.send_message(UIMessage::new("Usage: /delete <message id>".to_string())).await;
+                    }
+                }
+            }
+            "react" | "unreact" => {
+                let (id, emoji) = args.split_once(' ').unwrap_or((args, ""));
+                match id.parse::<u64>() {
+                    Ok(message_id) if !emoji.trim().is_empty() => {
+                        let emoji = emoji.trim().to_string();
+                        let command = if command == "react" {
+                            ChatCommand::React { message_id, emoji }
+                        } else {
+                            ChatCommand::Unreact { message_id, emoji }
+                        };
+                        if let Err(e) = state.client.send_command(command).await {
+                            error!("Failed to send reaction: {}", e);
+                        }
+                    }
+                    _ => {
+                        let _ = state.ui_controller.send_message(UIMessage::new(format!("Usage: /{} <message id> <emoji>", command))).await;
+                    }
+                }
+            }
+            "reply" => {
+                let (id, content) = args.split_once(' ').unwrap_or((args, ""));
+                match id.parse::<u64>() {
+                    Ok(id) if !content.is_empty() => send_chat_message(state, Some(id), content.to_string()).await,
+                    _ => {
+                        let _ = state.ui_controller.send_message(UIMessage::new("Usage: /reply <message id> <message>".to_string())).await;
+                    }
+                }
+            }
+            "thread" => {
+                match args.trim().parse::<u64>() {
+                    Ok(root) => {
+                        let command = ChatCommand::FetchHistory {
+                            room: state.room.clone(),
+                            before: None,
+                            limit: HISTORY_PAGE_SIZE,
+                            thread: Some(root),
+                            consistency: state.consistency,
+                            min_index: Some(state.seen_index),
+                        };
+                        if let Err(e) = state.client.send_command(command).await {
+                            error!("Failed to fetch thread: {}", e);
+                        }
+                    }
+                    Err(_) => {
+                        let _ = state.ui_controller.send_message(UIMessage::new("Usage: /thread <message id>".to_string())).await;
+                    }
+                }
+            }
+            "mentions" => {
+                let command = ChatCommand::FetchMentions { limit: HISTORY_PAGE_SIZE, consistency: state.consistency };
+                if let Err(e) = state.client.send_command(command).await {
+                    error!("Failed to fetch mentions: {}", e);
+                }
+            }
+            "search" => {
+                match parse_search(state, args) {
+                    Some(command) => {
+                        if let Err(e) = state.client.send_command(command).await {
+                            error!("Failed to send search: {}", e);
+                        }
+                    }
+                    None => {
+                        let _ = state.ui_controller.send_message(UIMessage::new("Usage: /search <query> [in:#room] [from:nick]".to_string())).await;
+                    }
+                }
+            }
+            "context" => {
+                let (room, id) = args.split_once(' ').unwrap_or(("", args));
+                if let Ok(id) = id.trim().parse::<u64>() {
+                    state.awaiting_context = Some(id);
+                    let command = ChatCommand::FetchHistory {
+                        room: room.to_string(),
+                        before: Some(id + 1),
+                        limit: CONTEXT_SIZE,
+                        thread: None,
+                        consistency: state.consistency,
+                        min_index: Some(state.seen_index),
+                    };
+                    if let Err(e) = state.client.send_command(command).await {
+                        error!("Failed to fetch context: {}", e);
+                    }
+                }
+            }
+            "topic" | "mode" if args.trim().is_empty() => {
+                state.awaiting_room_info = true;
+                let query = ChatCommand::RoomInfo { room: state.room.clone(), consistency: state.consistency };
+                if let Err(e) = state.client.send_command(query).await {
+                    error!("Failed to ask for room settings: {}", e);
+                }
+            }
+            "topic" | "mode" => {
+                let change = match command {
+                    "topic" => Some(RoomChange::Topic(args.trim().to_string())),
+                    _ => parse_mode(args),
+                };
+                match change {
+                    Some(change) => {
+                        let command = ChatCommand::UpdateRoom { room: state.room.clone(), change };
+                        if let Err(e) = state.client.send_command(command).await {
+                            error!("Failed to change room settings: {}", e);
+                        }
+                    }
+                    None => {
+                        let usage = "Usage: /mode [invite on|off | limit <n>|off | retention <n>|<duration>|off \
+                                     | description <text>]";
+                        let _ = state.ui_controller.send_message(UIMessage::new(usage.to_string())).await;
+                    }
+                }
+            }
+            "kick" | "ban" | "unban" | "mute" | "unmute" | "op" | "deop" | "invite" => {
+                match parse_moderation(state, command, args) {
+                    Some(command) => {
+                        if let Err(e) = state.client.send_command(command).await {
+                            error!("Failed to send moderation command: {}", e);
+                        }
+                    }
+                    None => {
+                        let usage = match command {
+                            "ban" | "mute" => format!("Usage: /{} <nick> [duration like 10m or 2h] [reason]", command),
+                            "kick" => "Usage: /kick <nick> [reason]".to_string(),
+                            _ => format!("Usage: /{} <nick>", command),
+                        };
+                        let _ = state.ui_controller.send_message(UIMessage::new(usage)).await;
+                    }
+                }
+            }
+            "who" => {
+                let room = match args.trim() {
+                    "" => state.room.clone(),
+                    room => room.trim_start_matches('#').to_string(),
+                };
+                if let Err(e) = state.client.send_command(ChatCommand::Who { room, consistency: state.consistency }).await {
+                    error!("Failed to list members: {}", e);
+                }
+            }
+            "read" => {
+                let consistency = match args.trim() {
+                    "linearizable" => Some(ReadConsistency::Linearizable),
+                    "lease" => Some(ReadConsistency::Lease),
+                    "stale" => Some(ReadConsistency::Stale),
+                    _ => None,
+                };
+                let text = match consistency {
+                    Some(consistency) => {
+                        state.consistency = consistency;
+                        format!("Queries now use {} reads", args.trim())
+                    }
+                    None => "Usage: /read <linearizable|lease|stale>".to_string(),
+                };
+                let _ = state.ui_controller.send_message(UIMessage::new(text)).await;
+            }
+            "history" => {
+                let before = state.oldest_seen.get(&state.room).copied();
+                fetch_history(state, before).await;
+            }
+            "retry" => {
+                match args.trim().parse::<u64>() {
+                    Ok(client_msg_id) => retry_message(state, client_msg_id).await,
+                    Err(_) => {
+                        let _ = state.ui_controller.send_message(UIMessage::new("Usage: /retry <message id>".to_string())).await;
+                    }
                 }
             }
             "quit" => {
                 info!("Quitting chat client via /quit command...");
-                let _ = state.ui_controller.send_message(UIMessage {
-                    content: "Shutting down...".to_string(),
-                    timestamp: Utc::now(),
-                }).await;
+                let _ = state.ui_controller.send_message(UIMessage::new("Shutting down...".to_string())).await;
                 return Ok(false); // Signal to stop the loop
             }
             _ => {
-                let _ = state.ui_controller.send_message(UIMessage {
-                    content: format!("Unknown command: /{}", command),
-                    timestamp: Utc::now(),
-                }).await;
+                let _ = state.ui_controller.send_message(UIMessage::new(format!("Unknown command: /{}", command))).await;
             }
         }
     } else {
         // Send regular message
-        if let Err(e) = state.client.send_message(&message).await {
-            error!("Failed to send message: {}", e);
-             // Optionally notify the UI about the failure
-             let _ = state.ui_controller.send_message(UIMessage {
-                content: format!("Error sending message: {}", e),
-                timestamp: Utc::now(),
-            }).await;
-        }
+        send_chat_message(state, None, message).await;
     }
     Ok(true) // Continue the loop
 }
 
+// Moves to another node, identifying ourselves and rejoining the current room there.
+// Messages still waiting for an Ack expire as usual and can be sent again with /retry.
+async fn reconnect(state: &mut ChatClientState, addr: &str) -> Result<()> {
+    let mut client = ChatClientChannel::connect(addr).await?;
+    client.send_command(ChatCommand::Nick { nick: state.nick.clone(), secret: state.secret.clone() }).await?;
+    client.send_command(ChatCommand::Join(state.room.clone())).await?;
+    state.client = client;
+    Ok(())
+}
+
+// Shows a message as pending and sends it to the current room
+async fn send_chat_message(state: &mut ChatClientState, reply_to: Option<u64>, content: String) {
+    let client_msg_id = state.next_msg_id;
+    state.next_msg_id += 1;
+
+    let _ = state.ui_controller.send_message(UIMessage::pending(client_msg_id, state.nick.clone(), reply_to, content.clone())).await;
+    state.pending.insert(client_msg_id, PendingMessage {
+        room: state.room.clone(),
+        reply_to,
+        content,
+        sent_at: Instant::now(),
+        failed: false,
+    });
+    send_pending(state, client_msg_id).await;
+}
+
+// Splits `/search` arguments into the query and its `in:` and `from:` filters
+fn parse_search(state: &ChatClientState, args: &str) -> Option<ChatCommand> {
+    let mut room = None;
+    let mut from = None;
+    let mut terms = Vec::new();
+
+    for word in args.split_whitespace() {
+        if let Some(r) = word.strip_prefix("in:") {
+            room = Some(r.trim_start_matches('#').to_string());
+        } else if let Some(f) = word.strip_prefix("from:") {
+            from = Some(f.trim_start_matches('@').to_string());
+        } else {
+            terms.push(word);
+        }
+    }
+
+    if terms.is_empty() {
+        return None;
+    }
+
+    Some(ChatCommand::Search {
+        query: terms.join(" "),
+        room,
+        from,
+        limit: HISTORY_PAGE_SIZE,
+        consistency: state.consistency,
+        min_index: Some(state.seen_index),
+    })
+}
+
+// Builds a moderation command against a member of the current room
+fn parse_moderation(state: &ChatClientState, command: &str, args: &str) -> Option<ChatCommand> {
+    let mut words = args.split_whitespace().peekable();
+    let nick = words.next()?.trim_start_matches('@').to_string();
+
+    // Bans and mutes last for good unless a duration comes first
+    let duration_secs = match command {
+        "ban" | "mute" => words.peek().and_then(|word| parse_duration(word)),
+        _ => None,
+    };
+    if duration_secs.is_some() {
+        words.next();
+    }
+
+    let action = match command {
+        "kick" => ModerationAction::Kick,
+        "ban" => ModerationAction::Ban { duration_secs },
+        "unban" => ModerationAction::Unban,
+        "mute" => ModerationAction::Mute { duration_secs },
+        "unmute" => ModerationAction::Unmute,
+        "op" => ModerationAction::SetRole(RoomRole::Operator),
+        "deop" => ModerationAction::SetRole(RoomRole::Member),
+        "invite" => ModerationAction::Invite,
+        _ => return None,
+    };
+    let reason = words.collect::<Vec<_>>().join(" ");
+    Some(ChatCommand::Moderate { room: state.room.clone(), nick, action, reason })
+}
+
+// Parses `/mode` arguments such as `invite on`, `limit 20`, `retention 7d` or
+// `description <text>`
+fn parse_mode(args: &str) -> Option<RoomChange> {
+    let (setting, value) = args.trim().split_once(' ').unwrap_or((args.trim(), ""));
+    let value = value.trim();
+    match (setting, value) {
+        ("invite", "on") => Some(RoomChange::InviteOnly(true)),
+        ("invite", "off") => Some(RoomChange::InviteOnly(false)),
+        ("limit", "off") => Some(RoomChange::MaxMembers(None)),
+        ("limit", count) => count.parse().ok().map(|count| RoomChange::MaxMembers(Some(count))),
+        ("retention", "off") => Some(RoomChange::Retention(Retention::Forever)),
+        ("retention", keep) => match keep.parse() {
+            Ok(count) => Some(RoomChange::Retention(Retention::Messages(count))),
+            Err(_) => parse_duration(keep).map(|secs| RoomChange::Retention(Retention::MaxAge { secs })),
+        },
+        ("description", text) => Some(RoomChange::Description(text.to_string())),
+        _ => None,
+    }
+}
+
+// Sums up a room's settings on one line
+fn describe_room(info: &RoomInfo) -> String {
+    let settings = &info.settings;
+    let mut text = format!("#{} is run by {}", info.room, info.owner);
+    if !info.operators.is_empty() {
+        text.push_str(&format!(" with {}", info.operators.join(", ")));
+    }
+    match settings.max_members {
+        Some(max) => text.push_str(&format!(", has {} of at most {} members", info.members, max)),
+        None => text.push_str(&format!(", has {} members", info.members)),
+    }
+    if settings.invite_only {
+        text.push_str(", is invite-only");
+    }
+    text.push_str(&format!(" and keeps messages {}", match settings.retention {
+        Retention::Forever => "forever".to_string(),
+        Retention::Messages(count) => format!("up to the last {}", count),
+        Retention::MaxAge { .. } => format!("for {}", settings.retention),
+    }));
+    if !settings.topic.is_empty() {
+        text.push_str(&format!(". Topic: {}", settings.topic));
+    }
+    if !settings.description.is_empty() {
+        text.push_str(&format!(". {}", settings.description));
+    }
+    text
+}
+
+// Makes `room` the one plain messages go to; its topic arrives once the server answers
+async fn enter_room(state: &mut ChatClientState, room: &str) {
+    state.room = room.to_string();
+    state.room_info = None;
+    let _ = state.ui_controller.set_topic(state.room.clone(), String::new()).await;
+}
+
+fn mentions_me(state: &ChatClientState, message: &Message) -> bool {
+    message.sender != state.nick && mentions(&message.content).any(|nick| nick == state.nick)
+}
+
+// Converts messages from the server, highlighting the ones that mention us
+fn chat_messages(state: &ChatClientState, messages: &[Message]) -> Vec<UIMessage> {
+    messages.iter()
+        .map(|m| UIMessage { mentions_me: mentions_me(state, m), ..UIMessage::chat(m) })
+        .collect()
+}
+
+async fn mark_mentions_read(state: &mut ChatClientState, up_to: u64) {
+    if let Err(e) = state.client.send_command(ChatCommand::MarkMentionsRead { up_to }).await {
+        error!("Failed to mark mentions as read: {}", e);
+    }
+}
+
+// Requests a page of the current room's history older than `before`
+async fn fetch_history(state: &mut ChatClientState, before: Option<u64>) {
+    let command = ChatCommand::FetchHistory {
+        room: state.room.clone(),
+        before,
+        limit: HISTORY_PAGE_SIZE,
+        thread: None,
+        consistency: state.consistency,
+        min_index: Some(state.seen_index),
+    };
+    if let Err(e) = state.client.send_command(command).await {
+        error!("Failed to fetch history: {}", e);
+    }
+}
+
+// Writes a pending message to the socket, marking it failed if that is not possible
+async fn send_pending(state: &mut ChatClientState, client_msg_id: u64) {
+    let Some(pending) = state.pending.get(&client_msg_id) else {
+        return;
+    };
+
+    if let Err(e) = state.client.send_message(client_msg_id, &pending.room, pending.reply_to, &pending.content).await {
+        error!("Failed to send message: {}", e);
+        mark_failed(state, client_msg_id).await;
+    }
+}
+
+async fn retry_message(state: &mut ChatClientState, client_msg_id: u64) {
+    let Some(pending) = state.pending.get_mut(&client_msg_id) else {
+        let _ = state.ui_controller.send_message(UIMessage::new(format!("No failed message with id {}", client_msg_id))).await;
+        return;
+    };
+
+    pending.sent_at = Instant::now();
+    pending.failed = false;
+    let _ = state.ui_controller.set_delivery_status(client_msg_id, DeliveryStatus::Pending).await;
+    send_pending(state, client_msg_id).await;
+}
+
+// Message IDs start from the clock so a restarted client does not reuse the IDs of
+// messages it sent before, which the server would take for resent ones.
+fn first_msg_id() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(1, |since| since.as_micros() as u64)
+}
+
+async fn mark_failed(state: &mut ChatClientState, client_msg_id: u64) {
+    if let Some(pending) = state.pending.get_mut(&client_msg_id) {
+        pending.failed = true;
+        let _ = state.ui_controller.set_delivery_status(client_msg_id, DeliveryStatus::Failed).await;
+    }
+}
+
+// Marks messages that were not acknowledged within ACK_TIMEOUT as failed
+async fn expire_pending(state: &mut ChatClientState) {
+    let expired: Vec<u64> = state.pending.iter()
+        .filter(|(_, pending)| !pending.failed && pending.sent_at.elapsed() > ACK_TIMEOUT)
+        .map(|(id, _)| *id)
+        .collect();
+
+    for client_msg_id in expired {
+        info!("Message {} was not acknowledged in time", client_msg_id);
+        mark_failed(state, client_msg_id).await;
+    }
+}
+
 // Main event loop logic
 async fn run_event_loop(mut client_state: ChatClientState) {
+    let mut ack_check = tokio::time::interval(Duration::from_secs(1));
+
     loop {
         tokio::select! {
+            // Expire messages that were never acknowledged
+            _ = ack_check.tick() => {
+                expire_pending(&mut client_state).await;
+            }
+
             // Handle server messages
             result = client_state.client.receive_event() => {
                 match result {
@@ -148,10 +744,7 @@ async fn run_event_loop(mut client_state: ChatClientState) {
                     Err(e) => {
                         error!("Error receiving event from server channel: {}", e);
                         // Attempt to inform the UI before breaking
-                         let _ = client_state.ui_controller.send_message(UIMessage {
-                            content: format!("Connection error: {}", e),
-                            timestamp: Utc::now(),
-                        }).await;
+                         let _ = client_state.ui_controller.send_message(UIMessage::new(format!("Connection error: {}", e))).await;
                         break; // Exit loop on channel receive error
                     }
                 }
@@ -180,6 +773,30 @@ async fn run_event_loop(mut client_state: ChatClientState) {
     // The loop finishes, the task completes.
 }
 
+// Reads our secret from the home directory, making up one the first time
+fn load_secret() -> Result<String> {
+    let home = std::env::var("HOME").map_err(|_| eyre::eyre!("HOME is not set; pass --secret instead"))?;
+    let path = PathBuf::from(home).join(SECRET_FILE);
+    if let Ok(secret) = fs::read_to_string(&path) {
+        return Ok(secret.trim().to_string());
+    }
+
+    // RandomState keys come from the OS's random source, so their hashes make a secret
+    // nobody can guess.
+    let secret: String = (0..4).map(|_| format!("{:016x}", RandomState::new().hash_one(0))).collect();
+    let mut options = fs::OpenOptions::new();
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .and_then(|mut file| file.write_all(secret.as_bytes()))
+        .map_err(|e| eyre::eyre!("failed to save secret to {}: {}", path.display(), e))?;
+    info!("Saved a new secret to {}", path.display());
+    Ok(secret)
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     // Setup logging first
@@ -190,9 +807,45 @@ async fn main() -> Result<()> {
     // Install custom panic and error hooks
     color_eyre::install()?;
     
-    let client_channel = ChatClientChannel::connect("127.0.0.1:8080")
+    // Command line: [--server ADDR] [--faults SPEC] [--secret SECRET] [NICK]
+    let mut server = DEFAULT_SERVER.to_string();
+    let mut nick = None;
+    let mut secret = std::env::var("RAFT_CHAT_SECRET").ok();
+    let mut faults: Option<FaultPolicy> = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--server" => server = args.next().ok_or_else(|| eyre::eyre!("--server needs an address"))?,
+            "--faults" => {
+                let spec = args.next().ok_or_else(|| eyre::eyre!("--faults needs a value"))?;
+                faults = Some(spec.parse()?);
+            }
+            "--secret" => secret = Some(args.next().ok_or_else(|| eyre::eyre!("--secret needs a value"))?),
+            _ => nick = Some(arg),
+        }
+    }
+
+    let mut client_channel = ChatClientChannel::connect(&server)
         .await
-        .map_err(|e| eyre::eyre!("failed to connect to chat server: {}", e))?;
+        .map_err(|e| eyre::eyre!("failed to connect to chat server at {}: {}", server, e))?;
+    if let Some(policy) = faults {
+        info!("Injecting faults into the connection: {:?}", policy);
+        client_channel = client_channel.with_faults(policy);
+    }
+
+    // Identify ourselves and join the default room
+    let nick = nick
+        .or_else(|| std::env::var("USER").ok())
+        .unwrap_or_else(|| "anonymous".to_string());
+    let room = DEFAULT_ROOM.to_string();
+    let secret = match secret {
+        Some(secret) => secret,
+        None => load_secret()?,
+    };
+    client_channel.send_command(ChatCommand::Nick { nick: nick.clone(), secret: secret.clone() }).await
+        .map_err(|e| eyre::eyre!("failed to set nick: {}", e))?;
+    client_channel.send_command(ChatCommand::Join(room.clone())).await
+        .map_err(|e| eyre::eyre!("failed to join {}: {}", room, e))?;
 
     // Create and initialize the UI
     let (mut ui, ui_controller) = ChatUI::new()?;
@@ -201,6 +854,19 @@ async fn main() -> Result<()> {
     let client_state = ChatClientState { // No longer mutable here
         client: client_channel,
         ui_controller: ui_controller.clone(), // Clone for the event loop task
+        nick,
+        secret,
+        room,
+        joining: None,
+        oldest_seen: HashMap::new(),
+        awaiting_context: None,
+        room_info: None,
+        awaiting_room_info: false,
+        next_msg_id: first_msg_id(),
+        pending: HashMap::new(),
+        // Any node may answer; seen_index still guarantees we read our own writes
+        consistency: ReadConsistency::Stale,
+        seen_index: 0,
     };
 
     // Spawn the main event loop task using the new function
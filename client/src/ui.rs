@@ -2,12 +2,14 @@ use crossterm::{
     cursor::{Hide, Show, MoveTo},
     event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyModifiers},
     execute,
-    style::Print,
+    style::{Attribute, Color, Print, ResetColor, SetAttribute, SetForegroundColor},
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen, Clear, ClearType, size},
     tty::IsTty,
 };
 use eyre::Result;
+use shared::Message;
 use std::{
+    collections::{BTreeMap, BTreeSet},
     io::{self, Stdout, Write}, mem, sync::Arc, time::Duration, sync::Mutex
 };
 use tokio::sync::{broadcast, mpsc, oneshot};
@@ -15,14 +17,175 @@ use tracing::error;
 
 const MAX_MESSAGES: usize = 1000;
 
-#[derive(Debug)]
+/// Delivery state of a message typed by the local user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryStatus {
+    /// Written to the socket, waiting for the server to acknowledge the commit.
+    Pending,
+    Confirmed,
+    Failed,
+}
+
+#[derive(Debug, Clone)]
 pub struct UIMessage {
     pub content: String,
     pub timestamp: chrono::DateTime<chrono::Utc>,
+    pub sender: Option<String>,
+    /// Server-assigned message ID, known once the message has been committed.
+    pub id: Option<u64>,
+    pub client_msg_id: Option<u64>,
+    pub status: DeliveryStatus,
+    pub edited: bool,
+    /// Number of users that reacted with each emoji.
+    pub reactions: BTreeMap<String, usize>,
+    /// Root of the thread this message replies to.
+    pub reply_to: Option<u64>,
+    pub room: Option<String>,
+    /// Highlighted because it mentions the local user.
+    pub mentions_me: bool,
+}
+
+impl UIMessage {
+    pub fn new(content: String) -> Self {
+        Self {
+            content,
+            timestamp: chrono::Utc::now(),
+            sender: None,
+            id: None,
+            client_msg_id: None,
+            status: DeliveryStatus::Confirmed,
+            edited: false,
+            reactions: BTreeMap::new(),
+            reply_to: None,
+            room: None,
+            mentions_me: false,
+        }
+    }
+
+    /// A chat message posted by another user.
+    pub fn chat(message: &Message) -> Self {
+        Self {
+            sender: Some(message.sender.clone()),
+            id: Some(message.id),
+            edited: message.edited,
+            reactions: reaction_counts(&message.reactions),
+            reply_to: message.reply_to,
+            room: Some(message.room.clone()),
+            ..Self::new(message.content.clone())
+        }
+    }
+
+    /// A message sent by the local user that has not been acknowledged yet.
+    pub fn pending(client_msg_id: u64, sender: String, reply_to: Option<u64>, content: String) -> Self {
+        Self {
+            sender: Some(sender),
+            client_msg_id: Some(client_msg_id),
+            reply_to,
+            status: DeliveryStatus::Pending,
+            ..Self::new(content)
+        }
+    }
+
+    // Messages the local user sent from this client and that have been committed
+    fn is_editable(&self) -> bool {
+        self.client_msg_id.is_some() && self.id.is_some()
+    }
+}
+
+pub fn reaction_counts(reactions: &BTreeMap<String, BTreeSet<String>>) -> BTreeMap<String, usize> {
+    reactions.iter().map(|(emoji, users)| (emoji.clone(), users.len())).collect()
+}
+
+#[derive(Debug)]
+pub enum UIEvent {
+    Message(UIMessage),
+    DeliveryStatus { client_msg_id: u64, status: DeliveryStatus },
+    Confirmed { client_msg_id: u64, id: u64 },
+    Edited { id: u64, content: String },
+    Deleted { id: u64 },
+    Reactions { id: u64, reactions: BTreeMap<String, usize> },
+    /// Older messages to show above the ones already displayed, oldest first.
+    History(Vec<UIMessage>),
+    /// Opens the thread view with a root message followed by its replies.
+    Thread { root: u64, messages: Vec<UIMessage> },
+    /// Opens the mentions view; `unread` marks mentions received while offline.
+    Mentions { messages: Vec<UIMessage>, unread: bool },
+    /// Opens the search results pane, newest match first.
+    SearchResults { query: String, messages: Vec<UIMessage> },
+    /// Shows the messages leading up to message `id`.
+    Context { id: u64, messages: Vec<UIMessage> },
+    /// Sets the room and topic shown on the top line.
+    Topic { room: String, topic: String },
+}
+
+// What a panel opened on top of the main message list shows
+enum PanelKind {
+    Thread(u64),
+    Mentions { unread: bool },
+    Search { query: String },
+    Context(u64),
+}
+
+struct Panel {
+    kind: PanelKind,
+    messages: Vec<UIMessage>,
+    /// Highlighted row in the search results
+    selected: Option<usize>,
+}
+
+impl Panel {
+    fn thread_root(&self) -> Option<u64> {
+        match self.kind {
+            PanelKind::Thread(root) => Some(root),
+            _ => None,
+        }
+    }
+
+    fn new(kind: PanelKind, messages: Vec<UIMessage>) -> Self {
+        let selected = match kind {
+            PanelKind::Search { .. } if !messages.is_empty() => Some(0),
+            _ => None,
+        };
+        Self { kind, messages, selected }
+    }
+
+    fn title(&self) -> String {
+        match &self.kind {
+            PanelKind::Thread(root) => format!("Thread #{} (Esc to return)", root),
+            PanelKind::Mentions { unread: true } => "Mentions while you were away (Esc to return)".to_string(),
+            PanelKind::Mentions { unread: false } => "Recent mentions (Esc to return)".to_string(),
+            PanelKind::Search { query } => format!(
+                "{} results for \"{}\" (Up/Down to select, Enter to jump, Esc to return)",
+                self.messages.len(), query
+            ),
+            PanelKind::Context(id) => format!("Message #{} in context (Esc to return)", id),
+        }
+    }
+
+    fn is_search(&self) -> bool {
+        matches!(self.kind, PanelKind::Search { .. })
+    }
+
+    fn is_highlighted(&self, index: usize, message: &UIMessage) -> bool {
+        match self.kind {
+            PanelKind::Context(id) => message.id == Some(id),
+            _ => self.selected == Some(index),
+        }
+    }
+
+    fn move_selection(&mut self, up: bool) {
+        if let Some(selected) = self.selected {
+            self.selected = Some(if up {
+                selected.saturating_sub(1)
+            } else {
+                (selected + 1).min(self.messages.len() - 1)
+            });
+        }
+    }
 }
 
 pub struct UIController {
-    message_tx: mpsc::Sender<UIMessage>,
+    message_tx: mpsc::Sender<UIEvent>,
     user_message_tx: broadcast::Sender<String>,
     user_message_rx: broadcast::Receiver<String>,
     shutdown_tx: Arc<Mutex<Option<oneshot::Sender<()>>>>,
@@ -41,7 +204,55 @@ impl Clone for UIController {
 
 impl UIController {
     pub async fn send_message(&self, message: UIMessage) -> Result<()> {
-        self.message_tx.send(message).await
+        self.send_event(UIEvent::Message(message)).await
+    }
+
+    pub async fn set_delivery_status(&self, client_msg_id: u64, status: DeliveryStatus) -> Result<()> {
+        self.send_event(UIEvent::DeliveryStatus { client_msg_id, status }).await
+    }
+
+    pub async fn confirm_message(&self, client_msg_id: u64, id: u64) -> Result<()> {
+        self.send_event(UIEvent::Confirmed { client_msg_id, id }).await
+    }
+
+    pub async fn edit_message(&self, id: u64, content: String) -> Result<()> {
+        self.send_event(UIEvent::Edited { id, content }).await
+    }
+
+    pub async fn delete_message(&self, id: u64) -> Result<()> {
+        self.send_event(UIEvent::Deleted { id }).await
+    }
+
+    pub async fn update_reactions(&self, id: u64, reactions: BTreeMap<String, usize>) -> Result<()> {
+        self.send_event(UIEvent::Reactions { id, reactions }).await
+    }
+
+    pub async fn show_history(&self, messages: Vec<UIMessage>) -> Result<()> {
+        self.send_event(UIEvent::History(messages)).await
+    }
+
+    pub async fn show_thread(&self, root: u64, messages: Vec<UIMessage>) -> Result<()> {
+        self.send_event(UIEvent::Thread { root, messages }).await
+    }
+
+    pub async fn show_mentions(&self, messages: Vec<UIMessage>, unread: bool) -> Result<()> {
+        self.send_event(UIEvent::Mentions { messages, unread }).await
+    }
+
+    pub async fn show_search_results(&self, query: String, messages: Vec<UIMessage>) -> Result<()> {
+        self.send_event(UIEvent::SearchResults { query, messages }).await
+    }
+
+    pub async fn show_context(&self, id: u64, messages: Vec<UIMessage>) -> Result<()> {
+        self.send_event(UIEvent::Context { id, messages }).await
+    }
+
+    pub async fn set_topic(&self, room: String, topic: String) -> Result<()> {
+        self.send_event(UIEvent::Topic { room, topic }).await
+    }
+
+    async fn send_event(&self, event: UIEvent) -> Result<()> {
+        self.message_tx.send(event).await
             .map_err(|e| eyre::eyre!("Failed to send message: {}", e))
     }
 
@@ -67,8 +278,13 @@ impl UIController {
 pub struct ChatUI {
     messages: Vec<UIMessage>,
     input_buffer: String,
+    /// ID of the message being edited in the input line, if any.
+    editing: Option<u64>,
+    panel: Option<Panel>,
+    /// Room being chatted in and its topic, shown on the top line.
+    topic: Option<(String, String)>,
     stdout: Stdout,
-    message_rx: mpsc::Receiver<UIMessage>,
+    message_rx: mpsc::Receiver<UIEvent>,
     shutdown_rx: oneshot::Receiver<()>,
     user_message_tx: broadcast::Sender<String>,
 }
@@ -93,6 +309,9 @@ impl ChatUI {
         Ok((Self {
             messages: Vec::with_capacity(MAX_MESSAGES),
             input_buffer: String::new(),
+            editing: None,
+            panel: None,
+            topic: None,
             stdout,
             message_rx,
             shutdown_rx,
@@ -123,20 +342,48 @@ impl ChatUI {
                         KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
                             break;
                         }
+                        KeyCode::Char('r') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                            self.retry_failed();
+                        }
                         KeyCode::Char(c) => {
                             self.input_buffer.push(c);
                         }
                         KeyCode::Backspace => {
                             self.input_buffer.pop();
                         }
-                        KeyCode::Enter => {
-                            if !self.input_buffer.is_empty() {
-                                let message = mem::take(&mut self.input_buffer);
-                                if let Err(e) = self.user_message_tx.send(message.clone()) {
-                                    error!("Failed to send message: {}", e);
-                                }
+                        KeyCode::Up | KeyCode::Down if self.panel.as_ref().is_some_and(Panel::is_search) => {
+                            if let Some(panel) = &mut self.panel {
+                                panel.move_selection(key.code == KeyCode::Up);
                             }
                         }
+                        KeyCode::Enter if self.input_buffer.is_empty() && self.panel.as_ref().is_some_and(Panel::is_search) => {
+                            self.jump_to_selected();
+                        }
+                        KeyCode::Up if self.panel.is_none() && (self.input_buffer.is_empty() || self.editing.is_some()) => {
+                            self.select_previous_own_message();
+                        }
+                        KeyCode::Enter if self.editing.is_some() => {
+                            self.submit_edit();
+                        }
+                        KeyCode::Enter if !self.input_buffer.is_empty() => {
+                            let mut message = mem::take(&mut self.input_buffer);
+                            // Plain messages typed in the thread view are replies to it
+                            if let Some(root) = self.panel.as_ref().and_then(Panel::thread_root)
+                                && !message.starts_with('/')
+                            {
+                                message = format!("/reply {} {}", root, message);
+                            }
+                            if let Err(e) = self.user_message_tx.send(message.clone()) {
+                                error!("Failed to send message: {}", e);
+                            }
+                        }
+                        KeyCode::Esc if self.editing.is_some() => {
+                            self.editing = None;
+                            self.input_buffer.clear();
+                        }
+                        KeyCode::Esc if self.panel.is_some() => {
+                            self.panel = None;
+                        }
                         KeyCode::Esc => break,
                         _ => {}
                     }
@@ -144,13 +391,69 @@ impl ChatUI {
             } 
 
             // check if the shutdown signal has been sent
-            if let Ok(_) = self.shutdown_rx.try_recv() {
+            if self.shutdown_rx.try_recv().is_ok() {
                 break;
             }
 
             // Check for new messages
-            while let Ok(message) = self.message_rx.try_recv() {
-                self.push_message(message);
+            while let Ok(event) = self.message_rx.try_recv() {
+                match event {
+                    UIEvent::Message(message) => {
+                        if let Some(panel) = &mut self.panel
+                            && message.reply_to.is_some()
+                            && message.reply_to == panel.thread_root()
+                        {
+                            panel.messages.push(message.clone());
+                        }
+                        self.push_message(message);
+                    }
+                    UIEvent::DeliveryStatus { client_msg_id, status } => {
+                        self.set_delivery_status(client_msg_id, status);
+                    }
+                    UIEvent::Confirmed { client_msg_id, id } => {
+                        for message in self.all_messages_mut().filter(|m| m.client_msg_id == Some(client_msg_id)) {
+                            message.status = DeliveryStatus::Confirmed;
+                            message.id = Some(id);
+                        }
+                    }
+                    UIEvent::Edited { id, content } => {
+                        for message in self.all_messages_mut().filter(|m| m.id == Some(id)) {
+                            message.content = content.clone();
+                            message.edited = true;
+                        }
+                    }
+                    UIEvent::Reactions { id, reactions } => {
+                        for message in self.all_messages_mut().filter(|m| m.id == Some(id)) {
+                            message.reactions = reactions.clone();
+                        }
+                    }
+                    UIEvent::History(messages) => self.prepend_history(messages),
+                    UIEvent::Thread { root, messages } => {
+                        self.panel = Some(Panel::new(PanelKind::Thread(root), messages));
+                    }
+                    UIEvent::Mentions { messages, unread } => {
+                        self.panel = Some(Panel::new(PanelKind::Mentions { unread }, messages));
+                    }
+                    UIEvent::SearchResults { query, messages } => {
+                        self.panel = Some(Panel::new(PanelKind::Search { query }, messages));
+                    }
+                    UIEvent::Context { id, messages } => {
+                        self.panel = Some(Panel::new(PanelKind::Context(id), messages));
+                    }
+                    UIEvent::Topic { room, topic } => {
+                        self.topic = Some((room, topic));
+                    }
+                    UIEvent::Deleted { id } => {
+                        self.messages.retain(|m| m.id != Some(id));
+                        if let Some(panel) = &mut self.panel {
+                            panel.messages.retain(|m| m.id != Some(id));
+                        }
+                        if self.editing == Some(id) {
+                            self.editing = None;
+                            self.input_buffer.clear();
+                        }
+                    }
+                }
             }
         }
 
@@ -173,6 +476,89 @@ impl ChatUI {
         }
     }
     
+    fn prepend_history(&mut self, mut messages: Vec<UIMessage>) {
+        messages.retain(|m| !self.messages.iter().any(|existing| existing.id.is_some() && existing.id == m.id));
+        let space = MAX_MESSAGES.saturating_sub(self.messages.len());
+        let skip = messages.len().saturating_sub(space);
+        self.messages.splice(0..0, messages.into_iter().skip(skip));
+    }
+
+    // Messages of the main list and of the open panel
+    fn all_messages_mut(&mut self) -> impl Iterator<Item = &mut UIMessage> {
+        self.messages.iter_mut()
+            .chain(self.panel.iter_mut().flat_map(|p| p.messages.iter_mut()))
+    }
+
+    fn set_delivery_status(&mut self, client_msg_id: u64, status: DeliveryStatus) {
+        for message in self.all_messages_mut().filter(|m| m.client_msg_id == Some(client_msg_id)) {
+            message.status = status;
+        }
+    }
+
+    // Moves the edit selection to the own message before the current one and loads
+    // its content into the input line
+    fn select_previous_own_message(&mut self) {
+        let start = match self.editing {
+            Some(id) => self.messages.iter().position(|m| m.id == Some(id)).unwrap_or(0),
+            None => self.messages.len(),
+        };
+
+        if let Some(message) = self.messages[..start].iter().rev().find(|m| m.is_editable()) {
+            self.editing = message.id;
+            self.input_buffer = message.content.clone();
+        }
+    }
+
+    // Asks the client to load the selected search result in context
+    fn jump_to_selected(&mut self) {
+        let Some(panel) = &self.panel else {
+            return;
+        };
+        let Some(message) = panel.selected.and_then(|i| panel.messages.get(i)) else {
+            return;
+        };
+
+        if let (Some(id), Some(room)) = (message.id, &message.room)
+            && let Err(e) = self.user_message_tx.send(format!("/context {} {}", room, id))
+        {
+            error!("Failed to send jump: {}", e);
+        }
+    }
+
+    // Sends the edited content, or deletes the message when the input was cleared
+    fn submit_edit(&mut self) {
+        let Some(id) = self.editing.take() else {
+            return;
+        };
+
+        let content = mem::take(&mut self.input_buffer);
+        let command = if content.is_empty() {
+            format!("/delete {}", id)
+        } else {
+            format!("/edit {} {}", id, content)
+        };
+
+        if let Err(e) = self.user_message_tx.send(command) {
+            error!("Failed to send edit: {}", e);
+        }
+    }
+
+    // Asks the client to resend the most recent failed message
+    fn retry_failed(&mut self) {
+        let Some(message) = self.messages.iter_mut().rev()
+            .find(|m| m.status == DeliveryStatus::Failed)
+        else {
+            return;
+        };
+
+        if let Some(client_msg_id) = message.client_msg_id {
+            message.status = DeliveryStatus::Pending;
+            if let Err(e) = self.user_message_tx.send(format!("/retry {}", client_msg_id)) {
+                error!("Failed to send retry: {}", e);
+            }
+        }
+    }
+
     fn clear_screen(&mut self) -> Result<()> {
         execute!(self.stdout, Clear(ClearType::All))?;
         Ok(())
@@ -186,37 +572,112 @@ impl ChatUI {
         let height = height as usize;
         let width = width as usize;
 
+        // The top line names the open panel, or the room and its topic
+        let header = match (&self.panel, &self.topic) {
+            (Some(panel), _) => Some(panel.title()),
+            (None, Some((room, topic))) if topic.is_empty() => Some(format!("#{}", room)),
+            (None, Some((room, topic))) => Some(format!("#{}: {}", room, topic)),
+            (None, None) => None,
+        };
+        if let Some(header) = header {
+            execute!(
+                self.stdout,
+                MoveTo(0, 0),
+                SetAttribute(Attribute::Bold),
+                Print(truncate(&header, width)),
+                SetAttribute(Attribute::Reset)
+            )?;
+        }
+        let messages = match &self.panel {
+            Some(panel) => &panel.messages,
+            None => &self.messages,
+        };
+        let show_rooms = self.panel.as_ref().is_some_and(|p| p.thread_root().is_none());
+
         // Draw messages from bottom up
         let mut y = height - 2; // Start one line above the input line
-        for message in self.messages.iter().rev() {
+        for (index, message) in messages.iter().enumerate().rev() {
             if y == 0 {
                 break;
             }
             let timestamp = message.timestamp.format("%H:%M:%S").to_string();
-            let line = format!("[{}] {}", timestamp, message.content);
+            let mut line = format!("[{}] ", timestamp);
+            if let Some(id) = message.id {
+                line.push_str(&format!("#{} ", id));
+            }
+            if let Some(room) = &message.room
+                && show_rooms
+            {
+                line.push_str(&format!("[{}] ", room));
+            }
+            if let Some(root) = message.reply_to
+                && self.panel.as_ref().and_then(Panel::thread_root).is_none()
+            {
+                line.push_str(&format!("(re #{}) ", root));
+            }
+            if let Some(sender) = &message.sender {
+                line.push_str(&format!("<{}> ", sender));
+            }
+            line.push_str(&message.content);
+            if message.edited {
+                line.push_str(" (edited)");
+            }
+            if message.status == DeliveryStatus::Failed {
+                line.push_str(" (failed, Ctrl-R to retry)");
+            }
             
-            // Truncate line if it's too long
-            let line = if line.len() > width as usize {
-                &line[..width as usize]
-            } else {
-                &line
+            let line = truncate(&line, width);
+
+            // Reactions go on their own line below the message
+            if !message.reactions.is_empty() {
+                let reactions: Vec<String> = message.reactions.iter()
+                    .map(|(emoji, count)| format!("{} {}", emoji, count))
+                    .collect();
+                let reaction_line = format!("    {}", reactions.join("  "));
+
+                execute!(
+                    self.stdout,
+                    MoveTo(0, y as u16),
+                    SetAttribute(Attribute::Dim),
+                    Print(truncate(&reaction_line, width)),
+                    SetAttribute(Attribute::Reset)
+                )?;
+                y -= 1;
+                if y == 0 {
+                    break;
+                }
+            }
+
+            match message.status {
+                DeliveryStatus::Pending => execute!(self.stdout, SetAttribute(Attribute::Dim))?,
+                DeliveryStatus::Failed => execute!(self.stdout, SetForegroundColor(Color::Red))?,
+                DeliveryStatus::Confirmed if message.mentions_me => {
+                    execute!(self.stdout, SetForegroundColor(Color::Yellow), SetAttribute(Attribute::Bold))?
+                }
+                DeliveryStatus::Confirmed => {}
+            }
+            let selected = match &self.panel {
+                Some(panel) => panel.is_highlighted(index, message),
+                None => self.editing.is_some() && message.id == self.editing,
             };
+            if selected {
+                execute!(self.stdout, SetAttribute(Attribute::Reverse))?;
+            }
 
             execute!(
                 self.stdout,
                 MoveTo(0, y as u16),
-                Print(line)
+                Print(line),
+                SetAttribute(Attribute::Reset),
+                ResetColor
             )?;
             y -= 1;
         }
 
         // Draw input bar
-        let input_line = format!("> {}", self.input_buffer);
-        let input_line = if input_line.len() > width as usize {
-            &input_line[..width as usize]
-        } else {
-            &input_line
-        };
+        let prompt = if self.editing.is_some() { "edit>" } else { ">" };
+        let input_line = format!("{} {}", prompt, self.input_buffer);
+        let input_line = truncate(&input_line, width);
 
         execute!(
             self.stdout,
@@ -227,11 +688,19 @@ impl ChatUI {
         // Show cursor at the end of input (at the last character position)
         execute!(
             self.stdout,
-            MoveTo(input_line.len() as u16, (height - 1) as u16),
+            MoveTo(input_line.chars().count() as u16, (height - 1) as u16),
             Show
         )?;
 
         self.stdout.flush()?;
         Ok(())
     }
-} 
\ No newline at end of file
+}
+
+// Truncates a line to at most `width` characters without splitting a character
+fn truncate(line: &str, width: usize) -> &str {
+    match line.char_indices().nth(width) {
+        Some((end, _)) => &line[..end],
+        None => line,
+    }
+}
@@ -0,0 +1,143 @@
+//! Inspects and controls a cluster through a node's admin commands.
+//!
+//! Each invocation connects to one node, sends one command and prints the answer.
+//! Membership changes and kicks have to go to the leader; `status` on any node says
+//! which one it is.
+
+use std::time::Duration;
+
+use eyre::{Result, WrapErr, bail, eyre};
+use shared::channel::ChatClientChannel;
+use shared::{AdminCommand, AdminResponse, ChatCommand, ChatResponse, ClusterStatus, parse_duration};
+
+const USAGE: &str = "\
+usage: raft-chat-admin [--node ADDR] COMMAND
+
+commands:
+  status              term, leader, commit and applied index, membership and follower progress
+  add ID PEER_ADDR    add node ID as a learner that listens for peers on PEER_ADDR
+  promote ID          make learner ID a voter
+  remove ID           take node ID out of the cluster
+  transfer ID         hand leadership to node ID
+  snapshot            compact the node's log into a snapshot
+  kick NICK [DURATION] [REASON]
+                      disconnect every session of NICK and keep them out for DURATION
+                      (such as 30s, 10m or 2h; 10m by default)
+  audit [ROOM]        the latest moderation actions, in every room or in ROOM
+
+ADDR is a node's admin address (--admin-listen) and defaults to 127.0.0.1:7080.";
+
+// Moderation actions `audit` lists
+const AUDIT_LIMIT: usize = 50;
+
+// Longer than any timeout the node applies itself, so its own error arrives first.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<(String, AdminCommand)> {
+    let mut addr = "127.0.0.1:7080".to_string();
+    let mut words = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--node" => addr = args.next().ok_or_else(|| eyre!("--node needs an address\n{}", USAGE))?,
+            "-h" | "--help" => bail!("{}", USAGE),
+            _ => words.push(arg),
+        }
+    }
+
+    let id = |word: Option<&String>| -> Result<u64> {
+        let word = word.ok_or_else(|| eyre!("missing node ID\n{}", USAGE))?;
+        word.parse().wrap_err_with(|| format!("{} is not a node ID", word))
+    };
+    let command = match words.first().map(String::as_str) {
+        Some("status") => AdminCommand::Status,
+        Some("snapshot") => AdminCommand::Snapshot,
+        Some("promote") => AdminCommand::Promote { id: id(words.get(1))? },
+        Some("remove") => AdminCommand::RemoveNode { id: id(words.get(1))? },
+        Some("transfer") => AdminCommand::TransferLeadership { to: id(words.get(1))? },
+        Some("add") => {
+            let addr = words.get(2).ok_or_else(|| eyre!("missing peer address\n{}", USAGE))?;
+            AdminCommand::AddLearner { id: id(words.get(1))?, addr: addr.clone() }
+        }
+        Some("kick") => {
+            let nick = words.get(1).ok_or_else(|| eyre!("missing nick\n{}", USAGE))?;
+            let duration_secs = words.get(2).and_then(|word| parse_duration(word));
+            let reason = words[2 + usize::from(duration_secs.is_some())..].join(" ");
+            AdminCommand::KickUser { nick: nick.clone(), reason, duration_secs }
+        }
+        Some("audit") => AdminCommand::AuditLog { room: words.get(1).cloned(), limit: AUDIT_LIMIT },
+        Some(other) => bail!("unknown command {}\n{}", other, USAGE),
+        None => bail!("{}", USAGE),
+    };
+    Ok((addr, command))
+}
+
+fn print_status(status: &ClusterStatus) {
+    let leader = status.leader.map_or("none".to_string(), |id| format!("node {}", id));
+    println!("node {}: {} in term {}, leader {}", status.id, status.role, status.term, leader);
+    println!(
+        "log: last {}, committed {} (leader {}), applied {}, snapshot {}",
+        status.last_index, status.commit_index, status.leader_commit, status.applied_index, status.snapshot_index
+    );
+    println!("voters: {:?}, learners: {:?}", status.voters, status.learners);
+    for (id, addr) in &status.peers {
+        println!("  node {} at {}", id, addr);
+    }
+
+    if status.followers.is_empty() {
+        if let Some(leader) = status.leader.filter(|leader| *leader != status.id) {
+            println!("ask node {} for follower progress", leader);
+        }
+        return;
+    }
+    println!("{:<6}{:<8}{:<8}{:<8}{:<11}active", "node", "match", "next", "lag", "state");
+    for follower in &status.followers {
+        println!(
+            "{:<6}{:<8}{:<8}{:<8}{:<11}{}",
+            follower.id, follower.match_index, follower.next_index, follower.lag, follower.state, follower.active
+        );
+    }
+}
+
+fn print_response(response: &AdminResponse) {
+    match response {
+        AdminResponse::Status(status) => print_status(status),
+        AdminResponse::LeadershipTransferred { to, term } => {
+            println!("node {} is the leader in term {}", to, term);
+        }
+        AdminResponse::MembershipChanged { voters, learners } => {
+            println!("voters: {:?}, learners: {:?}", voters, learners);
+        }
+        AdminResponse::SnapshotTaken { index } => println!("snapshot taken up to index {}", index),
+        AdminResponse::UserKicked { nick } => println!("kicked {}", nick),
+        AdminResponse::AuditLog(entries) if entries.is_empty() => println!("no moderation actions"),
+        AdminResponse::AuditLog(entries) => {
+            for entry in entries {
+                println!("{:>8}  {}", entry.index, entry);
+            }
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let (addr, command) = parse_args(std::env::args().skip(1))?;
+
+    let mut channel =
+        ChatClientChannel::connect(&addr).await.wrap_err_with(|| format!("failed to connect to {}", addr))?;
+    channel.send_command(ChatCommand::Admin(command)).await?;
+
+    loop {
+        let response = tokio::time::timeout(RESPONSE_TIMEOUT, channel.receive_event())
+            .await
+            .map_err(|_| eyre!("no answer from {} within {:?}", addr, RESPONSE_TIMEOUT))??;
+        match response {
+            ChatResponse::Admin(response) => {
+                print_response(&response);
+                return Ok(());
+            }
+            ChatResponse::Error(e) => bail!("{}", e),
+            // Anything else is chat traffic for the session, which an admin does not read.
+            _ => {}
+        }
+    }
+}
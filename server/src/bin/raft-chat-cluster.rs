@@ -0,0 +1,378 @@
+//! Runs a cluster of server nodes on localhost, for demos and for debugging failover.
+//!
+//! Each node gets its own ports and data directory. Nodes reach each other through
+//! proxies run by the launcher, one for each direction of each link, so that partitioning
+//! the cluster is a matter of the proxies refusing to forward between groups. Nodes can
+//! be killed, restarted and partitioned from a prompt.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::Stdio;
+
+use eyre::{Result, WrapErr, bail, eyre};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::process::{Child, Command};
+use tokio::sync::watch;
+
+const USAGE: &str = "usage: raft-chat-cluster [--nodes N] [--base-port PORT] [--data-dir DIR] [--server PATH] \
+[-- SERVER ARGS...]";
+
+const HELP: &str = "\
+commands:
+  status              show the nodes and whether they are running
+  kill N              stop node N abruptly
+  restart N           start node N again, killing it first if it is running
+  partition 1,2 3     split the nodes into groups that cannot reach each other
+  isolate N           cut node N off from all the others
+  heal                reconnect all nodes
+  help                show this help
+  quit                stop all nodes and exit";
+
+// Proxy ports leave room for one digit per node ID.
+const MAX_NODES: u64 = 9;
+
+/// Group of each node; nodes in different groups cannot reach each other.
+type Partition = BTreeMap<u64, usize>;
+
+struct Options {
+    nodes: u64,
+    /// Node N listens for clients on `base_port + N - 1`, for peers 1000 ports higher and
+    /// for admin commands 3000 ports higher.
+    base_port: u16,
+    data_dir: PathBuf,
+    server: PathBuf,
+    /// Passed on to every node.
+    server_args: Vec<String>,
+}
+
+impl Options {
+    fn from_args(mut args: impl Iterator<Item = String>) -> Result<Self> {
+        let mut nodes = 3;
+        let mut base_port = 8080;
+        let mut data_dir = PathBuf::from("data/cluster");
+        let mut server = None;
+        let mut server_args = Vec::new();
+
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| eyre!("{} needs a value\n{}", arg, USAGE));
+            match arg.as_str() {
+                "--nodes" => nodes = value()?.parse().wrap_err("--nodes must be a number")?,
+                "--base-port" => base_port = value()?.parse().wrap_err("--base-port must be a port number")?,
+                "--data-dir" => data_dir = PathBuf::from(value()?),
+                "--server" => server = Some(PathBuf::from(value()?)),
+                "--" => server_args.extend(args.by_ref()),
+                _ => bail!("unknown argument {}\n{}", arg, USAGE),
+            }
+        }
+
+        if !(1..=MAX_NODES).contains(&nodes) {
+            bail!("--nodes must be between 1 and {}", MAX_NODES);
+        }
+        if u32::from(base_port) + 3000 + MAX_NODES as u32 > u32::from(u16::MAX) {
+            bail!("--base-port {} leaves no room for the peer, proxy and admin ports", base_port);
+        }
+        // The server binary is built next to this one.
+        let server = match server {
+            Some(server) => server,
+            None => std::env::current_exe()?.with_file_name(format!("server{}", std::env::consts::EXE_SUFFIX)),
+        };
+
+        Ok(Self { nodes, base_port, data_dir, server, server_args })
+    }
+
+    fn ids(&self) -> impl Iterator<Item = u64> + use<> {
+        1..=self.nodes
+    }
+
+    fn client_addr(&self, id: u64) -> String {
+        format!("127.0.0.1:{}", self.base_port as u64 + id - 1)
+    }
+
+    fn peer_addr(&self, id: u64) -> String {
+        format!("127.0.0.1:{}", self.base_port as u64 + 1000 + id - 1)
+    }
+
+    fn admin_addr(&self, id: u64) -> String {
+        format!("127.0.0.1:{}", self.base_port as u64 + 3000 + id - 1)
+    }
+
+    /// Where node `from` sends to node `to`.
+    fn proxy_addr(&self, from: u64, to: u64) -> String {
+        format!("127.0.0.1:{}", self.base_port as u64 + 2000 + 10 * from + to)
+    }
+
+    fn node_dir(&self, id: u64) -> PathBuf {
+        self.data_dir.join(format!("node{}", id))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Prompt {
+    Status,
+    Kill(u64),
+    Restart(u64),
+    Partition(Vec<Vec<u64>>),
+    Isolate(u64),
+    Heal,
+    Help,
+    Quit,
+}
+
+impl Prompt {
+    fn parse(line: &str, nodes: u64) -> Result<Self, String> {
+        let node = |arg: Option<&str>| {
+            let arg = arg.ok_or("which node?")?;
+            match arg.parse() {
+                Ok(id) if (1..=nodes).contains(&id) => Ok(id),
+                _ => Err(format!("there is no node {}", arg)),
+            }
+        };
+
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some("status") => Prompt::Status,
+            Some("kill") => Prompt::Kill(node(words.next())?),
+            Some("restart") => Prompt::Restart(node(words.next())?),
+            Some("isolate") => Prompt::Isolate(node(words.next())?),
+            Some("heal") => Prompt::Heal,
+            Some("help") => Prompt::Help,
+            Some("quit" | "exit") => Prompt::Quit,
+            Some("partition") => {
+                let groups = words
+                    .by_ref()
+                    .map(|group| group.split(',').map(|id| node(Some(id))).collect())
+                    .collect::<Result<Vec<Vec<u64>>, String>>()?;
+                if groups.len() < 2 {
+                    return Err("a partition needs at least two groups, like: partition 1,2 3".to_string());
+                }
+                Prompt::Partition(groups)
+            }
+            Some(other) => return Err(format!("unknown command {}, try help", other)),
+            None => Prompt::Status,
+        };
+
+        if words.next().is_some() {
+            return Err("too many arguments, try help".to_string());
+        }
+        Ok(command)
+    }
+}
+
+struct Cluster {
+    options: Options,
+    children: BTreeMap<u64, Child>,
+    partition: watch::Sender<Partition>,
+}
+
+impl Cluster {
+    fn start(&mut self, id: u64) -> Result<()> {
+        let dir = self.options.node_dir(id);
+        std::fs::create_dir_all(&dir).wrap_err_with(|| format!("failed to create {}", dir.display()))?;
+        let log = std::fs::OpenOptions::new().create(true).append(true).open(dir.join("server.log"))?;
+
+        let mut command = Command::new(&self.options.server);
+        command
+            .args(["--id", &id.to_string()])
+            .args(["--listen", &self.options.client_addr(id)])
+            .args(["--admin-listen", &self.options.admin_addr(id)])
+            .args(["--peer-listen", &self.options.peer_addr(id)])
+            .arg("--data-dir")
+            .arg(&dir);
+        for peer in self.options.ids().filter(|peer| *peer != id) {
+            command.args(["--peer", &format!("{}={}", peer, self.options.proxy_addr(id, peer))]);
+            command.args(["--peer-client", &format!("{}={}", peer, self.options.client_addr(peer))]);
+        }
+        let child = command
+            .args(&self.options.server_args)
+            .stdin(Stdio::null())
+            .stdout(log.try_clone()?)
+            .stderr(log)
+            .kill_on_drop(true)
+            .spawn()
+            .wrap_err_with(|| format!("failed to start {}", self.options.server.display()))?;
+
+        self.children.insert(id, child);
+        Ok(())
+    }
+
+    async fn kill(&mut self, id: u64) {
+        if let Some(mut child) = self.children.remove(&id) {
+            let _ = child.kill().await;
+        }
+    }
+
+    fn print_status(&mut self) {
+        let partition = self.partition.borrow().clone();
+        println!("{:<6}{:<18}{:<18}{:<18}{:<12}{:<7}data", "node", "clients", "peers", "admin", "state", "group");
+        for id in self.options.ids() {
+            let state = match self.children.get_mut(&id).map(|child| (child.id(), child.try_wait())) {
+                Some((Some(pid), Ok(None))) => format!("pid {}", pid),
+                Some((_, Ok(Some(status)))) => format!("exited {}", status.code().unwrap_or(-1)),
+                _ => "stopped".to_string(),
+            };
+            let group = partition.get(&id).map_or("-".to_string(), |group| group.to_string());
+            println!(
+                "{:<6}{:<18}{:<18}{:<18}{:<12}{:<7}{}",
+                id,
+                self.options.client_addr(id),
+                self.options.peer_addr(id),
+                self.options.admin_addr(id),
+                state,
+                group,
+                self.options.node_dir(id).display()
+            );
+        }
+    }
+
+    // Clients can be pointed at any node, and at the survivors once one is killed
+    fn print_clients(&self) {
+        println!("\nclients connect with `client --server ADDR`:");
+        for id in self.options.ids() {
+            println!("  node {}  {}", id, self.options.client_addr(id));
+        }
+    }
+
+    async fn run(&mut self, command: Prompt) -> Result<bool> {
+        match command {
+            Prompt::Status => self.print_status(),
+            Prompt::Kill(id) => {
+                self.kill(id).await;
+                println!("killed node {}", id);
+            }
+            Prompt::Restart(id) => {
+                self.kill(id).await;
+                self.start(id)?;
+                println!("restarted node {}", id);
+            }
+            Prompt::Partition(groups) => {
+                let partition = groups
+                    .iter()
+                    .enumerate()
+                    .flat_map(|(group, ids)| ids.iter().map(move |id| (*id, group + 1)))
+                    .collect();
+                self.partition.send_replace(partition);
+                println!("partitioned into {:?}", groups);
+            }
+            Prompt::Isolate(id) => {
+                self.partition.send_replace(BTreeMap::from([(id, 1)]));
+                println!("isolated node {}", id);
+            }
+            Prompt::Heal => {
+                self.partition.send_replace(BTreeMap::new());
+                println!("healed the partition");
+            }
+            Prompt::Help => {
+                println!("{}", HELP);
+                self.print_clients();
+            }
+            Prompt::Quit => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+fn cut(partition: &Partition, from: u64, to: u64) -> bool {
+    partition.get(&from).unwrap_or(&0) != partition.get(&to).unwrap_or(&0)
+}
+
+// Forwards connections from node `from` to node `to` unless a partition separates them.
+async fn run_proxy(listener: TcpListener, from: u64, to: u64, target: String, partition: watch::Receiver<Partition>) {
+    loop {
+        let Ok((inbound, _)) = listener.accept().await else {
+            continue;
+        };
+        if cut(&partition.borrow(), from, to) {
+            continue;
+        }
+        tokio::spawn(forward(inbound, from, to, target.clone(), partition.clone()));
+    }
+}
+
+async fn forward(
+    mut inbound: TcpStream,
+    from: u64,
+    to: u64,
+    target: String,
+    mut partition: watch::Receiver<Partition>,
+) {
+    let Ok(mut outbound) = TcpStream::connect(&target).await else {
+        return;
+    };
+    // Dropping both ends when a partition starts makes the sender reconnect, which the
+    // proxy then refuses until the partition heals.
+    tokio::select! {
+        _ = tokio::io::copy_bidirectional(&mut inbound, &mut outbound) => {}
+        _ = partition.wait_for(|partition| cut(partition, from, to)) => {}
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    color_eyre::install()?;
+    let options = Options::from_args(std::env::args().skip(1))?;
+    if !options.server.exists() {
+        bail!("no server binary at {}; build it first or pass --server", options.server.display());
+    }
+
+    let (partition, watcher) = watch::channel(Partition::new());
+    for from in options.ids() {
+        for to in options.ids().filter(|to| *to != from) {
+            let addr = options.proxy_addr(from, to);
+            let listener = TcpListener::bind(&addr).await.wrap_err_with(|| format!("failed to bind {}", addr))?;
+            tokio::spawn(run_proxy(listener, from, to, options.peer_addr(to), watcher.clone()));
+        }
+    }
+
+    let mut cluster = Cluster { options, children: BTreeMap::new(), partition };
+    for id in cluster.options.ids() {
+        cluster.start(id)?;
+    }
+    cluster.print_status();
+    cluster.print_clients();
+    println!("\nLogs are in each node's data directory. Type help for commands.");
+
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    loop {
+        print!("> ");
+        std::io::stdout().flush()?;
+
+        let line = tokio::select! {
+            line = lines.next_line() => line?,
+            _ = tokio::signal::ctrl_c() => None,
+        };
+        let Some(line) = line else {
+            break;
+        };
+        match Prompt::parse(&line, cluster.options.nodes) {
+            Ok(command) => {
+                if !cluster.run(command).await? {
+                    break;
+                }
+            }
+            Err(e) => println!("{}", e),
+        }
+    }
+
+    for id in cluster.options.ids() {
+        cluster.kill(id).await;
+    }
+    println!("stopped all nodes");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prompt_commands_parse() {
+        assert_eq!(Prompt::parse("kill 2", 3), Ok(Prompt::Kill(2)));
+        assert_eq!(Prompt::parse("partition 1,2 3", 3), Ok(Prompt::Partition(vec![vec![1, 2], vec![3]])));
+        assert_eq!(Prompt::parse("", 3), Ok(Prompt::Status));
+        assert!(Prompt::parse("kill 4", 3).is_err());
+        assert!(Prompt::parse("partition 1,2,3", 3).is_err());
+        assert!(Prompt::parse("heal now", 3).is_err());
+    }
+}
@@ -0,0 +1,22 @@
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Where the node driver reads the time, so the simulator can run it on a virtual clock.
+pub trait Clock: Send {
+    fn now(&self) -> Instant;
+
+    /// Milliseconds since the Unix epoch, stamped on proposals so that every node applies
+    /// them at the same time.
+    fn unix_millis(&self) -> u64;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn unix_millis(&self) -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+    }
+}
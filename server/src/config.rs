@@ -0,0 +1,140 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use eyre::{Result, WrapErr, bail, eyre};
+use shared::fault::FaultPolicy;
+
+use crate::limits::RateLimits;
+use crate::raft::NodeId;
+
+const USAGE: &str = "usage: server [--id N] [--listen ADDR] [--admin-listen ADDR] [--peer-listen ADDR] \
+[--peer ID=ADDR]... [--peer-client ID=ADDR]... [--learner] [--data-dir DIR] [--no-pre-vote] [--no-check-quorum] \
+[--max-clock-drift-ms N] [--faults SPEC] [--http-listen ADDR] [--rate-limits FILE]";
+
+/// Command line configuration of a server node.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub id: NodeId,
+    /// Address clients connect to.
+    pub listen: SocketAddr,
+    /// Address `raft-chat-admin` connects to, the only one admin commands are taken on.
+    /// Anyone who can reach it controls the cluster, so it is local only by default.
+    pub admin_listen: SocketAddr,
+    /// Address the other nodes of the cluster connect to. Nothing authenticates peers, so
+    /// it is local only by default.
+    pub peer_listen: SocketAddr,
+    /// Peer addresses of the other voters, by node ID.
+    pub peers: BTreeMap<NodeId, String>,
+    /// Client addresses of other nodes, where clients are sent when this node shuts down.
+    pub peer_clients: BTreeMap<NodeId, String>,
+    /// Join an existing cluster as a learner instead of as one of its initial voters.
+    pub learner: bool,
+    pub data_dir: PathBuf,
+    pub pre_vote: bool,
+    pub check_quorum: bool,
+    /// How far clocks may drift apart, which shortens the leader's read lease.
+    pub max_clock_drift: Duration,
+    /// Faults to inject into client connections, for chaos experiments.
+    pub faults: Option<FaultPolicy>,
+    /// Address of the HTTP endpoint serving `/metrics`, `/healthz` and `/readyz`, if any.
+    pub http_listen: Option<SocketAddr>,
+    /// File the rate limits are read from, again on SIGHUP. Without one the defaults apply.
+    pub rate_limits_file: Option<PathBuf>,
+    pub rate_limits: RateLimits,
+}
+
+impl ServerConfig {
+    pub fn from_args(mut args: impl Iterator<Item = String>) -> Result<Self> {
+        let mut id = 1;
+        let mut listen = "0.0.0.0:8080".to_string();
+        let mut admin_listen = "127.0.0.1:7080".to_string();
+        let mut peer_listen = "127.0.0.1:9080".to_string();
+        let mut peers = BTreeMap::new();
+        let mut peer_clients = BTreeMap::new();
+        let mut learner = false;
+        let mut data_dir = None;
+        let mut pre_vote = true;
+        let mut check_quorum = true;
+        let mut max_clock_drift = Duration::from_millis(200);
+        let mut faults = None;
+        let mut http_listen = None;
+        let mut rate_limits_file = None;
+
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| eyre!("{} needs a value\n{}", arg, USAGE));
+            match arg.as_str() {
+                "--id" => id = value()?.parse().wrap_err("--id must be a number")?,
+                "--listen" => listen = value()?,
+                "--admin-listen" => admin_listen = value()?,
+                "--peer-listen" => peer_listen = value()?,
+                "--peer" => {
+                    let peer = value()?;
+                    let (peer_id, addr) = peer
+                        .split_once('=')
+                        .ok_or_else(|| eyre!("--peer must look like ID=ADDR, got {}", peer))?;
+                    let peer_id = peer_id.parse().wrap_err("peer ID must be a number")?;
+                    peers.insert(peer_id, addr.to_string());
+                }
+                "--peer-client" => {
+                    let peer = value()?;
+                    let (peer_id, addr) = peer
+                        .split_once('=')
+                        .ok_or_else(|| eyre!("--peer-client must look like ID=ADDR, got {}", peer))?;
+                    let peer_id = peer_id.parse().wrap_err("peer ID must be a number")?;
+                    peer_clients.insert(peer_id, addr.to_string());
+                }
+                "--learner" => learner = true,
+                "--data-dir" => data_dir = Some(PathBuf::from(value()?)),
+                "--no-pre-vote" => pre_vote = false,
+                "--no-check-quorum" => check_quorum = false,
+                "--max-clock-drift-ms" => {
+                    let ms = value()?.parse().wrap_err("--max-clock-drift-ms must be a number")?;
+                    max_clock_drift = Duration::from_millis(ms);
+                }
+                "--faults" => faults = Some(value()?.parse()?),
+                "--http-listen" => http_listen = Some(value()?.parse().wrap_err("invalid --http-listen address")?),
+                "--rate-limits" => rate_limits_file = Some(PathBuf::from(value()?)),
+                _ => bail!("unknown argument {}\n{}", arg, USAGE),
+            }
+        }
+
+        if peers.contains_key(&id) {
+            bail!("node {} cannot be its own peer", id);
+        }
+        let rate_limits = match &rate_limits_file {
+            Some(path) => RateLimits::load(path)?,
+            None => RateLimits::default(),
+        };
+
+        Ok(Self {
+            id,
+            listen: listen.parse().wrap_err("invalid --listen address")?,
+            admin_listen: admin_listen.parse().wrap_err("invalid --admin-listen address")?,
+            peer_listen: peer_listen.parse().wrap_err("invalid --peer-listen address")?,
+            peers,
+            peer_clients,
+            learner,
+            // Keep nodes started from the same directory apart by default.
+            data_dir: data_dir.unwrap_or_else(|| PathBuf::from(format!("data/node{}", id))),
+            pre_vote,
+            check_quorum,
+            max_clock_drift,
+            faults,
+            http_listen,
+            rate_limits_file,
+            rate_limits,
+        })
+    }
+
+    /// The initial voters of the cluster, which include this node unless it is a learner.
+    pub fn voters(&self) -> Vec<NodeId> {
+        let me = (!self.learner).then_some(self.id);
+        me.into_iter().chain(self.peers.keys().copied()).collect()
+    }
+
+    pub fn learners(&self) -> Vec<NodeId> {
+        if self.learner { vec![self.id] } else { Vec::new() }
+    }
+}
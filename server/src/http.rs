@@ -0,0 +1,185 @@
+//! A minimal HTTP endpoint for monitoring: one `GET` per connection, answered and closed.
+
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde_json::json;
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info, warn};
+
+use crate::node::NodeHandle;
+
+/// Longest request head read before giving up on a client.
+const MAX_REQUEST_SIZE: u64 = 8192;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a health check waits for the node task to answer.
+const NODE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Entries a node may have left to apply, behind the leader's commit index, and still
+/// count as ready.
+const MAX_READY_LAG: u64 = 100;
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn text(status: &'static str, body: impl Into<String>) -> Self {
+        Self { status, content_type: "text/plain; charset=utf-8", body: body.into() }
+    }
+
+    /// A check result: every named check is either "ok" or the reason it failed.
+    fn checks(checks: Vec<(&str, Result<(), String>)>) -> Self {
+        let ok = checks.iter().all(|(_, result)| result.is_ok());
+        let checks: serde_json::Map<_, _> = checks
+            .into_iter()
+            .map(|(name, result)| (name.to_string(), json!(result.err().unwrap_or_else(|| "ok".to_string()))))
+            .collect();
+        let body = json!({ "status": if ok { "ok" } else { "fail" }, "checks": checks });
+        Self {
+            status: if ok { "200 OK" } else { "503 Service Unavailable" },
+            content_type: "application/json",
+            body: format!("{}\n", body),
+        }
+    }
+}
+
+/// Serves monitoring requests on `addr`. `data_dir` is where the node keeps its log,
+/// which `/healthz` checks is still writable.
+pub async fn listen(addr: SocketAddr, node: NodeHandle, data_dir: PathBuf) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("Serving /metrics, /healthz and /readyz on http://{}", addr);
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((socket, _)) => {
+                    tokio::spawn(handle(socket, node.clone(), data_dir.clone()));
+                }
+                Err(e) => warn!("Error accepting HTTP connection: {}", e),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+async fn handle(socket: TcpStream, node: NodeHandle, data_dir: PathBuf) {
+    let (reader, mut writer) = socket.into_split();
+    let request = match tokio::time::timeout(REQUEST_TIMEOUT, read_request_line(reader)).await {
+        Ok(Ok(line)) => line,
+        Ok(Err(e)) => {
+            debug!("Bad HTTP request: {}", e);
+            return;
+        }
+        Err(_) => return,
+    };
+
+    let mut words = request.split_whitespace();
+    let response = match (words.next(), words.next()) {
+        (Some("GET"), Some(path)) => route(path, &node, &data_dir).await,
+        (Some(_), Some(_)) => Response::text("405 Method Not Allowed", "only GET is supported\n"),
+        _ => Response::text("400 Bad Request", "malformed request\n"),
+    };
+
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.content_type,
+        response.body.len()
+    );
+    let _ = writer.write_all(head.as_bytes()).await;
+    let _ = writer.write_all(response.body.as_bytes()).await;
+    let _ = writer.shutdown().await;
+}
+
+async fn route(path: &str, node: &NodeHandle, data_dir: &Path) -> Response {
+    // Query strings are accepted but ignored.
+    match path.split('?').next().unwrap_or_default() {
+        "/metrics" => Response {
+            status: "200 OK",
+            content_type: "text/plain; version=0.0.4; charset=utf-8",
+            body: node.metrics().render(),
+        },
+        "/healthz" => health(node, data_dir).await,
+        "/readyz" => readiness(node).await,
+        _ => Response::text("404 Not Found", "not found\n"),
+    }
+}
+
+// Alive: the node task still answers (it stops when storage fails) and the data
+// directory still takes writes.
+async fn health(node: &NodeHandle, data_dir: &Path) -> Response {
+    let node_check = match tokio::time::timeout(NODE_TIMEOUT, node.status()).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err(format!("node task did not answer within {:?}", NODE_TIMEOUT)),
+    };
+
+    let probe = data_dir.join(".healthz");
+    let storage_check = tokio::task::spawn_blocking(move || {
+        std::fs::write(&probe, b"ok")
+            .and_then(|_| std::fs::File::open(&probe)?.sync_all())
+            .and_then(|_| std::fs::remove_file(&probe))
+    })
+    .await;
+    let storage_check = match storage_check {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(e)) => Err(format!("cannot write to {}: {}", data_dir.display(), e)),
+        Err(e) => Err(e.to_string()),
+    };
+
+    Response::checks(vec![("node", node_check), ("storage", storage_check)])
+}
+
+// Ready to serve: a member of the cluster, following a known leader, and nearly caught
+// up with what it has committed.
+async fn readiness(node: &NodeHandle) -> Response {
+    let status = match tokio::time::timeout(NODE_TIMEOUT, node.status()).await {
+        Ok(Ok(status)) => status,
+        Ok(Err(e)) => return Response::checks(vec![("node", Err(e.to_string()))]),
+        Err(_) => return Response::checks(vec![("node", Err("node task did not answer".to_string()))]),
+    };
+
+    let member = if status.voters.contains(&status.id) || status.learners.contains(&status.id) {
+        Ok(())
+    } else {
+        Err(format!("node {} is not a member of the cluster", status.id))
+    };
+    let leader = match status.leader {
+        Some(_) => Ok(()),
+        None => Err("no leader known".to_string()),
+    };
+    let lag = status.leader_commit.saturating_sub(status.applied_index);
+    let caught_up = if lag <= MAX_READY_LAG {
+        Ok(())
+    } else {
+        Err(format!(
+            "applied index {} is {} entries behind commit index {}",
+            status.applied_index, lag, status.leader_commit
+        ))
+    };
+
+    Response::checks(vec![("member", member), ("leader", leader), ("caught_up", caught_up)])
+}
+
+// Reads the request line and skips the headers, which nothing here needs.
+async fn read_request_line(reader: impl tokio::io::AsyncRead + Unpin) -> std::io::Result<String> {
+    let mut reader = BufReader::new(reader.take(MAX_REQUEST_SIZE));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+
+    let mut header = String::new();
+    loop {
+        header.clear();
+        if reader.read_line(&mut header).await? == 0 || header.trim_end().is_empty() {
+            return Ok(request_line);
+        }
+    }
+}
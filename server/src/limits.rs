@@ -0,0 +1,275 @@
+//! Rate limits that keep one client from flooding a room, and with it the Raft log.
+//!
+//! Limits are token buckets: each allows `burst` requests at once and refills at
+//! `per_sec`. They are read from a file of `key = value` lines, which the server reads
+//! again on SIGHUP, so they can be changed without a restart.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use eyre::{Result, WrapErr, bail, eyre};
+
+/// How fast requests of one kind may come: `burst` at once, then `per_sec`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rate {
+    pub per_sec: f64,
+    pub burst: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimits {
+    /// Messages each user may send, on this node.
+    pub user_messages: Rate,
+    /// Messages the users at one address may send together, on this node, so taking new
+    /// nicks does not get around the limit per user. Loopback is left out, since every
+    /// client on the machine shares it.
+    pub addr_messages: Rate,
+    /// Messages each room may receive from the clients of this node.
+    pub room_messages: Rate,
+    /// Commands of any kind on one connection.
+    pub connection_commands: Rate,
+    /// Open connections from one IP address; zero for no limit.
+    pub connections_per_ip: usize,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            user_messages: Rate { per_sec: 5.0, burst: 10.0 },
+            addr_messages: Rate { per_sec: 20.0, burst: 50.0 },
+            room_messages: Rate { per_sec: 50.0, burst: 100.0 },
+            connection_commands: Rate { per_sec: 20.0, burst: 40.0 },
+            connections_per_ip: 32,
+        }
+    }
+}
+
+impl RateLimits {
+    /// Reads limits from a file, starting from the defaults for anything it leaves out.
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path).wrap_err_with(|| format!("failed to read {}", path.display()))?;
+        text.parse().wrap_err_with(|| format!("invalid rate limits in {}", path.display()))
+    }
+}
+
+impl FromStr for RateLimits {
+    type Err = eyre::Report;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let mut limits = RateLimits::default();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (key, value) = line.split_once('=').ok_or_else(|| eyre!("line {}: expected KEY = VALUE", n + 1))?;
+            let (key, value) = (key.trim(), value.trim());
+            if key == "connections_per_ip" {
+                let max = value.parse().map_err(|_| eyre!("line {}: {} must be a whole number", n + 1, key))?;
+                limits.connections_per_ip = max;
+                continue;
+            }
+            let number = value.parse::<f64>().ok().filter(|v| v.is_finite() && *v > 0.0);
+            let number = number.ok_or_else(|| eyre!("line {}: {} must be a positive number", n + 1, key))?;
+            match key {
+                "user_messages_per_sec" => limits.user_messages.per_sec = number,
+                "user_message_burst" => limits.user_messages.burst = number,
+                "addr_messages_per_sec" => limits.addr_messages.per_sec = number,
+                "addr_message_burst" => limits.addr_messages.burst = number,
+                "room_messages_per_sec" => limits.room_messages.per_sec = number,
+                "room_message_burst" => limits.room_messages.burst = number,
+                "connection_commands_per_sec" => limits.connection_commands.per_sec = number,
+                "connection_command_burst" => limits.connection_commands.burst = number,
+                _ => bail!("line {}: unknown limit {}", n + 1, key),
+            }
+        }
+        Ok(limits)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: Rate, now: Instant) -> Self {
+        Self { tokens: rate.burst.max(1.0), updated: now }
+    }
+
+    fn refill(&mut self, rate: Rate, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate.per_sec).min(rate.burst.max(1.0));
+        self.updated = now;
+    }
+
+    /// How long until a request may be let through, or `None` if one may be now.
+    fn wait(&mut self, rate: Rate, now: Instant) -> Option<Duration> {
+        self.refill(rate, now);
+        (self.tokens < 1.0).then(|| Duration::from_secs_f64((1.0 - self.tokens) / rate.per_sec))
+    }
+
+    /// Takes a token, or says how long to wait for one.
+    pub fn take(&mut self, rate: Rate, now: Instant) -> Result<(), Duration> {
+        match self.wait(rate, now) {
+            Some(wait) => Err(wait),
+            None => {
+                self.tokens -= 1.0;
+                Ok(())
+            }
+        }
+    }
+
+    // A full bucket behaves the same as a new one, so it need not be kept.
+    fn is_full(&mut self, rate: Rate, now: Instant) -> bool {
+        self.refill(rate, now);
+        self.tokens >= rate.burst.max(1.0)
+    }
+}
+
+/// Buckets for the messages sent by each user, from each address and to each room.
+#[derive(Debug, Default)]
+pub struct MessageLimiter {
+    users: HashMap<String, TokenBucket>,
+    addrs: HashMap<IpAddr, TokenBucket>,
+    rooms: HashMap<String, TokenBucket>,
+}
+
+impl MessageLimiter {
+    /// Lets a message from `user` at `addr` to `room` through, or says how long the
+    /// user has to wait. A rejected message uses up none of the buckets.
+    pub fn check(
+        &mut self,
+        limits: &RateLimits,
+        user: &str,
+        addr: Option<IpAddr>,
+        room: &str,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        let (user_rate, addr_rate, room_rate) = (limits.user_messages, limits.addr_messages, limits.room_messages);
+        let user_bucket = self.users.entry(user.to_string()).or_insert_with(|| TokenBucket::new(user_rate, now));
+        let room_bucket = self.rooms.entry(room.to_string()).or_insert_with(|| TokenBucket::new(room_rate, now));
+        let mut addr_bucket = addr
+            .filter(|addr| !addr.is_loopback())
+            .map(|addr| self.addrs.entry(addr).or_insert_with(|| TokenBucket::new(addr_rate, now)));
+
+        let wait = user_bucket
+            .wait(user_rate, now)
+            .max(room_bucket.wait(room_rate, now))
+            .max(addr_bucket.as_mut().and_then(|bucket| bucket.wait(addr_rate, now)));
+        if let Some(wait) = wait {
+            return Err(wait);
+        }
+        let _ = user_bucket.take(user_rate, now);
+        let _ = room_bucket.take(room_rate, now);
+        if let Some(bucket) = addr_bucket {
+            let _ = bucket.take(addr_rate, now);
+        }
+        Ok(())
+    }
+
+    /// Forgets users, addresses and rooms that have been quiet long enough to have full
+    /// buckets.
+    pub fn prune(&mut self, limits: &RateLimits, now: Instant) {
+        self.users.retain(|_, bucket| !bucket.is_full(limits.user_messages, now));
+        self.addrs.retain(|_, bucket| !bucket.is_full(limits.addr_messages, now));
+        self.rooms.retain(|_, bucket| !bucket.is_full(limits.room_messages, now));
+    }
+}
+
+/// Open connections per IP address, shared by the accept loop and the connections.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionCounts(Arc<Mutex<HashMap<IpAddr, usize>>>);
+
+impl ConnectionCounts {
+    /// Counts a new connection from `ip`, unless it already has `max` open. The
+    /// connection is counted until the returned slot is dropped.
+    pub fn acquire(&self, ip: IpAddr, max: usize) -> Option<ConnectionSlot> {
+        let mut counts = self.0.lock().unwrap();
+        let count = counts.entry(ip).or_default();
+        if max > 0 && *count >= max {
+            return None;
+        }
+        *count += 1;
+        Some(ConnectionSlot { counts: self.clone(), ip })
+    }
+}
+
+pub struct ConnectionSlot {
+    counts: ConnectionCounts,
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut counts = self.counts.0.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_allow_a_burst_then_refill() {
+        let start = Instant::now();
+        let limits = RateLimits {
+            user_messages: Rate { per_sec: 2.0, burst: 3.0 },
+            room_messages: Rate { per_sec: 100.0, burst: 100.0 },
+            ..RateLimits::default()
+        };
+        let mut limiter = MessageLimiter::default();
+        let localhost = Some([127, 0, 0, 1].into());
+
+        for _ in 0..3 {
+            assert!(limiter.check(&limits, "alice", localhost, "general", start).is_ok());
+        }
+        assert_eq!(limiter.check(&limits, "alice", localhost, "general", start), Err(Duration::from_millis(500)));
+        assert!(limiter.check(&limits, "bob", localhost, "general", start).is_ok());
+        assert!(limiter.check(&limits, "alice", None, "general", start + Duration::from_millis(500)).is_ok());
+
+        limiter.prune(&limits, start + Duration::from_secs(10));
+        assert!(limiter.users.is_empty());
+    }
+
+    #[test]
+    fn new_nicks_share_the_limit_of_their_address() {
+        let start = Instant::now();
+        let limits = RateLimits {
+            user_messages: Rate { per_sec: 1.0, burst: 1.0 },
+            addr_messages: Rate { per_sec: 1.0, burst: 2.0 },
+            ..RateLimits::default()
+        };
+        let mut limiter = MessageLimiter::default();
+        let addr = Some([10, 0, 0, 1].into());
+
+        assert!(limiter.check(&limits, "alice", addr, "general", start).is_ok());
+        assert!(limiter.check(&limits, "alice", addr, "general", start).is_err());
+        assert!(limiter.check(&limits, "guest-1-1", addr, "general", start).is_ok());
+        assert!(limiter.check(&limits, "guest-1-2", addr, "general", start).is_err());
+        assert!(limiter.check(&limits, "bob", Some([10, 0, 0, 2].into()), "general", start).is_ok());
+    }
+
+    #[test]
+    fn parses_limits_and_rejects_unknown_keys() {
+        let limits: RateLimits = "# quieter rooms\nroom_messages_per_sec = 10\nconnections_per_ip=0\n".parse().unwrap();
+        assert_eq!(limits.room_messages, Rate { per_sec: 10.0, burst: 100.0 });
+        assert_eq!(limits.connections_per_ip, 0);
+        assert!("user_messages = 5".parse::<RateLimits>().is_err());
+        for value in ["fast", "0", "-1", "inf", "NaN"] {
+            assert!(format!("user_messages_per_sec = {}", value).parse::<RateLimits>().is_err());
+        }
+        assert!("connections_per_ip = 1.5".parse::<RateLimits>().is_err());
+    }
+}
@@ -1,7 +1,46 @@
-use shared::{channel::ChatClientChannel, ChatCommand, ChatResponse};
+mod auth;
+mod clock;
+mod config;
+mod http;
+mod limits;
+mod metrics;
+mod node;
+mod peer;
+mod raft;
+mod search;
+#[cfg(test)]
+mod sim;
+mod state;
+mod storage;
+
+use shared::{
+    channel::ChatClientChannel, fault::FaultPolicy, ChatCommand, ChatError, ChatResponse, ErrorCode, ErrorResponse,
+};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 use tokio::net::{TcpListener, TcpStream};
-use tracing::{info, error};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinSet;
+use tracing::{info, error, warn};
 use eyre::{Result, WrapErr};
+use config::ServerConfig;
+use limits::{ConnectionCounts, ConnectionSlot, RateLimits, TokenBucket};
+use node::{Node, NodeHandle};
+
+// Responses queued per connection before the node starts dropping them. A client can
+// get a couple of responses for each command the node applies in one batch.
+const OUTBOUND_QUEUE_SIZE: usize = 1024;
+
+// How long a shutdown may take to hand off leadership and flush client connections
+// before the server exits regardless.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Malformed commands a connection may send, each answered with an error, before it is
+// dropped as broken or hostile.
+const MAX_MALFORMED_COMMANDS: u32 = 5;
+
+// When a client turned away for having too many connections open is told to try again
+const CONNECTION_RETRY_AFTER: Duration = Duration::from_secs(5);
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -11,52 +50,283 @@ async fn main() -> Result<()> {
     // Install custom panic and error hooks
     color_eyre::install()?;
     
-    info!("Starting chat server...");
+    let config = ServerConfig::from_args(std::env::args().skip(1))?;
+    info!("Starting chat server {}...", config.id);
+    if let Some(faults) = &config.faults {
+        warn!("Injecting faults into client connections: {:?}", faults);
+    }
+
+    let node = Node::open(&config)
+        .wrap_err("Failed to open node storage")?
+        .spawn();
+
+    peer::listen(config.peer_listen, node.clone())
+        .await
+        .wrap_err("Failed to bind peer address")?;
+
+    if let Some(addr) = config.http_listen {
+        http::listen(addr, node.clone(), config.data_dir.clone())
+            .await
+            .wrap_err("Failed to bind HTTP address")?;
+    }
 
     // Listen for incoming connections
-    let listener = TcpListener::bind("0.0.0.0:8080")
+    let listener = TcpListener::bind(config.listen)
         .await
         .wrap_err("Failed to bind to address")?;
         
-    info!("Server listening on 0.0.0.0:8080");
+    info!("Server listening on {}", config.listen);
+
+    let admin_listener = TcpListener::bind(config.admin_listen)
+        .await
+        .wrap_err("Failed to bind admin address")?;
+    info!("Taking admin commands on {}", config.admin_listen);
+
+    let (limits_tx, limits) = watch::channel(config.rate_limits.clone());
+    if let Some(path) = config.rate_limits_file.clone() {
+        tokio::spawn(reload_rate_limits(path, node.clone(), limits_tx));
+    }
+    let counts = ConnectionCounts::default();
+
+    let mut connections = JoinSet::new();
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
 
     loop {
-        match listener.accept().await {
-            Ok((socket, addr)) => {
-                info!("New connection from {}", addr);
-                tokio::spawn(handle_connection(socket));
+        tokio::select! {
+            result = listener.accept() => {
+                match result {
+                    Ok((socket, addr)) => {
+                        let max = limits.borrow().connections_per_ip;
+                        let Some(slot) = counts.acquire(addr.ip(), max) else {
+                            warn!("Turning away {}: {} connections from that address are open", addr, max);
+                            connections.spawn(reject(socket, max));
+                            continue;
+                        };
+                        info!("New connection from {}", addr);
+                        let faults = config.faults.clone();
+                        let connection = handle_connection(socket, node.clone(), faults, limits.clone(), slot, false);
+                        connections.spawn(connection);
+                    }
+                    Err(e) => {
+                        error!("Error accepting connection: {}", e);
+                    }
+                }
+            }
+
+            result = admin_listener.accept() => {
+                match result {
+                    Ok((socket, addr)) => {
+                        let max = limits.borrow().connections_per_ip;
+                        let Some(slot) = counts.acquire(addr.ip(), max) else {
+                            warn!("Turning away admin {}: {} connections from that address are open", addr, max);
+                            connections.spawn(reject(socket, max));
+                            continue;
+                        };
+                        info!("New admin connection from {}", addr);
+                        connections.spawn(handle_connection(socket, node.clone(), None, limits.clone(), slot, true));
+                    }
+                    Err(e) => {
+                        error!("Error accepting admin connection: {}", e);
+                    }
+                }
             }
+
+            // Reap finished connections so the set does not grow without bound
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+
+            _ = &mut shutdown => break,
+        }
+    }
+
+    info!("Shutting down, no longer accepting connections");
+    drop(listener);
+    drop(admin_listener);
+
+    // The node hands off leadership, sends its clients elsewhere and closes their
+    // sessions; each connection ends once it has written out its queue.
+    let drained = tokio::time::timeout(SHUTDOWN_TIMEOUT, async {
+        if let Err(e) = node.shutdown().await {
+            error!("Failed to shut down node: {}", e);
+        }
+        while connections.join_next().await.is_some() {}
+    })
+    .await;
+    if drained.is_err() {
+        warn!("Shutdown took longer than {:?}, dropping {} connections", SHUTDOWN_TIMEOUT, connections.len());
+    }
+
+    info!("Server {} stopped", config.id);
+    Ok(())
+}
+
+// Reads the rate limits file again on every SIGHUP. A file that fails to parse leaves
+// the limits as they were.
+async fn reload_rate_limits(path: PathBuf, node: NodeHandle, limits: watch::Sender<RateLimits>) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
             Err(e) => {
-                error!("Error accepting connection: {}", e);
+                warn!("Cannot listen for SIGHUP, rate limits will not be reloaded: {}", e);
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            match RateLimits::load(&path) {
+                Ok(new_limits) => {
+                    info!("Reloaded rate limits from {}", path.display());
+                    limits.send_replace(new_limits.clone());
+                    let _ = node.set_rate_limits(new_limits).await;
+                }
+                Err(e) => error!("Keeping the current rate limits: {:?}", e),
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = (path, node, limits);
+}
+
+// Tells a client with too many connections open to come back later, then hangs up.
+async fn reject(socket: TcpStream, max: usize) {
+    let Ok(mut client) = ChatClientChannel::from_stream(socket) else {
+        return;
+    };
+    let code = ErrorCode::RateLimited { retry_after_ms: CONNECTION_RETRY_AFTER.as_millis() as u64 };
+    let details = format!("too many connections from your address, at most {} may be open", max);
+    let _ = client.send_response(&ChatResponse::error(code, details)).await;
+}
+
+// Resolves on SIGTERM, or on Ctrl-C from a terminal.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = terminate.recv() => {}
+                    _ = tokio::signal::ctrl_c() => {}
+                }
+                return;
             }
+            Err(e) => warn!("Cannot listen for SIGTERM: {}", e),
         }
     }
+    let _ = tokio::signal::ctrl_c().await;
 }
 
-async fn handle_connection(socket: TcpStream) {
-    let mut client = ChatClientChannel::from_stream(socket).unwrap();
+// The connection holds `_slot` until it closes, which counts it against its address.
+// Connections to the admin port take admin commands and nothing else; chat connections
+// take everything else.
+async fn handle_connection(
+    socket: TcpStream,
+    node: NodeHandle,
+    faults: Option<FaultPolicy>,
+    limits: watch::Receiver<RateLimits>,
+    _slot: ConnectionSlot,
+    admin: bool,
+) {
+    let addr = match socket.peer_addr() {
+        Ok(addr) => addr,
+        Err(e) => {
+            error!("Failed to get the peer address of a connection: {}", e);
+            return;
+        }
+    };
+    let mut client = match ChatClientChannel::from_stream(socket) {
+        Ok(client) => client,
+        Err(e) => {
+            error!("Failed to set up connection: {}", e);
+            return;
+        }
+    };
+    if let Some(policy) = faults {
+        client = client.with_faults(policy);
+    }
+
+    let (outbound_tx, mut outbound_rx) = mpsc::channel(OUTBOUND_QUEUE_SIZE);
+    let session = match node.connect(outbound_tx, addr.ip()).await {
+        Ok(session) => session,
+        Err(e) => {
+            error!("Failed to register session: {}", e);
+            return;
+        }
+    };
 
+    let mut malformed = 0;
+    let mut commands = TokenBucket::new(limits.borrow().connection_commands, Instant::now());
     loop {
+        tokio::select! {
+            result = client.receive_command() => {
+                let rate = limits.borrow().connection_commands;
+                let result = match result {
+                    Ok(cmd) if let Err(wait) = commands.take(rate, Instant::now()) => {
+                        let code = ErrorCode::RateLimited { retry_after_ms: wait.as_millis() as u64 + 1 };
+                        let error = ErrorResponse::new(code, "too many commands on this connection").for_command(&cmd);
+                        if client.send_response(&ChatResponse::Error(error)).await.is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+                    Ok(cmd) if admin != matches!(cmd, ChatCommand::Admin(_)) => {
+                        let error = if admin {
+                            ChatError::Protocol("only admin commands are taken on this port".to_string())
+                        } else {
+                            ChatError::Unauthorized("admin commands are only taken on the admin port".to_string())
+                        };
+                        if client.send_response(&error.into()).await.is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+                    Ok(cmd) => match cmd {
+                        ChatCommand::Admin(command) => node.admin(session, command).await,
+                        _ if cmd.read_consistency().is_some() => node.query(session, cmd).await,
+                        _ => node.propose(session, cmd).await,
+                    },
 
-        match client.receive_command().await {
-            Ok(cmd) => {
-                match cmd {
-                    ChatCommand::SendMessage(message) => {
-                        let response = ChatResponse::MessageReceived(message);
-                        let mut response_bytes = serde_json::to_vec(&response).unwrap();
-                        client.send_bytes(&mut response_bytes).await.unwrap();
+                    // The bad line has been consumed, so the connection can carry on.
+                    Err(ChatError::Protocol(e)) => {
+                        malformed += 1;
+                        warn!(
+                            "Malformed command {} of {} from session {}: {}",
+                            malformed, MAX_MALFORMED_COMMANDS, session, e
+                        );
+                        let response = ChatResponse::from(ChatError::Protocol(e));
+                        if client.send_response(&response).await.is_err() || malformed >= MAX_MALFORMED_COMMANDS {
+                            break;
+                        }
+                        continue;
                     }
 
-                    ChatCommand::Join(_) => todo!(),
+                    Err(e) => {
+                        error!("Error reading from socket: {}", e);
+                        break;
+                    }
+                };
 
-                    ChatCommand::Leave(_) => todo!(),
+                // The node task has stopped; tell the client before hanging up.
+                if let Err(e) = result {
+                    error!("Failed to hand command to the node: {}", e);
+                    let _ = client.send_response(&e.into()).await;
+                    break;
                 }
             }
 
-            Err(e) => {
-                error!("Error reading from socket: {}", e);
-                break;
+            response = outbound_rx.recv() => {
+                // The node closes the queue to end the session, for example to kick the user.
+                let Some(response) = response else {
+                    break;
+                };
+                if let Err(e) = client.send_response(&response).await {
+                    error!("Error writing to socket: {}", e);
+                    break;
+                }
             }
         }
     }
+
+    let _ = node.disconnect(session).await;
 }
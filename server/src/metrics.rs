@@ -0,0 +1,202 @@
+//! Counters and gauges describing a node, served in the Prometheus text format on
+//! `/metrics`.
+//!
+//! The node task updates them as it goes; gauges that are cheap to read off its state
+//! are refreshed once per tick rather than on every change.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Upper bounds in seconds of the latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 12] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+/// Rooms that get a series of their own; messages to any other room are counted under
+/// `OTHER_ROOMS`, so anyone creating rooms cannot make the output grow without bound.
+const MAX_ROOM_LABELS: usize = 100;
+const OTHER_ROOMS: &str = "other";
+
+pub struct Histogram {
+    /// Observations per bucket, with one more for those above the last bound.
+    counts: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self { counts: std::array::from_fn(|_| AtomicU64::new(0)), sum_micros: AtomicU64::new(0) }
+    }
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let bucket = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound).unwrap_or(LATENCY_BUCKETS.len());
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} histogram", name, help, name);
+        let mut total = 0;
+        for (i, count) in self.counts.iter().enumerate() {
+            total += count.load(Ordering::Relaxed);
+            let bound = LATENCY_BUCKETS.get(i).map_or("+Inf".to_string(), |bound| bound.to_string());
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, total);
+        }
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{}_sum {}\n{}_count {}", name, sum, name, total);
+    }
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    pub connected_clients: AtomicU64,
+    /// Responses waiting in the outbound queues of all sessions, and in the fullest one.
+    pub outbound_queued: AtomicU64,
+    pub outbound_queue_max: AtomicU64,
+    pub responses_dropped: AtomicU64,
+    pub term: AtomicU64,
+    pub is_leader: AtomicU64,
+    pub leader_changes: AtomicU64,
+    pub commit_index: AtomicU64,
+    pub applied_index: AtomicU64,
+    /// Entries in the log that have not been compacted into a snapshot.
+    pub log_entries: AtomicU64,
+    pub snapshots_taken: AtomicU64,
+    pub snapshots_installed: AtomicU64,
+    /// From a proposal reaching the leader to the leader applying it.
+    pub commit_latency: Histogram,
+    /// Time to persist new entries and the hard state, fsync included.
+    pub storage_sync: Histogram,
+    /// Messages applied per room, for the first rooms messages were sent to.
+    messages: Mutex<BTreeMap<String, u64>>,
+}
+
+impl Metrics {
+    pub fn message_applied(&self, room: &str) {
+        let mut messages = self.messages.lock().unwrap();
+        let labelled = messages.len() - usize::from(messages.contains_key(OTHER_ROOMS));
+        let room = if messages.contains_key(room) || labelled < MAX_ROOM_LABELS { room } else { OTHER_ROOMS };
+        match messages.get_mut(room) {
+            Some(count) => *count += 1,
+            None => {
+                messages.insert(room.to_string(), 1);
+            }
+        }
+    }
+
+    /// The metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: &AtomicU64| {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+            let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+        };
+        metric(
+            "raft_chat_connected_clients",
+            "gauge",
+            "Client sessions connected to this node.",
+            &self.connected_clients,
+        );
+        metric(
+            "raft_chat_outbound_queued",
+            "gauge",
+            "Responses waiting to be written to clients.",
+            &self.outbound_queued,
+        );
+        metric(
+            "raft_chat_outbound_queue_max_depth",
+            "gauge",
+            "Responses waiting in the fullest client queue.",
+            &self.outbound_queue_max,
+        );
+        metric(
+            "raft_chat_responses_dropped_total",
+            "counter",
+            "Responses dropped because a client's queue was full.",
+            &self.responses_dropped,
+        );
+        metric("raft_chat_term", "gauge", "Current Raft term.", &self.term);
+        metric("raft_chat_is_leader", "gauge", "Whether this node is the leader.", &self.is_leader);
+        metric(
+            "raft_chat_leader_changes_total",
+            "counter",
+            "Times this node saw the leader change.",
+            &self.leader_changes,
+        );
+        metric("raft_chat_commit_index", "gauge", "Highest log index known to be committed.", &self.commit_index);
+        metric("raft_chat_applied_index", "gauge", "Highest log index applied to the chat state.", &self.applied_index);
+        metric("raft_chat_log_entries", "gauge", "Log entries not yet compacted into a snapshot.", &self.log_entries);
+        metric(
+            "raft_chat_snapshots_taken_total",
+            "counter",
+            "Snapshots this node compacted its log into.",
+            &self.snapshots_taken,
+        );
+        metric(
+            "raft_chat_snapshots_installed_total",
+            "counter",
+            "Snapshots received from the leader.",
+            &self.snapshots_installed,
+        );
+
+        self.commit_latency.render(
+            &mut out,
+            "raft_chat_commit_latency_seconds",
+            "Time from a proposal reaching the leader to the leader applying it.",
+        );
+        self.storage_sync.render(
+            &mut out,
+            "raft_chat_storage_sync_seconds",
+            "Time to persist log entries and the hard state, fsync included.",
+        );
+
+        let _ = writeln!(out, "# HELP raft_chat_messages_total Chat messages applied, by room.");
+        let _ = writeln!(out, "# TYPE raft_chat_messages_total counter");
+        for (room, count) in self.messages.lock().unwrap().iter() {
+            let room = room.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+            let _ = writeln!(out, "raft_chat_messages_total{{room=\"{}\"}} {}", room, count);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_cumulative_buckets_and_escaped_labels() {
+        let metrics = Metrics::default();
+        metrics.commit_latency.observe(Duration::from_millis(3));
+        metrics.commit_latency.observe(Duration::from_millis(40));
+        metrics.commit_latency.observe(Duration::from_secs(9));
+        metrics.message_applied("general");
+        metrics.message_applied("say \"hi\"");
+
+        let text = metrics.render();
+        assert!(text.contains("raft_chat_commit_latency_seconds_bucket{le=\"0.0025\"} 0\n"));
+        assert!(text.contains("raft_chat_commit_latency_seconds_bucket{le=\"0.005\"} 1\n"));
+        assert!(text.contains("raft_chat_commit_latency_seconds_bucket{le=\"5\"} 2\n"));
+        assert!(text.contains("raft_chat_commit_latency_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(text.contains("raft_chat_commit_latency_seconds_sum 9.043\n"));
+        assert!(text.contains("raft_chat_messages_total{room=\"say \\\"hi\\\"\"} 1\n"));
+    }
+
+    #[test]
+    fn rooms_past_the_label_limit_share_one_series() {
+        let metrics = Metrics::default();
+        for room in 0..MAX_ROOM_LABELS + 5 {
+            metrics.message_applied(&format!("room{}", room));
+        }
+        metrics.message_applied("room0");
+
+        let text = metrics.render();
+        assert_eq!(text.matches("raft_chat_messages_total{").count(), MAX_ROOM_LABELS + 1);
+        assert!(text.contains("raft_chat_messages_total{room=\"room0\"} 2\n"));
+        assert!(text.contains("raft_chat_messages_total{room=\"other\"} 5\n"));
+    }
+}
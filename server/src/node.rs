@@ -0,0 +1,1077 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{BuildHasher, RandomState};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use shared::{
+    AdminCommand, AdminResponse, ChatCommand, ChatError, ChatEvent, ChatResponse, ClusterStatus, ErrorCode,
+    ErrorResponse, FollowerStatus, ReadConsistency,
+};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, error, info, warn};
+
+use crate::auth;
+use crate::clock::{Clock, SystemClock};
+use crate::config::ServerConfig;
+use crate::limits::{MessageLimiter, RateLimits};
+use crate::metrics::Metrics;
+use crate::peer::{PeerMessage, Peers, Transport};
+use crate::raft::{self, ConfChange, LogEntry, NodeId, Payload, Raft, RaftError};
+use crate::state::{Audience, ChatState};
+use crate::storage::{FileStorage, Restored, Storage};
+
+pub type SessionId = u64;
+
+/// Number of applied entries kept in the log before it is compacted into a snapshot.
+const SNAPSHOT_THRESHOLD: u64 = 1000;
+
+/// Length of a Raft tick. Elections time out after 10-20 ticks without a leader.
+pub const TICK_INTERVAL: Duration = Duration::from_millis(100);
+const ELECTION_TICK: u32 = 10;
+const HEARTBEAT_TICK: u32 = 1;
+
+/// How long a read may wait for the leader or for its minimum index to be applied.
+const READ_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How long a leadership transfer may take: the leader gives up after an election
+/// timeout, and the election itself needs some time on top of that.
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Most requests handled before their work is persisted and sent. Everything queued up
+/// to this many is handled together, so a burst of messages costs one log append and
+/// one fsync, and goes out to each follower in one message.
+const MAX_BATCH: usize = 256;
+
+/// How often rate limit buckets of users and rooms that went quiet are dropped.
+const LIMITER_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long a kicked user stays out unless the admin says otherwise.
+const DEFAULT_KICK_SECS: u64 = 600;
+
+const NO_LEADER: &str = "no leader available, try again shortly";
+
+/// The session a proposal came from, so it can be acknowledged once it commits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Origin {
+    pub node: NodeId,
+    pub session: SessionId,
+}
+
+/// A client command as it is stored in the replicated log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Proposal {
+    pub origin: Origin,
+    /// User the command is executed as.
+    pub user: String,
+    pub command: ChatCommand,
+    /// When the leader took the proposal, in milliseconds since the Unix epoch. The state
+    /// machine applies the command as of this time, so every node agrees on it.
+    #[serde(default)]
+    pub time: u64,
+    /// Proof that the session may act as `user`, checked against the credential the nick
+    /// was taken with. Empty for guests.
+    #[serde(default)]
+    pub credential: String,
+    /// Address the session connected from, if it came over the network.
+    #[serde(default)]
+    pub addr: Option<IpAddr>,
+}
+
+struct Session {
+    /// The nick the session's commands run as. A nick the session asked for is used right
+    /// away; commands fail to apply if the nick turns out to belong to someone else.
+    nick: String,
+    /// The name the session had as a guest, which it goes back to if a nick is refused.
+    guest: String,
+    credential: String,
+    addr: Option<IpAddr>,
+    outbound: mpsc::Sender<ChatResponse>,
+}
+
+/// An admin's leadership transfer, answered once `to` has become the leader.
+struct PendingTransfer {
+    session: SessionId,
+    to: NodeId,
+    deadline: Instant,
+}
+
+/// A shutdown waiting for leadership to move before the node lets its clients go.
+struct PendingShutdown {
+    reply: oneshot::Sender<()>,
+    deadline: Instant,
+}
+
+/// An admin's membership change, answered once the entry at `index` is applied.
+struct PendingConfChange {
+    session: SessionId,
+    index: u64,
+}
+
+/// What a snapshot holds besides the log: the chat state and the addresses of the
+/// nodes added since the cluster started.
+#[derive(Serialize, Deserialize)]
+struct SnapshotData<'a> {
+    state: Cow<'a, ChatState>,
+    peers: BTreeMap<NodeId, String>,
+}
+
+/// A query waiting for its read index to be confirmed and then applied.
+struct PendingQuery {
+    session: SessionId,
+    command: ChatCommand,
+    deadline: Instant,
+}
+
+pub enum NodeRequest {
+    Connect {
+        session: SessionId,
+        outbound: mpsc::Sender<ChatResponse>,
+        addr: Option<IpAddr>,
+    },
+    Disconnect {
+        session: SessionId,
+    },
+    Propose {
+        session: SessionId,
+        command: ChatCommand,
+    },
+    Query {
+        session: SessionId,
+        command: ChatCommand,
+    },
+    Admin {
+        session: SessionId,
+        command: AdminCommand,
+    },
+    Status {
+        reply: oneshot::Sender<ClusterStatus>,
+    },
+    Shutdown {
+        reply: oneshot::Sender<()>,
+    },
+    SetRateLimits(RateLimits),
+    Peer(PeerMessage),
+}
+
+/// Cheap handle used by connection tasks to talk to the node task.
+#[derive(Clone)]
+pub struct NodeHandle {
+    tx: mpsc::Sender<NodeRequest>,
+    next_session: Arc<AtomicU64>,
+    metrics: Arc<Metrics>,
+}
+
+impl NodeHandle {
+    /// Registers a new client session from `addr` whose responses are pushed into
+    /// `outbound`.
+    pub async fn connect(&self, outbound: mpsc::Sender<ChatResponse>, addr: IpAddr) -> ChatEvent<SessionId> {
+        let session = self.next_session.fetch_add(1, Ordering::Relaxed);
+        self.send(NodeRequest::Connect { session, outbound, addr: Some(addr) }).await?;
+        Ok(session)
+    }
+
+    pub async fn disconnect(&self, session: SessionId) -> ChatEvent<()> {
+        self.send(NodeRequest::Disconnect { session }).await
+    }
+
+    /// Proposes `command` to the cluster, forwarding it to the leader if necessary. The
+    /// outcome (`Ack` or `Error`) is delivered to the session's outbound queue once the
+    /// entry has been committed and applied.
+    pub async fn propose(&self, session: SessionId, command: ChatCommand) -> ChatEvent<()> {
+        self.send(NodeRequest::Propose { session, command }).await
+    }
+
+    /// Answers a read-only command from the local state machine without touching the log,
+    /// after waiting for the consistency the command asks for.
+    pub async fn query(&self, session: SessionId, command: ChatCommand) -> ChatEvent<()> {
+        self.send(NodeRequest::Query { session, command }).await
+    }
+
+    /// Runs an operator command. The outcome is delivered to the session's outbound queue.
+    pub async fn admin(&self, session: SessionId, command: AdminCommand) -> ChatEvent<()> {
+        self.send(NodeRequest::Admin { session, command }).await
+    }
+
+    /// Prepares the node to exit: hands leadership to another voter if this node leads,
+    /// then sends every client elsewhere, closes their sessions and syncs storage.
+    /// Returns once that is done; the connections still have to drain their queues.
+    pub async fn shutdown(&self) -> ChatEvent<()> {
+        let (reply, done) = oneshot::channel();
+        self.send(NodeRequest::Shutdown { reply }).await?;
+        done.await.map_err(|_| ChatError::Internal("node task has stopped".to_string()))
+    }
+
+    /// Replaces the rate limits on messages, for example after the limits file changed.
+    pub async fn set_rate_limits(&self, limits: RateLimits) -> ChatEvent<()> {
+        self.send(NodeRequest::SetRateLimits(limits)).await
+    }
+
+    /// The node's view of the cluster, as `AdminCommand::Status` reports it.
+    pub async fn status(&self) -> ChatEvent<ClusterStatus> {
+        let (reply, status) = oneshot::channel();
+        self.send(NodeRequest::Status { reply }).await?;
+        status.await.map_err(|_| ChatError::Internal("node task has stopped".to_string()))
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Hands a message received from another node to the node task.
+    pub async fn peer_message(&self, msg: PeerMessage) -> ChatEvent<()> {
+        self.send(NodeRequest::Peer(msg)).await
+    }
+
+    async fn send(&self, request: NodeRequest) -> ChatEvent<()> {
+        self.tx
+            .send(request)
+            .await
+            .map_err(|_| ChatError::Internal("node task has stopped".to_string()))
+    }
+}
+
+/// One member of the cluster. It owns the Raft core, its storage and the chat state
+/// machine; all of them are only touched by the node task, so entries are persisted,
+/// replicated and applied strictly in order.
+pub struct Node {
+    id: NodeId,
+    raft: Raft,
+    storage: Box<dyn Storage>,
+    peers: Box<dyn Transport>,
+    clock: Box<dyn Clock>,
+    state: ChatState,
+    sessions: HashMap<SessionId, Session>,
+    /// Leader as of the last processed ready, to log leadership changes.
+    leader: Option<NodeId>,
+    next_read: u64,
+    /// Queries waiting for the leader to confirm a read index, by read ID.
+    reads: HashMap<u64, PendingQuery>,
+    /// Queries waiting for the state machine to apply their read index.
+    confirmed_reads: Vec<(u64, PendingQuery)>,
+    transfer: Option<PendingTransfer>,
+    conf_change: Option<PendingConfChange>,
+    shutdown: Option<PendingShutdown>,
+    /// Client addresses of the other nodes, to send clients to when shutting down.
+    peer_clients: BTreeMap<NodeId, String>,
+    rate_limits: RateLimits,
+    /// Messages sent by the users of this node, per user and per room.
+    limiter: MessageLimiter,
+    limiter_pruned: Instant,
+    metrics: Arc<Metrics>,
+    /// Term and time of the proposals this node appended as leader, to measure how long
+    /// they take to commit.
+    proposed: BTreeMap<u64, (u64, Instant)>,
+}
+
+impl Node {
+    /// Creates a node from its configuration, restoring whatever it had on disk.
+    pub fn open(config: &ServerConfig) -> ChatEvent<Self> {
+        let (storage, restored) = FileStorage::open(&config.data_dir)?;
+        let peers = Peers::start(&config.peers);
+        Self::new(config, Box::new(storage), restored, Box::new(peers), Box::new(SystemClock))
+    }
+
+    /// Creates a node on top of the given storage, which held `restored`, and transport.
+    pub fn new(
+        config: &ServerConfig,
+        storage: Box<dyn Storage>,
+        restored: Restored,
+        mut peers: Box<dyn Transport>,
+        clock: Box<dyn Clock>,
+    ) -> ChatEvent<Self> {
+        let state = match &restored.snapshot {
+            Some(snapshot) => restore_snapshot(&snapshot.data, peers.as_mut(), config.id)?,
+            None => ChatState::default(),
+        };
+
+        let raft_config = raft::Config {
+            id: config.id,
+            voters: config.voters(),
+            learners: config.learners(),
+            election_tick: ELECTION_TICK,
+            heartbeat_tick: HEARTBEAT_TICK,
+            pre_vote: config.pre_vote,
+            check_quorum: config.check_quorum,
+            max_clock_drift: config.max_clock_drift.as_millis().div_ceil(TICK_INTERVAL.as_millis()) as u32,
+            seed: config.id,
+        };
+        let raft = Raft::new(raft_config, restored.hard_state, restored.snapshot, restored.entries);
+        let now = clock.now();
+
+        Ok(Self {
+            id: config.id,
+            raft,
+            storage,
+            peers,
+            clock,
+            state,
+            sessions: HashMap::new(),
+            leader: None,
+            next_read: 1,
+            reads: HashMap::new(),
+            confirmed_reads: Vec::new(),
+            transfer: None,
+            conf_change: None,
+            shutdown: None,
+            peer_clients: config.peer_clients.clone(),
+            rate_limits: config.rate_limits.clone(),
+            limiter: MessageLimiter::default(),
+            limiter_pruned: now,
+            metrics: Arc::default(),
+            proposed: BTreeMap::new(),
+        })
+    }
+
+    /// Spawns the node task and returns a handle to it.
+    pub fn spawn(self) -> NodeHandle {
+        let (tx, rx) = mpsc::channel(1024);
+        let metrics = self.metrics.clone();
+        tokio::spawn(self.run(rx));
+
+        NodeHandle {
+            tx,
+            next_session: Arc::new(AtomicU64::new(1)),
+            metrics,
+        }
+    }
+
+    async fn run(mut self, mut rx: mpsc::Receiver<NodeRequest>) {
+        let mut ticker = tokio::time::interval(TICK_INTERVAL);
+
+        loop {
+            tokio::select! {
+                request = rx.recv() => match request {
+                    Some(request) => {
+                        self.handle(request);
+                        for _ in 1..MAX_BATCH {
+                            let Ok(request) = rx.try_recv() else {
+                                break;
+                            };
+                            self.handle(request);
+                        }
+                    }
+                    None => return,
+                },
+                _ = ticker.tick() => self.tick(),
+            }
+
+            if let Err(e) = self.process_ready() {
+                // Carrying on without stable storage could lose acknowledged entries.
+                error!("Stopping node: {}", e);
+                return;
+            }
+        }
+    }
+
+    #[cfg(test)]
+    pub fn raft(&self) -> &Raft {
+        &self.raft
+    }
+
+    /// Advances the Raft core by one tick and times out whatever waited too long.
+    pub fn tick(&mut self) {
+        self.raft.tick();
+        self.expire_reads();
+        self.expire_transfer();
+        self.maybe_finish_shutdown();
+        self.update_metrics();
+
+        let now = self.clock.now();
+        if now >= self.limiter_pruned + LIMITER_PRUNE_INTERVAL {
+            self.limiter.prune(&self.rate_limits, now);
+            self.limiter_pruned = now;
+        }
+    }
+
+    // Refreshes the gauges that are read off the node's state
+    fn update_metrics(&self) {
+        let metrics = &self.metrics;
+        let log = self.raft.log();
+        let queued = self.sessions.values().map(|s| s.outbound.max_capacity() - s.outbound.capacity());
+        let (total, max) = queued.fold((0, 0), |(total, max), depth| (total + depth, max.max(depth)));
+
+        metrics.connected_clients.store(self.sessions.len() as u64, Ordering::Relaxed);
+        metrics.outbound_queued.store(total as u64, Ordering::Relaxed);
+        metrics.outbound_queue_max.store(max as u64, Ordering::Relaxed);
+        metrics.term.store(self.raft.term(), Ordering::Relaxed);
+        metrics.is_leader.store(u64::from(self.raft.leader() == Some(self.id)), Ordering::Relaxed);
+        metrics.commit_index.store(log.commit_index(), Ordering::Relaxed);
+        metrics.applied_index.store(log.applied(), Ordering::Relaxed);
+        metrics.log_entries.store(log.last_index() - log.snapshot().index, Ordering::Relaxed);
+    }
+
+    pub fn handle(&mut self, request: NodeRequest) {
+        match request {
+            NodeRequest::Connect { session, outbound, addr } => {
+                let nick = guest_nick(self.id, session);
+                // Dropping the queue right away closes the connection of a kicked user.
+                if let Err(e) = self.state.check_suspended(&nick, addr, self.clock.unix_millis()) {
+                    let _ = outbound.try_send(e.into());
+                    return;
+                }
+                let guest = nick.clone();
+                self.sessions.insert(session, Session { nick, guest, credential: String::new(), addr, outbound });
+            }
+            NodeRequest::Disconnect { session } => {
+                self.sessions.remove(&session);
+            }
+            NodeRequest::Propose { session, command: ChatCommand::Admin(_) } => {
+                let error = ChatError::Unauthorized("admin commands are only taken on the admin port".to_string());
+                self.deliver(session, error.into());
+            }
+            NodeRequest::Propose { session, command: ChatCommand::Nick { nick, secret } } => {
+                let Some(s) = self.sessions.get_mut(&session) else {
+                    return;
+                };
+                // Only the secret's hash goes into the log, salted as the nick was registered.
+                let credential = if secret.is_empty() {
+                    String::new()
+                } else {
+                    auth::credential(&nick, &secret, self.state.credential(&nick))
+                };
+                s.nick = nick.clone();
+                s.credential = credential.clone();
+
+                let origin = Origin { node: self.id, session };
+                let command = ChatCommand::Nick { nick: nick.clone(), secret: String::new() };
+                let (time, addr) = (self.clock.unix_millis(), s.addr);
+                self.propose(Proposal { origin, user: nick, command, time, credential, addr });
+            }
+            NodeRequest::Propose { session, command } => {
+                let Some(s) = self.sessions.get(&session) else {
+                    return;
+                };
+                if let Err(e) = command.validate() {
+                    return self.deliver(session, ChatResponse::Error(ErrorResponse::from(e).for_command(&command)));
+                }
+                let (user, credential, addr) = (s.nick.clone(), s.credential.clone(), s.addr);
+                let now = self.clock.now();
+                if let ChatCommand::SendMessage(message) = &command
+                    && let Err(wait) = self.limiter.check(&self.rate_limits, &user, addr, &message.room, now)
+                {
+                    let details = format!("{} is sending messages to {} too fast", user, message.room);
+                    let code = ErrorCode::RateLimited { retry_after_ms: wait.as_millis() as u64 + 1 };
+                    self.deliver(session, ChatResponse::Error(ErrorResponse::new(code, details).for_command(&command)));
+                    return;
+                }
+                let origin = Origin { node: self.id, session };
+                self.propose(Proposal { origin, user, command, time: self.clock.unix_millis(), credential, addr });
+            }
+            NodeRequest::Query { session, command } => {
+                self.query(session, command);
+            }
+            NodeRequest::Admin { session, command } => {
+                self.admin(session, command);
+            }
+            NodeRequest::Status { reply } => {
+                let _ = reply.send(self.status());
+            }
+            NodeRequest::Shutdown { reply } => self.start_shutdown(reply),
+            NodeRequest::SetRateLimits(limits) => {
+                info!("Rate limits are now {:?}", limits);
+                self.rate_limits = limits;
+            }
+            NodeRequest::Peer(PeerMessage::Raft(msg)) => {
+                if msg.to == self.id {
+                    self.raft.step(msg);
+                }
+            }
+            // Admin commands are only taken on the admin port, which a peer connection
+            // would get around.
+            NodeRequest::Peer(PeerMessage::Forward(proposal)) if matches!(proposal.command, ChatCommand::Admin(_)) => {
+                warn!("Refusing an admin command forwarded by node {}", proposal.origin.node);
+            }
+            // The time a forwarded proposal arrives with decides when bans, mutes and
+            // retention run out, so the leader goes by its own clock instead.
+            NodeRequest::Peer(PeerMessage::Forward(mut proposal)) => {
+                proposal.time = self.clock.unix_millis();
+                self.propose(proposal);
+            }
+        }
+    }
+
+    fn propose(&mut self, proposal: Proposal) {
+        match self.raft.propose(Payload::Command(Box::new(proposal.clone()))) {
+            Ok(index) => {
+                debug!("Proposed entry {}", index);
+                self.proposed.insert(index, (self.raft.term(), self.clock.now()));
+            }
+            Err(RaftError::NotLeader { leader: Some(leader) }) if proposal.origin.node == self.id => {
+                self.peers.send(leader, PeerMessage::Forward(proposal));
+            }
+            Err(e) => {
+                // Forwarded proposals are not forwarded again; their sender times out instead.
+                debug!("Rejecting proposal: {}", e);
+                if proposal.origin.node == self.id {
+                    let error = ErrorResponse::from(e).for_command(&proposal.command);
+                    self.deliver(proposal.origin.session, ChatResponse::Error(error));
+                }
+            }
+        }
+    }
+
+    fn admin(&mut self, session: SessionId, command: AdminCommand) {
+        match command {
+            AdminCommand::TransferLeadership { to } if self.raft.leader() == Some(to) => {
+                let response = AdminResponse::LeadershipTransferred { to, term: self.raft.term() };
+                self.deliver(session, ChatResponse::Admin(response));
+            }
+            AdminCommand::TransferLeadership { to } => match self.raft.transfer_leadership(to) {
+                Ok(()) => {
+                    let deadline = self.clock.now() + TRANSFER_TIMEOUT;
+                    self.transfer = Some(PendingTransfer { session, to, deadline });
+                }
+                Err(e) => self.deliver(session, ChatResponse::Error(e.into())),
+            },
+            AdminCommand::AddLearner { id, addr } => {
+                self.propose_conf_change(session, ConfChange::AddLearner { id, addr });
+            }
+            AdminCommand::Promote { id } => self.propose_conf_change(session, ConfChange::Promote { id }),
+            AdminCommand::RemoveNode { id } => self.propose_conf_change(session, ConfChange::Remove { id }),
+            AdminCommand::Status => {
+                let status = self.status();
+                self.deliver(session, ChatResponse::Admin(AdminResponse::Status(status)));
+            }
+            AdminCommand::AuditLog { room, limit } => {
+                let entries = self.state.audit_log(room.as_deref(), limit);
+                self.deliver(session, ChatResponse::Admin(AdminResponse::AuditLog(entries)));
+            }
+            AdminCommand::Snapshot => match self.snapshot() {
+                Ok(index) => self.deliver(session, ChatResponse::Admin(AdminResponse::SnapshotTaken { index })),
+                Err(e) => {
+                    error!("Failed to take snapshot: {}", e);
+                    self.deliver(session, e.into());
+                }
+            },
+            // Sessions live on whichever node the user connected to, so the kick goes
+            // through the log for every node to apply. Like membership changes it is not
+            // forwarded, since followers refuse to forward admin commands.
+            command @ AdminCommand::KickUser { .. } => {
+                let Some(user) = self.sessions.get(&session).map(|s| s.nick.clone()) else {
+                    return;
+                };
+                let command = ChatCommand::Admin(command);
+                if let Err(e) = command.validate() {
+                    return self.deliver(session, e.into());
+                }
+                if let Some(leader) = self.raft.leader().filter(|leader| *leader != self.id) {
+                    let error = format!("kicks must be sent to the leader, node {}", leader);
+                    let code = ErrorCode::NotLeader { leader: Some(leader) };
+                    return self.deliver(session, ChatResponse::error(code, error));
+                }
+                let origin = Origin { node: self.id, session };
+                let time = self.clock.unix_millis();
+                self.propose(Proposal { origin, user, command, time, credential: String::new(), addr: None });
+            }
+        }
+    }
+
+    fn status(&self) -> ClusterStatus {
+        let log = self.raft.log();
+        let membership = self.raft.membership();
+        let followers = self
+            .raft
+            .progress()
+            .iter()
+            .map(|(id, progress)| FollowerStatus {
+                id: *id,
+                match_index: progress.match_index,
+                next_index: progress.next_index,
+                lag: log.last_index().saturating_sub(progress.match_index),
+                state: format!("{:?}", progress.state),
+                active: progress.recent_active,
+            })
+            .collect();
+
+        ClusterStatus {
+            id: self.id,
+            role: format!("{:?}", self.raft.role()),
+            term: self.raft.term(),
+            leader: self.raft.leader(),
+            commit_index: log.commit_index(),
+            leader_commit: self.raft.leader_commit(),
+            applied_index: log.applied(),
+            last_index: log.last_index(),
+            snapshot_index: log.snapshot().index,
+            voters: membership.voters.iter().copied().collect(),
+            learners: membership.learners.iter().copied().collect(),
+            peers: self.peers.addresses(),
+            followers,
+        }
+    }
+
+    // Membership changes are not forwarded: the admin is pointed at the leader instead.
+    fn propose_conf_change(&mut self, session: SessionId, change: ConfChange) {
+        match self.raft.propose_conf_change(change) {
+            Ok(index) => {
+                if let Some(previous) = self.conf_change.replace(PendingConfChange { session, index }) {
+                    let error = "superseded by another membership change";
+                    self.deliver(previous.session, ChatResponse::error(ErrorCode::InvalidCommand, error));
+                }
+            }
+            Err(RaftError::NotLeader { leader: Some(leader) }) => {
+                let error = format!("membership changes must be sent to the leader, node {}", leader);
+                self.deliver(session, ChatResponse::error(ErrorCode::NotLeader { leader: Some(leader) }, error));
+            }
+            Err(e) => self.deliver(session, ChatResponse::Error(e.into())),
+        }
+    }
+
+    fn start_shutdown(&mut self, reply: oneshot::Sender<()>) {
+        info!("Node {} shutting down", self.id);
+        let mut deadline = self.clock.now();
+
+        // The voter furthest along takes over fastest.
+        let membership = self.raft.membership();
+        let successor = self
+            .raft
+            .progress()
+            .iter()
+            .filter(|(id, _)| **id != self.id && membership.voters.contains(id))
+            .max_by_key(|(_, progress)| progress.match_index)
+            .map(|(id, _)| *id);
+        if self.raft.leader() == Some(self.id)
+            && let Some(to) = successor
+            && self.raft.transfer_leadership(to).is_ok()
+        {
+            info!("Handing leadership to node {} before shutting down", to);
+            deadline += TRANSFER_TIMEOUT;
+        }
+        self.shutdown = Some(PendingShutdown { reply, deadline });
+        self.maybe_finish_shutdown();
+    }
+
+    // Lets the clients go once another node is known to lead, so they can be sent there,
+    // or when waiting for that takes too long
+    fn maybe_finish_shutdown(&mut self) {
+        let now = self.clock.now();
+        let handed_over = self.raft.leader().is_some_and(|leader| leader != self.id);
+        let Some(shutdown) = self.shutdown.take_if(|s| s.deadline <= now || handed_over) else {
+            return;
+        };
+
+        // Clients go to the new leader if there is one, or to any other node.
+        let leader = self.raft.leader().filter(|leader| *leader != self.id);
+        let reconnect_to = leader
+            .and_then(|leader| self.peer_clients.get(&leader))
+            .or_else(|| self.peer_clients.values().next())
+            .cloned();
+        // Dropping the sessions closes their queues, which ends each connection once it
+        // has written out what is queued.
+        for (_, session) in self.sessions.drain() {
+            let notice = ChatResponse::ServerShutdown { reconnect_to: reconnect_to.clone() };
+            if session.outbound.try_send(notice).is_err() {
+                self.metrics.responses_dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        if let Err(e) = self.storage.sync() {
+            error!("Failed to sync storage: {}", e);
+        }
+        info!("Node {} let its clients go, sending them to {:?}", self.id, reconnect_to);
+        let _ = shutdown.reply.send(());
+    }
+
+    fn expire_transfer(&mut self) {
+        if let Some(transfer) = self.transfer.take_if(|t| t.deadline <= self.clock.now()) {
+            let error = format!("leadership transfer to node {} timed out", transfer.to);
+            let error = ErrorResponse::new(ErrorCode::Internal, error).retryable();
+            self.deliver(transfer.session, ChatResponse::Error(error));
+        }
+    }
+
+    /// Carries out the work produced by the Raft core: persist, then send, then apply.
+    pub fn process_ready(&mut self) -> ChatEvent<()> {
+        if self.raft.leader() != self.leader {
+            self.leader = self.raft.leader();
+            self.metrics.leader_changes.fetch_add(1, Ordering::Relaxed);
+            info!(
+                "Node {} is now {:?} in term {} (leader: {:?})",
+                self.id,
+                self.raft.role(),
+                self.raft.term(),
+                self.leader
+            );
+
+            if let Some(transfer) = self.transfer.take_if(|t| Some(t.to) == self.leader) {
+                let response = AdminResponse::LeadershipTransferred { to: transfer.to, term: self.raft.term() };
+                self.deliver(transfer.session, ChatResponse::Admin(response));
+            }
+            self.maybe_finish_shutdown();
+        }
+
+        let ready = self.raft.ready();
+
+        if let Some(snapshot) = &ready.snapshot {
+            self.storage.save_snapshot(snapshot, &[])?;
+            self.state = restore_snapshot(&snapshot.data, self.peers.as_mut(), self.id)?;
+            self.metrics.snapshots_installed.fetch_add(1, Ordering::Relaxed);
+        }
+        // Timed on the real clock: this measures the disk, which the simulator has none of.
+        let sync_started = Instant::now();
+        if !ready.entries.is_empty() {
+            self.storage.append(&ready.entries)?;
+        }
+        if let Some(hard_state) = &ready.hard_state {
+            self.storage.save_hard_state(hard_state)?;
+        }
+        if !ready.entries.is_empty() || ready.hard_state.is_some() {
+            self.metrics.storage_sync.observe(sync_started.elapsed());
+        }
+
+        for msg in ready.messages {
+            self.peers.send(msg.to, PeerMessage::Raft(msg));
+        }
+
+        for entry in ready.committed {
+            self.apply(entry);
+        }
+
+        for read in ready.read_states {
+            let Some(query) = self.reads.remove(&read.id) else {
+                continue;
+            };
+            match read.index {
+                Some(index) => self.confirmed_reads.push((index.max(query.command.min_index()), query)),
+                None => self.deliver(query.session, no_leader()),
+            }
+        }
+        self.serve_confirmed_reads();
+
+        self.maybe_snapshot()
+    }
+
+    fn apply(&mut self, entry: LogEntry) {
+        debug!("Applying entry {}", entry.index);
+
+        // Proposals at earlier indexes were lost when another leader overwrote them.
+        let later = self.proposed.split_off(&(entry.index + 1));
+        if let Some((term, proposed_at)) = std::mem::replace(&mut self.proposed, later).remove(&entry.index)
+            && term == entry.term
+        {
+            self.metrics.commit_latency.observe(self.clock.now() - proposed_at);
+        }
+
+        // An entry other than the admin's at its index means the change was lost in a
+        // leadership change.
+        if !matches!(entry.payload, Payload::ConfChange(_))
+            && let Some(pending) = self.conf_change.take_if(|c| c.index == entry.index)
+        {
+            let error = ErrorResponse::new(ErrorCode::Internal, "membership change was lost, try again");
+            self.deliver(pending.session, ChatResponse::Error(error.retryable()));
+        }
+
+        let proposal = match entry.payload {
+            Payload::Noop => return,
+            Payload::ConfChange(change) => return self.apply_conf_change(entry.index, change),
+            Payload::Command(proposal) => *proposal,
+        };
+
+        // Only the node the proposal came from knows the session it refers to.
+        let origin = (proposal.origin.node == self.id).then_some(proposal.origin.session);
+
+        if let ChatCommand::Admin(AdminCommand::KickUser { nick, reason, duration_secs }) = proposal.command {
+            let secs = duration_secs.unwrap_or(DEFAULT_KICK_SECS);
+            self.state.suspend(&nick, proposal.time.saturating_add(secs.saturating_mul(1000)), proposal.time);
+            return self.kick(origin, nick, reason);
+        }
+        if let ChatCommand::Nick { nick, .. } = &proposal.command {
+            return self.take_nick(origin, nick, &proposal);
+        }
+
+        let result = self
+            .state
+            .admit(&proposal.user, &proposal.credential, proposal.addr, proposal.time, &proposal.command)
+            .and_then(|()| self.state.apply(entry.index, proposal.time, &proposal.user, &proposal.command));
+        match result {
+            Ok(effects) => {
+                for effect in effects {
+                    for session in self.audience_sessions(&effect.audience, origin) {
+                        self.deliver(session, effect.response.clone());
+                    }
+                }
+
+                if let ChatCommand::SendMessage(message) = &proposal.command {
+                    self.metrics.message_applied(&message.room);
+                }
+            }
+            Err(e) => {
+                if let Some(origin) = origin {
+                    let error = ErrorResponse::from(e).for_command(&proposal.command);
+                    self.deliver(origin, ChatResponse::Error(error));
+                }
+            }
+        }
+    }
+
+    fn apply_conf_change(&mut self, index: u64, change: ConfChange) {
+        match &change {
+            ConfChange::AddLearner { id, addr } if *id != self.id => self.peers.add(*id, addr.clone()),
+            ConfChange::Remove { id } if *id != self.id => self.peers.remove(*id),
+            ConfChange::Remove { .. } => warn!("Node {} was removed from the cluster", self.id),
+            _ => {}
+        }
+        self.raft.apply_conf_change(&change);
+
+        if let Some(pending) = self.conf_change.take_if(|c| c.index == index) {
+            let membership = self.raft.membership();
+            let response = AdminResponse::MembershipChanged {
+                voters: membership.voters.iter().copied().collect(),
+                learners: membership.learners.iter().copied().collect(),
+            };
+            self.deliver(pending.session, ChatResponse::Admin(response));
+        }
+    }
+
+    // Answers a session's request for a nick. A session refused the nick goes back to
+    // being a guest, unless it has asked for another nick since.
+    fn take_nick(&mut self, origin: Option<SessionId>, nick: &str, proposal: &Proposal) {
+        let credential = &proposal.credential;
+        let result = self.state.take_nick(nick, credential, proposal.addr, proposal.time);
+        let Some(origin) = origin else {
+            return;
+        };
+
+        match result {
+            Ok(()) => {
+                self.deliver(origin, ChatResponse::NickChanged(nick.to_string()));
+                self.deliver_unread_mentions(origin);
+            }
+            Err(e) => {
+                if let Some(session) = self.sessions.get_mut(&origin)
+                    && session.nick == nick
+                    && session.credential == *credential
+                {
+                    session.nick = session.guest.clone();
+                    session.credential = String::new();
+                }
+                self.deliver(origin, e.into());
+            }
+        }
+    }
+
+    // Closes this node's sessions of `nick`: dropping a session's queue ends its connection
+    // once the queue has drained.
+    fn kick(&mut self, origin: Option<SessionId>, nick: String, reason: String) {
+        if let Some(origin) = origin {
+            self.deliver(origin, ChatResponse::Admin(AdminResponse::UserKicked { nick: nick.clone() }));
+        }
+
+        let kicked: Vec<SessionId> =
+            self.sessions.iter().filter(|(_, session)| session.nick == nick).map(|(id, _)| *id).collect();
+        for session in kicked {
+            info!("Kicking {} (session {}): {}", nick, session, reason);
+            self.deliver(session, ChatResponse::Kicked { reason: reason.clone() });
+            self.sessions.remove(&session);
+        }
+    }
+
+    fn query(&mut self, session: SessionId, command: ChatCommand) {
+        let deadline = self.clock.now() + READ_TIMEOUT;
+
+        match command.read_consistency() {
+            Some(ReadConsistency::Stale) | None => {
+                // Any node can answer, once it has caught up with what the client has seen.
+                let index = command.min_index();
+                self.confirmed_reads.push((index, PendingQuery { session, command, deadline }));
+                self.serve_confirmed_reads();
+            }
+            Some(consistency) => {
+                let id = self.next_read;
+                self.next_read += 1;
+
+                match self.raft.read_index(id, consistency == ReadConsistency::Lease) {
+                    Ok(()) => {
+                        self.reads.insert(id, PendingQuery { session, command, deadline });
+                    }
+                    Err(_) => self.deliver(session, no_leader()),
+                }
+            }
+        }
+    }
+
+    // Answers the confirmed reads whose index has been applied
+    fn serve_confirmed_reads(&mut self) {
+        let applied = self.raft.log().applied();
+        let ready: Vec<_> = self.confirmed_reads.extract_if(.., |(index, _)| *index <= applied).collect();
+
+        for (_, query) in ready {
+            self.answer(query.session, query.command);
+        }
+    }
+
+    // Fails reads whose leader never answered, for example because it was partitioned,
+    // and reads this node could not catch up for in time
+    fn expire_reads(&mut self) {
+        let now = self.clock.now();
+        let mut expired: Vec<PendingQuery> =
+            self.reads.extract_if(|_, q| q.deadline <= now).map(|(_, q)| q).collect();
+        expired.extend(self.confirmed_reads.extract_if(.., |(_, q)| q.deadline <= now).map(|(_, q)| q));
+
+        for query in expired {
+            let error = ErrorResponse::new(ErrorCode::Internal, "read timed out, try again");
+            self.deliver(query.session, ChatResponse::Error(error.retryable()));
+        }
+    }
+
+    fn answer(&mut self, session: SessionId, command: ChatCommand) {
+        let Some(s) = self.sessions.get(&session) else {
+            return;
+        };
+        let user = s.nick.clone();
+        if let Err(e) = self.state.authenticate(&s.nick, &s.credential) {
+            return self.deliver(session, e.into());
+        }
+
+        let result = match command {
+            ChatCommand::FetchHistory { room, before, limit, thread, .. } => {
+                self.state.history(&user, &room, thread, before, limit, self.clock.unix_millis())
+            }
+            ChatCommand::FetchMentions { limit, .. } => Ok(ChatResponse::Mentions {
+                messages: self.state.recent_mentions(&user, limit),
+                unread: false,
+            }),
+            ChatCommand::Search { query, room, from, limit, .. } => {
+                self.state.search(&user, &query, room.as_deref(), from.as_deref(), limit, self.clock.unix_millis())
+            }
+            ChatCommand::Who { room, .. } => self.state.members(&user, &room),
+            ChatCommand::RoomInfo { room, .. } => self.state.room_info(&room),
+            _ => Err(ChatError::Protocol("command is not a query".to_string())),
+        };
+
+        // Tag reads with how far this node had got, so clients can ask any node later
+        // for a view at least this fresh.
+        let result = result.map(|mut response| {
+            if let ChatResponse::History { applied_index, .. } | ChatResponse::SearchResults { applied_index, .. } =
+                &mut response
+            {
+                *applied_index = self.raft.log().applied();
+            }
+            response
+        });
+
+        match result {
+            Ok(response) => self.deliver(session, response),
+            Err(e) => self.deliver(session, e.into()),
+        }
+    }
+
+    // Sends the mentions a user received while offline once they identify themselves
+    fn deliver_unread_mentions(&mut self, session: SessionId) {
+        let Some(user) = self.sessions.get(&session).map(|s| s.nick.clone()) else {
+            return;
+        };
+
+        let messages = self.state.unread_mentions(&user);
+        if !messages.is_empty() {
+            self.deliver(session, ChatResponse::Mentions { messages, unread: true });
+        }
+    }
+
+    // Replaces the applied prefix of the log with a snapshot once it grows too long
+    fn maybe_snapshot(&mut self) -> ChatEvent<()> {
+        if self.raft.log().applied() - self.raft.log().snapshot().index >= SNAPSHOT_THRESHOLD {
+            self.snapshot()?;
+        }
+        Ok(())
+    }
+
+    // Replaces the applied prefix of the log with a snapshot and returns its last index
+    fn snapshot(&mut self) -> ChatEvent<u64> {
+        let applied = self.raft.log().applied();
+        if applied == self.raft.log().snapshot().index {
+            return Ok(applied);
+        }
+
+        let snapshot = SnapshotData { state: Cow::Borrowed(&self.state), peers: self.peers.addresses() };
+        let data = serde_json::to_string(&snapshot)
+            .map_err(|e| ChatError::Internal(format!("failed to serialize snapshot: {}", e)))?;
+        self.raft.compact(applied, data);
+        self.storage.save_snapshot(self.raft.log().snapshot(), self.raft.log().entries())?;
+        self.metrics.snapshots_taken.fetch_add(1, Ordering::Relaxed);
+        debug!("Compacted log up to index {}", applied);
+        Ok(applied)
+    }
+
+    // Resolves an audience to the sessions connected to this node
+    fn audience_sessions(&self, audience: &Audience, origin: Option<SessionId>) -> Vec<SessionId> {
+        let is_member = |room: &str, nick: &str| {
+            self.state.room(room).is_some_and(|r| r.members.contains(nick))
+        };
+
+        self.sessions
+            .iter()
+            .filter(|(_, session)| self.state.authenticate(&session.nick, &session.credential).is_ok())
+            .filter(|(id, session)| match audience {
+                Audience::Room(room) => is_member(room, &session.nick),
+                Audience::RoomExceptOrigin(room) => Some(**id) != origin && is_member(room, &session.nick),
+                Audience::User(nick) => session.nick == *nick,
+                Audience::Origin => Some(**id) == origin,
+            })
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    /// Queues a response for a session without blocking the node on a slow client.
+    fn deliver(&mut self, session: SessionId, response: ChatResponse) {
+        if let Some(s) = self.sessions.get(&session)
+            && let Err(e) = s.outbound.try_send(response)
+        {
+            self.metrics.responses_dropped.fetch_add(1, Ordering::Relaxed);
+            warn!("Dropping response for session {}: {}", session, e);
+        }
+    }
+}
+
+// Session IDs start over on every node and on every restart, and guests share the
+// replicated state with everyone else, so a guest's name has to be unique across the
+// cluster and over time.
+fn guest_nick(node: NodeId, session: SessionId) -> String {
+    format!("guest-{}-{:016x}", node, RandomState::new().hash_one(session))
+}
+
+fn no_leader() -> ChatResponse {
+    ChatResponse::error(ErrorCode::NotLeader { leader: None }, NO_LEADER)
+}
+
+impl From<RaftError> for ErrorResponse {
+    fn from(error: RaftError) -> Self {
+        match error {
+            RaftError::NotLeader { leader } => ErrorResponse::new(ErrorCode::NotLeader { leader }, NO_LEADER),
+            RaftError::TransferInProgress { .. } => {
+                ErrorResponse::new(ErrorCode::NotLeader { leader: None }, "leadership is moving, try again shortly")
+            }
+            RaftError::ConfChangeInProgress => {
+                ErrorResponse::new(ErrorCode::InvalidCommand, error.to_string()).retryable()
+            }
+            RaftError::NotAVoter(_) | RaftError::InvalidConfChange(_) => {
+                ErrorResponse::new(ErrorCode::InvalidCommand, error.to_string())
+            }
+        }
+    }
+}
+
+// Restores the chat state from a snapshot and starts talking to the peers it lists.
+// Snapshots taken before membership changes existed hold only the chat state.
+fn restore_snapshot(data: &str, peers: &mut dyn Transport, me: NodeId) -> ChatEvent<ChatState> {
+    let snapshot = serde_json::from_str::<SnapshotData>(data).or_else(|_| {
+        let state = serde_json::from_str(data)?;
+        Ok(SnapshotData { state: Cow::Owned(state), peers: BTreeMap::new() })
+    });
+    let snapshot: SnapshotData =
+        snapshot.map_err(|e: serde_json::Error| ChatError::Internal(format!("failed to parse snapshot: {}", e)))?;
+
+    // Addresses the node already has, such as those given on its command line, win: nodes
+    // may reach each other by different routes, and the snapshot holds the leader's.
+    let known = peers.addresses();
+    for (id, addr) in snapshot.peers {
+        if id != me && !known.contains_key(&id) {
+            peers.add(id, addr);
+        }
+    }
+    Ok(snapshot.state.into_owned())
+}
@@ -0,0 +1,178 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
+
+use crate::node::{NodeHandle, Proposal};
+use crate::raft::{NodeId, RaftMessage};
+
+/// Messages queued per peer before new ones are dropped. Raft tolerates lost messages,
+/// so a slow peer must never hold up the node task.
+const PEER_QUEUE_SIZE: usize = 1024;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+const RECONNECT_DELAY: Duration = Duration::from_millis(500);
+
+/// What nodes send each other, one JSON object per line.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum PeerMessage {
+    Raft(RaftMessage),
+    /// A client command received by a follower, to be proposed by the leader.
+    Forward(Proposal),
+}
+
+/// How a node reaches the others: [`Peers`] in production, an in-memory network in the
+/// simulator.
+pub trait Transport: Send {
+    /// Sends without waiting. Raft tolerates lost messages, so delivery is best effort.
+    fn send(&self, to: NodeId, msg: PeerMessage);
+
+    /// Starts sending to a new peer, or to a known peer at a new address.
+    fn add(&mut self, id: NodeId, addr: String);
+
+    /// Stops sending to a peer that has left the cluster.
+    fn remove(&mut self, id: NodeId);
+
+    fn addresses(&self) -> BTreeMap<NodeId, String>;
+}
+
+struct PeerSender {
+    addr: String,
+    tx: mpsc::Sender<PeerMessage>,
+}
+
+/// Outgoing connections to the other nodes of the cluster.
+#[derive(Default)]
+pub struct Peers {
+    senders: BTreeMap<NodeId, PeerSender>,
+}
+
+impl Peers {
+    /// Spawns a sender task per peer. Connections are made lazily and re-established
+    /// after failures; messages sent while a peer is unreachable are dropped.
+    pub fn start(peers: &BTreeMap<NodeId, String>) -> Self {
+        let mut started = Self::default();
+        for (id, addr) in peers {
+            started.add(*id, addr.clone());
+        }
+        started
+    }
+}
+
+impl Transport for Peers {
+    fn add(&mut self, id: NodeId, addr: String) {
+        if self.senders.get(&id).is_some_and(|sender| sender.addr == addr) {
+            return;
+        }
+
+        let (tx, rx) = mpsc::channel(PEER_QUEUE_SIZE);
+        tokio::spawn(run_sender(id, addr.clone(), rx));
+        // Replacing the old sender closes its queue, which stops its task.
+        self.senders.insert(id, PeerSender { addr, tx });
+    }
+
+    fn remove(&mut self, id: NodeId) {
+        // Dropping the sender closes its queue, which stops its task.
+        self.senders.remove(&id);
+    }
+
+    fn addresses(&self) -> BTreeMap<NodeId, String> {
+        self.senders.iter().map(|(id, sender)| (*id, sender.addr.clone())).collect()
+    }
+
+    fn send(&self, to: NodeId, msg: PeerMessage) {
+        match self.senders.get(&to) {
+            Some(sender) => {
+                if sender.tx.try_send(msg).is_err() {
+                    debug!("Dropping message for node {}", to);
+                }
+            }
+            None => warn!("No address for node {}", to),
+        }
+    }
+}
+
+async fn run_sender(id: NodeId, addr: String, mut rx: mpsc::Receiver<PeerMessage>) {
+    let mut stream: Option<TcpStream> = None;
+    let mut retry_at = Instant::now();
+
+    while let Some(msg) = rx.recv().await {
+        if stream.is_none() && Instant::now() >= retry_at {
+            match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(&addr)).await {
+                Ok(Ok(s)) => {
+                    info!("Connected to node {} at {}", id, addr);
+                    stream = Some(s);
+                }
+                _ => retry_at = Instant::now() + RECONNECT_DELAY,
+            }
+        }
+        let Some(s) = stream.as_mut() else {
+            continue;
+        };
+
+        let mut line = match serde_json::to_vec(&msg) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("Failed to serialize peer message: {}", e);
+                continue;
+            }
+        };
+        line.push(b'\n');
+
+        if let Err(e) = s.write_all(&line).await {
+            warn!("Lost connection to node {}: {}", id, e);
+            stream = None;
+            retry_at = Instant::now() + RECONNECT_DELAY;
+        }
+    }
+}
+
+/// Accepts connections from other nodes and hands their messages to the node task.
+pub async fn listen(addr: SocketAddr, node: NodeHandle) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("Listening for peers on {}", addr);
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((socket, addr)) => {
+                    debug!("Peer connection from {}", addr);
+                    tokio::spawn(run_receiver(socket, node.clone()));
+                }
+                Err(e) => warn!("Error accepting peer connection: {}", e),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+async fn run_receiver(socket: TcpStream, node: NodeHandle) {
+    let mut lines = BufReader::new(socket).lines();
+
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => return,
+            Err(e) => {
+                debug!("Peer connection closed: {}", e);
+                return;
+            }
+        };
+
+        match serde_json::from_str(&line) {
+            Ok(msg) => {
+                if node.peer_message(msg).await.is_err() {
+                    return;
+                }
+            }
+            Err(e) => warn!("Ignoring malformed peer message: {}", e),
+        }
+    }
+}
@@ -0,0 +1,172 @@
+use super::message::{LogEntry, Membership, Snapshot};
+
+/// The in-memory Raft log: a snapshot followed by the entries after it.
+///
+/// Entries handed out by [`RaftLog::take_unstable`] are the ones the driver still has to
+/// write to stable storage; [`RaftLog::take_committed`] yields the ones to apply.
+#[derive(Debug, Default)]
+pub struct RaftLog {
+    snapshot: Snapshot,
+    /// Entries after the snapshot; `entries[0].index == snapshot.index + 1`.
+    entries: Vec<LogEntry>,
+    commit_index: u64,
+    applied: u64,
+    /// First index that has not been handed out for persistence yet.
+    unstable_from: u64,
+}
+
+impl RaftLog {
+    /// Rebuilds the log from what the driver found on stable storage.
+    pub fn restore(snapshot: Option<Snapshot>, entries: Vec<LogEntry>, commit: u64) -> Self {
+        let snapshot = snapshot.unwrap_or_default();
+        let entries: Vec<LogEntry> = entries.into_iter().filter(|e| e.index > snapshot.index).collect();
+        let last_index = snapshot.index + entries.len() as u64;
+
+        Self {
+            commit_index: commit.clamp(snapshot.index, last_index),
+            applied: snapshot.index,
+            unstable_from: last_index + 1,
+            snapshot,
+            entries,
+        }
+    }
+
+    pub fn snapshot(&self) -> &Snapshot {
+        &self.snapshot
+    }
+
+    pub fn entries(&self) -> &[LogEntry] {
+        &self.entries
+    }
+
+    pub fn first_index(&self) -> u64 {
+        self.snapshot.index + 1
+    }
+
+    pub fn last_index(&self) -> u64 {
+        self.snapshot.index + self.entries.len() as u64
+    }
+
+    pub fn last_term(&self) -> u64 {
+        self.entries.last().map_or(self.snapshot.term, |e| e.term)
+    }
+
+    pub fn commit_index(&self) -> u64 {
+        self.commit_index
+    }
+
+    pub fn applied(&self) -> u64 {
+        self.applied
+    }
+
+    /// Term of the entry at `index`, if it is still known.
+    pub fn term(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot.index {
+            return Some(self.snapshot.term);
+        }
+        if index < self.first_index() || index > self.last_index() {
+            return None;
+        }
+        Some(self.entries[(index - self.first_index()) as usize].term)
+    }
+
+    pub fn matches(&self, index: u64, term: u64) -> bool {
+        self.term(index) == Some(term)
+    }
+
+    /// The last index at or before `index` whose term is no newer than `term`, with its
+    /// term. Every entry after it has a newer term, so none of them can match a log whose
+    /// entry at `index` has `term`.
+    pub fn find_conflict_by_term(&self, index: u64, term: u64) -> (u64, u64) {
+        let mut index = index.min(self.last_index());
+        while index > self.snapshot.index && self.term(index).is_some_and(|t| t > term) {
+            index -= 1;
+        }
+        (index, self.term(index).unwrap_or(0))
+    }
+
+    /// Whether a log ending at (`last_index`, `last_term`) is at least as up to date as ours.
+    pub fn is_up_to_date(&self, last_index: u64, last_term: u64) -> bool {
+        last_term > self.last_term() || (last_term == self.last_term() && last_index >= self.last_index())
+    }
+
+    /// Appends entries created by the leader itself.
+    pub fn append(&mut self, entry: LogEntry) {
+        debug_assert_eq!(entry.index, self.last_index() + 1);
+        self.entries.push(entry);
+    }
+
+    /// Appends entries received from the leader after `prev_index`, dropping any
+    /// conflicting suffix. Returns the index of the last entry now known to match.
+    pub fn append_after(&mut self, prev_index: u64, entries: Vec<LogEntry>) -> u64 {
+        let last_new = prev_index + entries.len() as u64;
+
+        for (i, entry) in entries.iter().enumerate() {
+            match self.term(entry.index) {
+                Some(term) if term == entry.term => continue,
+                // Entries before the snapshot are committed and therefore match.
+                None if entry.index <= self.snapshot.index => continue,
+                _ => {
+                    assert!(entry.index > self.commit_index, "conflict with committed entry {}", entry.index);
+                    self.entries.truncate((entry.index - self.first_index()) as usize);
+                    self.entries.extend(entries[i..].iter().cloned());
+                    self.unstable_from = self.unstable_from.min(entry.index);
+                    break;
+                }
+            }
+        }
+
+        last_new
+    }
+
+    /// Up to `max` entries starting at `from`.
+    pub fn slice(&self, from: u64, max: usize) -> Vec<LogEntry> {
+        if from < self.first_index() || from > self.last_index() {
+            return Vec::new();
+        }
+        let start = (from - self.first_index()) as usize;
+        let end = start.saturating_add(max).min(self.entries.len());
+        self.entries[start..end].to_vec()
+    }
+
+    pub fn commit_to(&mut self, index: u64) {
+        if index > self.commit_index {
+            self.commit_index = index.min(self.last_index());
+        }
+    }
+
+    pub fn take_unstable(&mut self) -> Vec<LogEntry> {
+        let entries = self.slice(self.unstable_from.max(self.first_index()), usize::MAX);
+        self.unstable_from = self.last_index() + 1;
+        entries
+    }
+
+    pub fn take_committed(&mut self) -> Vec<LogEntry> {
+        if self.commit_index <= self.applied {
+            return Vec::new();
+        }
+        let entries = self.slice(self.applied + 1, (self.commit_index - self.applied) as usize);
+        self.applied = self.commit_index;
+        entries
+    }
+
+    /// Discards entries up to `index`, which must already be applied.
+    pub fn compact(&mut self, index: u64, data: String, membership: Membership) {
+        assert!(index <= self.applied, "compacting unapplied entry {}", index);
+        if index <= self.snapshot.index {
+            return;
+        }
+        let term = self.term(index).expect("compacted index is in the log");
+        self.entries.drain(..(index - self.first_index() + 1) as usize);
+        self.snapshot = Snapshot { index, term, data, membership };
+    }
+
+    /// Replaces the whole log with a snapshot received from the leader.
+    pub fn install(&mut self, snapshot: Snapshot) {
+        self.entries.clear();
+        self.commit_index = snapshot.index;
+        self.applied = snapshot.index;
+        self.unstable_from = snapshot.index + 1;
+        self.snapshot = snapshot;
+    }
+}
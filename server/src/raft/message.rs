@@ -0,0 +1,133 @@
+use std::collections::BTreeSet;
+
+use serde::{Deserialize, Serialize};
+
+use super::NodeId;
+use crate::node::Proposal;
+
+/// What a log entry carries.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Payload {
+    /// Appended by every new leader so it can commit entries from earlier terms.
+    Noop,
+    Command(Box<Proposal>),
+    ConfChange(ConfChange),
+}
+
+/// A change to the cluster membership. Changes take effect when they are applied, one
+/// at a time.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ConfChange {
+    /// Adds a node that receives the log but does not vote. `addr` is its peer address.
+    AddLearner { id: NodeId, addr: String },
+    /// Turns a learner into a voter.
+    Promote { id: NodeId },
+    /// Takes a voter or learner out of the cluster.
+    Remove { id: NodeId },
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Membership {
+    pub voters: BTreeSet<NodeId>,
+    /// Nodes that replicate the log without counting towards quorum.
+    pub learners: BTreeSet<NodeId>,
+}
+
+impl Membership {
+    pub fn contains(&self, id: NodeId) -> bool {
+        self.voters.contains(&id) || self.learners.contains(&id)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogEntry {
+    pub index: u64,
+    pub term: u64,
+    pub payload: Payload,
+}
+
+/// State that must be on stable storage before any message reflecting it is sent.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HardState {
+    pub term: u64,
+    pub vote: Option<NodeId>,
+    pub commit: u64,
+}
+
+/// The serialized state machine as of `index`, replacing all log entries up to it.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub index: u64,
+    pub term: u64,
+    pub data: String,
+    /// Membership as of `index`; empty in a node's initial, blank snapshot.
+    #[serde(default)]
+    pub membership: Membership,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RaftMessage {
+    pub from: NodeId,
+    pub to: NodeId,
+    pub term: u64,
+    pub kind: MessageKind,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum MessageKind {
+    /// Asks for a vote. With `pre_vote` set, `term` is the term the sender would campaign
+    /// in, and granting it changes nothing on the receiver. `transfer` marks an election
+    /// started at the leader's request, which voters accept even while in a lease.
+    RequestVote {
+        last_log_index: u64,
+        last_log_term: u64,
+        pre_vote: bool,
+        #[serde(default)]
+        transfer: bool,
+    },
+    RequestVoteResponse {
+        granted: bool,
+        pre_vote: bool,
+    },
+    /// Replicates entries following `prev_log_index`; empty when used as a heartbeat.
+    /// `context` numbers the leader's broadcast rounds and is echoed in the response, so
+    /// the leader knows which rounds a follower has acknowledged.
+    AppendEntries {
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<LogEntry>,
+        commit: u64,
+        context: u64,
+    },
+    /// On success `match_index` is the last index known to match the leader. On
+    /// rejection it is the `prev_log_index` that did not match, and `hint` is the last
+    /// index the follower might share with the leader: the last one whose term, given
+    /// in `hint_term`, is no newer than the rejected entry's.
+    AppendEntriesResponse {
+        success: bool,
+        match_index: u64,
+        hint: u64,
+        #[serde(default)]
+        hint_term: u64,
+        context: u64,
+    },
+    InstallSnapshot {
+        snapshot: Snapshot,
+    },
+    /// Asks the leader for an index that is safe to serve a linearizable read at.
+    ReadIndex {
+        id: u64,
+        lease: bool,
+    },
+    /// `None` when the receiver is not (or no longer) the leader.
+    ReadIndexResponse {
+        id: u64,
+        index: Option<u64>,
+    },
+    /// Asks the leader to hand leadership to `to`.
+    TransferLeadership {
+        to: NodeId,
+    },
+    /// Sent by a leader to the node it is handing leadership to, which campaigns at once.
+    TimeoutNow,
+}
@@ -0,0 +1,960 @@
+//! A deterministic Raft core.
+//!
+//! [`Raft`] does no I/O and reads no clock: time advances through [`Raft::tick`], peer
+//! messages arrive through [`Raft::step`], and everything the driver has to do in
+//! response (persist, send, apply) is collected into a [`Ready`].
+
+mod log;
+mod message;
+mod progress;
+#[cfg(test)]
+mod tests;
+
+use std::collections::{BTreeMap, VecDeque};
+
+use serde::Serialize;
+use thiserror::Error;
+
+pub use log::RaftLog;
+pub use message::{ConfChange, HardState, LogEntry, Membership, MessageKind, Payload, RaftMessage, Snapshot};
+pub use progress::{Progress, ProgressState};
+
+pub type NodeId = u64;
+
+/// Largest number of entries sent in a single `AppendEntries`.
+const MAX_ENTRIES_PER_MESSAGE: usize = 256;
+
+/// How far behind the leader's log a learner may be and still be promoted.
+const MAX_PROMOTION_LAG: u64 = MAX_ENTRIES_PER_MESSAGE as u64;
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub id: NodeId,
+    /// Initial membership, used until a snapshot or the log says otherwise.
+    pub voters: Vec<NodeId>,
+    pub learners: Vec<NodeId>,
+    /// Minimum ticks without hearing from a leader before campaigning. The actual
+    /// timeout is randomized in `[election_tick, 2 * election_tick)`.
+    pub election_tick: u32,
+    pub heartbeat_tick: u32,
+    /// Run a pre-vote round before campaigning, so a node that cannot win an election
+    /// (for example one coming back from a partition) does not bump the term.
+    pub pre_vote: bool,
+    /// Step down as leader when a quorum has not been heard from for an election
+    /// timeout, and ignore vote requests while a live leader is known.
+    pub check_quorum: bool,
+    /// Ticks by which clocks may drift apart during an election timeout. Lease reads
+    /// stop this long before followers could vote for a new leader.
+    pub max_clock_drift: u32,
+    /// Seed for the election timeout jitter.
+    pub seed: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Role {
+    Follower,
+    PreCandidate,
+    Candidate,
+    Leader,
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum RaftError {
+    #[error("not the leader (leader: {leader:?})")]
+    NotLeader { leader: Option<NodeId> },
+    #[error("leadership is being transferred to node {to}")]
+    TransferInProgress { to: NodeId },
+    #[error("node {0} is not a voter")]
+    NotAVoter(NodeId),
+    #[error("another membership change is still in progress")]
+    ConfChangeInProgress,
+    #[error("invalid membership change: {0}")]
+    InvalidConfChange(String),
+}
+
+/// A read that may be served once the state machine has applied `index`, or must be
+/// failed when `index` is `None` because leadership could not be confirmed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadState {
+    pub id: u64,
+    pub index: Option<u64>,
+}
+
+/// A read waiting for a quorum to acknowledge broadcast `round`.
+#[derive(Debug)]
+struct PendingRead {
+    id: u64,
+    from: NodeId,
+    index: u64,
+    round: u64,
+}
+
+/// Work produced by the core that the driver must carry out, in this order: persist
+/// `snapshot`, `hard_state` and `entries`, then send `messages`, then apply `committed`.
+#[derive(Debug, Default)]
+pub struct Ready {
+    pub hard_state: Option<HardState>,
+    pub snapshot: Option<Snapshot>,
+    pub entries: Vec<LogEntry>,
+    pub messages: Vec<RaftMessage>,
+    pub committed: Vec<LogEntry>,
+    pub read_states: Vec<ReadState>,
+}
+
+pub struct Raft {
+    config: Config,
+    term: u64,
+    vote: Option<NodeId>,
+    role: Role,
+    leader: Option<NodeId>,
+    log: RaftLog,
+    membership: Membership,
+    /// Index of the last membership change proposed by this leader.
+    pending_conf_index: u64,
+    /// Votes received in the current (pre-)election.
+    votes: BTreeMap<NodeId, bool>,
+    progress: BTreeMap<NodeId, Progress>,
+    election_elapsed: u32,
+    heartbeat_elapsed: u32,
+    randomized_election_timeout: u32,
+    rng: u64,
+    messages: Vec<RaftMessage>,
+    persisted_hard_state: HardState,
+    received_snapshot: Option<Snapshot>,
+    /// Ticks since the node started.
+    ticks: u64,
+    /// Index of the entry the leader appended when it was elected.
+    term_start_index: u64,
+    /// Number of the last broadcast round, and when recent rounds were sent.
+    round: u64,
+    rounds_sent: VecDeque<(u64, u64)>,
+    pending_reads: Vec<PendingRead>,
+    read_states: Vec<ReadState>,
+    /// Highest commit index a leader has sent us.
+    leader_commit: u64,
+    /// Node leadership is being handed to, and ticks since the transfer started.
+    transferee: Option<NodeId>,
+    transfer_elapsed: u32,
+}
+
+impl Raft {
+    /// Creates a follower from the state found on stable storage.
+    pub fn new(config: Config, hard_state: HardState, snapshot: Option<Snapshot>, entries: Vec<LogEntry>) -> Self {
+        let membership = match &snapshot {
+            Some(snapshot) if !snapshot.membership.voters.is_empty() => snapshot.membership.clone(),
+            _ => Membership {
+                voters: config.voters.iter().copied().collect(),
+                learners: config.learners.iter().copied().collect(),
+            },
+        };
+        let log = RaftLog::restore(snapshot, entries, hard_state.commit);
+        let rng = config.seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1;
+
+        let mut raft = Self {
+            term: hard_state.term,
+            vote: hard_state.vote,
+            role: Role::Follower,
+            leader: None,
+            log,
+            membership,
+            pending_conf_index: 0,
+            votes: BTreeMap::new(),
+            progress: BTreeMap::new(),
+            election_elapsed: 0,
+            heartbeat_elapsed: 0,
+            randomized_election_timeout: config.election_tick,
+            rng,
+            messages: Vec::new(),
+            persisted_hard_state: hard_state,
+            received_snapshot: None,
+            ticks: 0,
+            term_start_index: 0,
+            round: 0,
+            rounds_sent: VecDeque::new(),
+            pending_reads: Vec::new(),
+            read_states: Vec::new(),
+            leader_commit: 0,
+            transferee: None,
+            transfer_elapsed: 0,
+            config,
+        };
+        raft.reset_election_timeout();
+        raft
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.config.id
+    }
+
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn leader(&self) -> Option<NodeId> {
+        self.leader
+    }
+
+    pub fn log(&self) -> &RaftLog {
+        &self.log
+    }
+
+    pub fn membership(&self) -> &Membership {
+        &self.membership
+    }
+
+    /// The highest index known to be committed anywhere: the leader's own commit index, or
+    /// the last one a leader sent. A follower has caught up once it has applied this far.
+    pub fn leader_commit(&self) -> u64 {
+        self.leader_commit.max(self.log.commit_index())
+    }
+
+    /// The leader's view of each follower's log; empty on other nodes.
+    pub fn progress(&self) -> &BTreeMap<NodeId, Progress> {
+        &self.progress
+    }
+
+    pub fn hard_state(&self) -> HardState {
+        HardState { term: self.term, vote: self.vote, commit: self.log.commit_index() }
+    }
+
+    /// Advances logical time by one tick.
+    pub fn tick(&mut self) {
+        self.ticks += 1;
+        self.election_elapsed += 1;
+
+        if self.role == Role::Leader {
+            if self.election_elapsed >= self.config.election_tick {
+                self.election_elapsed = 0;
+                if self.config.check_quorum && !self.quorum_active() {
+                    tracing::info!("Node {} lost contact with a quorum, stepping down", self.id());
+                    self.become_follower(self.term, None);
+                    return;
+                }
+            }
+
+            if let Some(to) = self.transferee {
+                self.transfer_elapsed += 1;
+                if self.transfer_elapsed >= self.config.election_tick {
+                    tracing::info!("Node {} aborting leadership transfer to {}", self.id(), to);
+                    self.transferee = None;
+                }
+            }
+
+            self.heartbeat_elapsed += 1;
+            if self.heartbeat_elapsed >= self.config.heartbeat_tick {
+                self.heartbeat_elapsed = 0;
+                self.broadcast_heartbeat();
+            }
+        } else if self.election_elapsed >= self.randomized_election_timeout && self.is_voter(self.id()) {
+            self.campaign();
+        }
+    }
+
+    /// Appends a command to the log. Only the leader accepts proposals, and not while it
+    /// is handing leadership over. New entries are sent with the next [`Ready`], so all
+    /// proposals made before it share one message per follower.
+    pub fn propose(&mut self, payload: Payload) -> Result<u64, RaftError> {
+        if self.role != Role::Leader {
+            return Err(RaftError::NotLeader { leader: self.leader });
+        }
+        if let Some(to) = self.transferee {
+            return Err(RaftError::TransferInProgress { to });
+        }
+
+        let index = self.log.last_index() + 1;
+        self.log.append(LogEntry { index, term: self.term, payload });
+        self.maybe_commit();
+        Ok(index)
+    }
+
+    /// Requests an index at which read `id` can be served linearizably. The answer arrives
+    /// as a [`ReadState`] once leadership has been confirmed by a quorum, immediately if
+    /// `lease` is set and the leader's lease is still valid. Followers ask the leader.
+    pub fn read_index(&mut self, id: u64, lease: bool) -> Result<(), RaftError> {
+        match (self.role, self.leader) {
+            (Role::Leader, _) => {
+                self.handle_read_index(self.id(), id, lease);
+                Ok(())
+            }
+            (_, Some(leader)) => {
+                self.send(leader, MessageKind::ReadIndex { id, lease });
+                Ok(())
+            }
+            (_, None) => Err(RaftError::NotLeader { leader: None }),
+        }
+    }
+
+    /// Proposes a membership change. Only one change may be in flight at a time, a
+    /// learner can only be promoted once it has nearly caught up with the leader, and the
+    /// leader cannot remove itself: leadership has to be transferred first.
+    pub fn propose_conf_change(&mut self, change: ConfChange) -> Result<u64, RaftError> {
+        if self.role == Role::Leader && self.pending_conf_index > self.log.applied() {
+            return Err(RaftError::ConfChangeInProgress);
+        }
+
+        match &change {
+            ConfChange::AddLearner { id, .. } if self.membership.contains(*id) => {
+                return Err(RaftError::InvalidConfChange(format!("node {} is already a member", id)));
+            }
+            ConfChange::Promote { id } if !self.membership.learners.contains(id) => {
+                return Err(RaftError::InvalidConfChange(format!("node {} is not a learner", id)));
+            }
+            ConfChange::Promote { id } if self.role == Role::Leader => {
+                let matched = self.progress.get(id).map_or(0, |p| p.match_index);
+                let lag = self.log.last_index() - matched;
+                if lag > MAX_PROMOTION_LAG {
+                    let reason = format!("node {} is still catching up ({} entries behind)", id, lag);
+                    return Err(RaftError::InvalidConfChange(reason));
+                }
+            }
+            ConfChange::Remove { id } if !self.membership.contains(*id) => {
+                return Err(RaftError::InvalidConfChange(format!("node {} is not a member", id)));
+            }
+            ConfChange::Remove { id } if *id == self.id() => {
+                let reason = "the leader cannot remove itself, transfer leadership first".to_string();
+                return Err(RaftError::InvalidConfChange(reason));
+            }
+            _ => {}
+        }
+
+        let index = self.propose(Payload::ConfChange(change))?;
+        self.pending_conf_index = index;
+        Ok(index)
+    }
+
+    /// Puts a committed membership change into effect. The driver calls this when it
+    /// applies the entry carrying it.
+    pub fn apply_conf_change(&mut self, change: &ConfChange) {
+        match change {
+            ConfChange::AddLearner { id, .. } => {
+                if self.membership.contains(*id) {
+                    return;
+                }
+                self.membership.learners.insert(*id);
+                if self.role == Role::Leader && *id != self.id() {
+                    let next_index = self.log.last_index() + 1;
+                    self.progress.insert(*id, Progress::new(next_index));
+                    self.send_append(*id);
+                }
+            }
+            ConfChange::Promote { id } => {
+                if self.membership.learners.remove(id) {
+                    self.membership.voters.insert(*id);
+                }
+            }
+            ConfChange::Remove { id } => {
+                self.membership.voters.remove(id);
+                self.membership.learners.remove(id);
+                self.progress.remove(id);
+                if self.transferee == Some(*id) {
+                    self.transferee = None;
+                }
+            }
+        }
+        tracing::info!("Node {} applied {:?}, membership is now {:?}", self.id(), change, self.membership);
+
+        if self.role == Role::Leader {
+            self.maybe_commit();
+        }
+    }
+
+    /// Hands leadership to `to` once its log has caught up. The transfer is abandoned if
+    /// that does not happen within an election timeout. Followers pass the request on.
+    pub fn transfer_leadership(&mut self, to: NodeId) -> Result<(), RaftError> {
+        if !self.is_voter(to) {
+            return Err(RaftError::NotAVoter(to));
+        }
+
+        match (self.role, self.leader) {
+            (Role::Leader, _) => {
+                self.handle_transfer(to);
+                Ok(())
+            }
+            (_, Some(leader)) => {
+                self.send(leader, MessageKind::TransferLeadership { to });
+                Ok(())
+            }
+            (_, None) => Err(RaftError::NotLeader { leader: None }),
+        }
+    }
+
+    /// Handles a message from a peer.
+    pub fn step(&mut self, msg: RaftMessage) {
+        if msg.term > self.term {
+            if let MessageKind::RequestVote { transfer: false, .. } = msg.kind
+                && self.in_lease()
+            {
+                // A live leader is known, so this candidate is disrupting a healthy cluster.
+                tracing::debug!("Node {} ignoring vote request from {} during leader lease", self.id(), msg.from);
+                return;
+            }
+
+            match msg.kind {
+                // Pre-votes are about a future term; they never move ours forward.
+                MessageKind::RequestVote { pre_vote: true, .. } => {}
+                MessageKind::RequestVoteResponse { pre_vote: true, granted: true } => {}
+                MessageKind::AppendEntries { .. } | MessageKind::InstallSnapshot { .. } => {
+                    self.become_follower(msg.term, Some(msg.from));
+                }
+                _ => self.become_follower(msg.term, None),
+            }
+        } else if msg.term < self.term {
+            match msg.kind {
+                // Tell a stale leader about the newer term so it steps down.
+                MessageKind::AppendEntries { .. } | MessageKind::InstallSnapshot { .. } => {
+                    let kind =
+                        MessageKind::AppendEntriesResponse { success: false, match_index: 0, hint: 0, hint_term: 0, context: 0 };
+                    self.send(msg.from, kind);
+                }
+                // A pre-candidate stuck in an old term needs to hear about the new one.
+                MessageKind::RequestVote { pre_vote: true, .. } => {
+                    self.send(msg.from, MessageKind::RequestVoteResponse { granted: false, pre_vote: true });
+                }
+                _ => {}
+            }
+            return;
+        }
+
+        match msg.kind {
+            MessageKind::RequestVote { last_log_index, last_log_term, pre_vote, .. } => {
+                self.handle_request_vote(msg.from, msg.term, last_log_index, last_log_term, pre_vote);
+            }
+            MessageKind::RequestVoteResponse { granted, pre_vote } => {
+                self.handle_vote_response(msg.from, granted, pre_vote);
+            }
+            MessageKind::AppendEntries { prev_log_index, prev_log_term, entries, commit, context } => {
+                self.follow(msg.from);
+                self.handle_append(msg.from, prev_log_index, prev_log_term, entries, commit, context);
+            }
+            MessageKind::AppendEntriesResponse { success, match_index, hint, hint_term, context } => {
+                self.handle_append_response(msg.from, success, match_index, (hint, hint_term), context);
+            }
+            MessageKind::InstallSnapshot { snapshot } => {
+                self.follow(msg.from);
+                self.handle_snapshot(msg.from, snapshot);
+            }
+            MessageKind::ReadIndex { id, lease } => {
+                if self.role == Role::Leader {
+                    self.handle_read_index(msg.from, id, lease);
+                } else {
+                    self.send(msg.from, MessageKind::ReadIndexResponse { id, index: None });
+                }
+            }
+            MessageKind::ReadIndexResponse { id, index } => {
+                self.read_states.push(ReadState { id, index });
+            }
+            MessageKind::TransferLeadership { to } => {
+                if self.role == Role::Leader && self.is_voter(to) {
+                    self.handle_transfer(to);
+                }
+            }
+            MessageKind::TimeoutNow => {
+                if self.is_voter(self.id()) && self.role == Role::Follower {
+                    tracing::info!("Node {} taking over leadership from {}", self.id(), msg.from);
+                    self.become_candidate(true);
+                }
+            }
+        }
+    }
+
+    /// Collects the work produced since the last call. The driver must finish it before
+    /// feeding the core any further input.
+    pub fn ready(&mut self) -> Ready {
+        if self.role == Role::Leader {
+            for peer in self.peers() {
+                self.send_append(peer);
+            }
+        }
+
+        let hard_state = self.hard_state();
+        let hard_state = (hard_state != self.persisted_hard_state).then(|| {
+            self.persisted_hard_state = hard_state.clone();
+            hard_state
+        });
+
+        Ready {
+            hard_state,
+            snapshot: self.received_snapshot.take(),
+            entries: self.log.take_unstable(),
+            messages: std::mem::take(&mut self.messages),
+            committed: self.log.take_committed(),
+            read_states: std::mem::take(&mut self.read_states),
+        }
+    }
+
+    /// Replaces applied entries up to `index` with a snapshot of the state machine.
+    pub fn compact(&mut self, index: u64, data: String) {
+        self.log.compact(index, data, self.membership.clone());
+    }
+
+    fn is_voter(&self, id: NodeId) -> bool {
+        self.membership.voters.contains(&id)
+    }
+
+    fn quorum(&self) -> usize {
+        self.membership.voters.len() / 2 + 1
+    }
+
+    // Every other member the leader replicates to, learners included.
+    fn peers(&self) -> Vec<NodeId> {
+        let members = self.membership.voters.iter().chain(&self.membership.learners);
+        members.copied().filter(|id| *id != self.id()).collect()
+    }
+
+    fn voting_peers(&self) -> Vec<NodeId> {
+        self.membership.voters.iter().copied().filter(|id| *id != self.id()).collect()
+    }
+
+    // Whether a leader (possibly this node) was heard from within the election timeout.
+    fn leader_alive(&self) -> bool {
+        self.leader.is_some() && self.election_elapsed < self.config.election_tick
+    }
+
+    // With check-quorum, a live leader also means refusing to be pulled into a new term.
+    fn in_lease(&self) -> bool {
+        self.config.check_quorum && self.leader_alive()
+    }
+
+    fn send(&mut self, to: NodeId, kind: MessageKind) {
+        self.messages.push(RaftMessage { from: self.id(), to, term: self.term, kind });
+    }
+
+    fn next_random(&mut self) -> u64 {
+        // xorshift64
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+
+    fn reset_election_timeout(&mut self) {
+        self.election_elapsed = 0;
+        let jitter = self.next_random() % u64::from(self.config.election_tick.max(1));
+        self.randomized_election_timeout = self.config.election_tick + jitter as u32;
+    }
+
+    fn become_follower(&mut self, term: u64, leader: Option<NodeId>) {
+        if term > self.term {
+            self.term = term;
+            self.vote = None;
+        }
+        self.role = Role::Follower;
+        self.leader = leader;
+        self.votes.clear();
+        self.progress.clear();
+        self.transferee = None;
+        self.reset_election_timeout();
+
+        for read in std::mem::take(&mut self.pending_reads) {
+            self.answer_read(read.from, read.id, None);
+        }
+    }
+
+    // Called for every message from the current leader.
+    fn follow(&mut self, leader: NodeId) {
+        if self.role != Role::Follower || self.leader != Some(leader) {
+            self.become_follower(self.term, Some(leader));
+        }
+        self.election_elapsed = 0;
+    }
+
+    fn campaign(&mut self) {
+        if self.config.pre_vote {
+            self.become_pre_candidate();
+        } else {
+            self.become_candidate(false);
+        }
+    }
+
+    fn become_pre_candidate(&mut self) {
+        tracing::debug!("Node {} starting pre-vote for term {}", self.id(), self.term + 1);
+        self.role = Role::PreCandidate;
+        self.leader = None;
+        self.votes.clear();
+        self.reset_election_timeout();
+        self.request_votes(true, false);
+    }
+
+    fn become_candidate(&mut self, transfer: bool) {
+        self.term += 1;
+        self.vote = Some(self.id());
+        self.role = Role::Candidate;
+        self.leader = None;
+        self.votes.clear();
+        self.reset_election_timeout();
+        tracing::info!("Node {} campaigning in term {}", self.id(), self.term);
+        self.request_votes(false, transfer);
+    }
+
+    fn request_votes(&mut self, pre_vote: bool, transfer: bool) {
+        self.votes.insert(self.id(), true);
+        if self.tally_votes(pre_vote) {
+            return;
+        }
+
+        let term = if pre_vote { self.term + 1 } else { self.term };
+        let kind = MessageKind::RequestVote {
+            last_log_index: self.log.last_index(),
+            last_log_term: self.log.last_term(),
+            pre_vote,
+            transfer,
+        };
+        for peer in self.voting_peers() {
+            self.messages.push(RaftMessage { from: self.id(), to: peer, term, kind: kind.clone() });
+        }
+    }
+
+    // Acts on the votes received so far. Returns true once the election is decided.
+    fn tally_votes(&mut self, pre_vote: bool) -> bool {
+        let granted = self.votes.values().filter(|granted| **granted).count();
+        let rejected = self.votes.len() - granted;
+
+        if granted >= self.quorum() {
+            if pre_vote {
+                self.become_candidate(false);
+            } else {
+                self.become_leader();
+            }
+            true
+        } else if rejected >= self.quorum() {
+            self.become_follower(self.term, None);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn become_leader(&mut self) {
+        tracing::info!("Node {} became leader in term {}", self.id(), self.term);
+        self.role = Role::Leader;
+        self.leader = Some(self.id());
+        self.votes.clear();
+        self.heartbeat_elapsed = 0;
+        self.election_elapsed = 0;
+
+        let next_index = self.log.last_index() + 1;
+        self.progress = self
+            .peers()
+            .into_iter()
+            .map(|id| (id, Progress::new(next_index)))
+            .collect();
+        self.rounds_sent.clear();
+
+        // Entries from earlier terms can only be committed through one of our own.
+        self.term_start_index = next_index;
+        let _ = self.propose(Payload::Noop);
+        // An earlier leader may have left a membership change uncommitted.
+        self.pending_conf_index = next_index;
+    }
+
+    fn handle_request_vote(&mut self, from: NodeId, term: u64, last_log_index: u64, last_log_term: u64, pre_vote: bool) {
+        let can_vote = if !self.is_voter(self.id()) {
+            false
+        } else if pre_vote {
+            term > self.term && !self.leader_alive()
+        } else {
+            self.vote == Some(from) || (self.vote.is_none() && self.leader.is_none())
+        };
+        let granted = can_vote && self.log.is_up_to_date(last_log_index, last_log_term);
+
+        if granted && !pre_vote {
+            self.vote = Some(from);
+            self.election_elapsed = 0;
+        }
+
+        // A granted pre-vote is answered in the term it was asked for.
+        let response_term = if granted { term } else { self.term };
+        self.messages.push(RaftMessage {
+            from: self.id(),
+            to: from,
+            term: response_term,
+            kind: MessageKind::RequestVoteResponse { granted, pre_vote },
+        });
+    }
+
+    fn handle_vote_response(&mut self, from: NodeId, granted: bool, pre_vote: bool) {
+        let expected = if pre_vote { Role::PreCandidate } else { Role::Candidate };
+        if self.role != expected || !self.is_voter(from) {
+            return;
+        }
+
+        self.votes.insert(from, granted);
+        self.tally_votes(pre_vote);
+    }
+
+    fn handle_append(
+        &mut self,
+        leader: NodeId,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<LogEntry>,
+        commit: u64,
+        context: u64,
+    ) {
+        // Everything up to our commit index already matches the leader, so only the
+        // entries after it are of interest.
+        let commit_index = self.log.commit_index();
+        let (prev_log_index, prev_log_term, entries) = if prev_log_index < commit_index {
+            let entries = entries.into_iter().filter(|e| e.index > commit_index).collect();
+            (commit_index, self.log.term(commit_index).unwrap_or(0), entries)
+        } else {
+            (prev_log_index, prev_log_term, entries)
+        };
+
+        self.leader_commit = self.leader_commit.max(commit);
+        let kind = if self.log.matches(prev_log_index, prev_log_term) {
+            let last_new = self.log.append_after(prev_log_index, entries);
+            self.log.commit_to(commit.min(last_new));
+            MessageKind::AppendEntriesResponse { success: true, match_index: last_new, hint: 0, hint_term: 0, context }
+        } else {
+            // Point the leader past every entry of ours from a term newer than the one it
+            // expected, so it can skip a whole conflicting term per round trip.
+            let (hint, hint_term) = self.log.find_conflict_by_term(prev_log_index, prev_log_term);
+            MessageKind::AppendEntriesResponse { success: false, match_index: prev_log_index, hint, hint_term, context }
+        };
+        self.send(leader, kind);
+    }
+
+    fn handle_append_response(&mut self, from: NodeId, success: bool, match_index: u64, hint: (u64, u64), context: u64) {
+        if self.role != Role::Leader {
+            return;
+        }
+        // Skip our own entries from terms the follower does not have either.
+        let probe = match hint {
+            (index, 0) => index,
+            (index, term) => self.log.find_conflict_by_term(index, term).0,
+        };
+        let Some(progress) = self.progress.get_mut(&from) else {
+            return;
+        };
+        progress.recent_active = true;
+        // Even a rejection acknowledges us as the leader of this term.
+        let acked_new_round = context > progress.acked_round;
+        progress.acked_round = progress.acked_round.max(context);
+
+        if success {
+            if progress.acknowledge(match_index) {
+                self.maybe_commit();
+            }
+            self.send_append(from);
+        } else if progress.reject(match_index, probe) {
+            self.send_append(from);
+        }
+
+        if acked_new_round {
+            self.advance_reads();
+        }
+        if self.transferee == Some(from) {
+            self.maybe_send_timeout_now(from);
+        }
+    }
+
+    fn handle_snapshot(&mut self, leader: NodeId, snapshot: Snapshot) {
+        let index = snapshot.index;
+        if index > self.log.commit_index() {
+            tracing::info!("Node {} installing snapshot at index {}", self.id(), index);
+            if !snapshot.membership.voters.is_empty() {
+                self.membership = snapshot.membership.clone();
+            }
+            self.log.install(snapshot.clone());
+            self.received_snapshot = Some(snapshot);
+        }
+
+        let match_index = self.log.commit_index();
+        let kind = MessageKind::AppendEntriesResponse { success: true, match_index, hint: 0, hint_term: 0, context: 0 };
+        self.send(leader, kind);
+    }
+
+    // Starts a new broadcast round, so acknowledgements can be told apart from those of
+    // messages sent before it.
+    fn broadcast_heartbeat(&mut self) {
+        self.round += 1;
+        self.rounds_sent.push_back((self.round, self.ticks));
+        while self.rounds_sent.front().is_some_and(|(_, sent)| sent + u64::from(self.config.election_tick) <= self.ticks) {
+            self.rounds_sent.pop_front();
+        }
+
+        for peer in self.peers() {
+            self.send_heartbeat(peer);
+        }
+    }
+
+    // A probing follower gets its probe again, in case it was lost. One that is
+    // replicating gets an empty append after everything already sent: it is rejected
+    // if any of those messages were lost, which starts probing again.
+    fn send_heartbeat(&mut self, to: NodeId) {
+        let Some(progress) = self.progress.get_mut(&to) else {
+            return;
+        };
+
+        match progress.state {
+            ProgressState::Probe => {
+                progress.resume_probe();
+                self.send_append(to);
+            }
+            ProgressState::Replicate => {
+                let prev_log_index = progress.next_index - 1;
+                let prev_log_term = self.log.term(prev_log_index).unwrap_or(0);
+                let (commit, context) = (self.log.commit_index(), self.round);
+                let entries = Vec::new();
+                self.send(to, MessageKind::AppendEntries { prev_log_index, prev_log_term, entries, commit, context });
+            }
+        }
+    }
+
+    // Sends a probe, or while replicating as many messages as the inflight window allows.
+    fn send_append(&mut self, to: NodeId) {
+        loop {
+            let Some(progress) = self.progress.get_mut(&to) else {
+                return;
+            };
+            if progress.is_paused() {
+                return;
+            }
+
+            if progress.next_index <= self.log.snapshot().index {
+                // The entries the follower needs have been compacted away.
+                let snapshot = self.log.snapshot().clone();
+                progress.snapshot_sent(snapshot.index);
+                self.send(to, MessageKind::InstallSnapshot { snapshot });
+                return;
+            }
+
+            let entries = self.log.slice(progress.next_index, MAX_ENTRIES_PER_MESSAGE);
+            if entries.is_empty() && progress.state == ProgressState::Replicate {
+                return;
+            }
+            let prev_log_index = progress.next_index - 1;
+            let prev_log_term = self.log.term(prev_log_index).unwrap_or(0);
+            progress.sent(prev_log_index + entries.len() as u64);
+
+            let (commit, context) = (self.log.commit_index(), self.round);
+            self.send(to, MessageKind::AppendEntries { prev_log_index, prev_log_term, entries, commit, context });
+        }
+    }
+
+    fn handle_transfer(&mut self, to: NodeId) {
+        if to == self.id() || self.transferee == Some(to) {
+            return;
+        }
+
+        tracing::info!("Node {} transferring leadership to {}", self.id(), to);
+        self.transferee = Some(to);
+        self.transfer_elapsed = 0;
+        if !self.maybe_send_timeout_now(to) {
+            self.send_append(to);
+        }
+    }
+
+    // Tells the transferee to campaign once its log is as up to date as ours, so it is
+    // guaranteed to win the election.
+    fn maybe_send_timeout_now(&mut self, to: NodeId) -> bool {
+        let caught_up = self.progress.get(&to).is_some_and(|p| p.match_index == self.log.last_index());
+        if caught_up {
+            self.send(to, MessageKind::TimeoutNow);
+        }
+        caught_up
+    }
+
+    fn handle_read_index(&mut self, from: NodeId, id: u64, lease: bool) {
+        // Waiting for our own first entry ensures everything committed by earlier
+        // leaders has been applied before the read is served.
+        let index = self.log.commit_index().max(self.term_start_index);
+
+        if self.voting_peers().is_empty() || (lease && self.lease_valid()) {
+            self.answer_read(from, id, Some(index));
+        } else {
+            self.broadcast_heartbeat();
+            self.pending_reads.push(PendingRead { id, from, index, round: self.round });
+        }
+    }
+
+    fn answer_read(&mut self, from: NodeId, id: u64, index: Option<u64>) {
+        if from == self.id() {
+            self.read_states.push(ReadState { id, index });
+        } else {
+            self.send(from, MessageKind::ReadIndexResponse { id, index });
+        }
+    }
+
+    // Latest broadcast round acknowledged by a quorum of voters, counting ourselves.
+    fn quorum_acked_round(&self) -> u64 {
+        let mut acked: Vec<u64> = self
+            .membership
+            .voters
+            .iter()
+            .map(|id| match self.progress.get(id) {
+                Some(progress) => progress.acked_round,
+                None if *id == self.id() => self.round,
+                None => 0,
+            })
+            .collect();
+        acked.sort_unstable_by(|a, b| b.cmp(a));
+        acked[self.quorum() - 1]
+    }
+
+    fn advance_reads(&mut self) {
+        let acked = self.quorum_acked_round();
+        let (confirmed, pending) = std::mem::take(&mut self.pending_reads)
+            .into_iter()
+            .partition(|read| read.round <= acked);
+        self.pending_reads = pending;
+
+        for read in confirmed {
+            self.answer_read(read.from, read.id, Some(read.index));
+        }
+    }
+
+    // The leader holds a lease for an election timeout after a quorum acknowledged a
+    // round, less the allowed clock drift: until then followers refuse to vote for
+    // anyone else. This relies on check-quorum, which is what makes followers refuse.
+    // A transferee told to time out campaigns without waiting for the lease, so there is
+    // no lease while a transfer is under way.
+    fn lease_valid(&self) -> bool {
+        if !self.config.check_quorum || self.transferee.is_some() {
+            return false;
+        }
+
+        let acked = self.quorum_acked_round();
+        let Some((_, sent)) = self.rounds_sent.iter().find(|(round, _)| *round == acked) else {
+            return false;
+        };
+        let lease = self.config.election_tick.saturating_sub(self.config.max_clock_drift);
+        self.ticks < sent + u64::from(lease)
+    }
+
+    // Commits the highest index stored on a quorum, if it belongs to the current term.
+    fn maybe_commit(&mut self) {
+        let mut matched: Vec<u64> = self
+            .membership
+            .voters
+            .iter()
+            .map(|id| match self.progress.get(id) {
+                Some(progress) => progress.match_index,
+                None if *id == self.id() => self.log.last_index(),
+                None => 0,
+            })
+            .collect();
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+
+        let candidate = matched[self.quorum() - 1];
+        if candidate > self.log.commit_index() && self.log.term(candidate) == Some(self.term) {
+            self.log.commit_to(candidate);
+        }
+    }
+
+    // Checks that a quorum responded since the last check, and starts a new period.
+    fn quorum_active(&mut self) -> bool {
+        let mut active = 1;
+        for (id, progress) in self.progress.iter_mut() {
+            if self.membership.voters.contains(id) && std::mem::take(&mut progress.recent_active) {
+                active += 1;
+            }
+        }
+        active >= self.quorum()
+    }
+}
@@ -0,0 +1,131 @@
+use std::collections::VecDeque;
+
+/// Largest number of `AppendEntries` the leader keeps unacknowledged per follower.
+const MAX_INFLIGHT: usize = 64;
+
+/// How the leader is sending entries to a follower.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressState {
+    /// The follower's log position is unknown: one message at a time until it accepts one.
+    Probe,
+    /// The follower is keeping up: entries are sent as soon as they are appended, without
+    /// waiting for earlier messages to be acknowledged.
+    Replicate,
+}
+
+/// The leader's view of a follower's log.
+#[derive(Debug, Clone)]
+pub struct Progress {
+    pub match_index: u64,
+    pub next_index: u64,
+    pub state: ProgressState,
+    /// Whether a probe is waiting for its answer.
+    probe_sent: bool,
+    /// Last index of each message sent but not yet acknowledged while replicating.
+    inflight: VecDeque<u64>,
+    /// Whether the follower responded since the last quorum check.
+    pub recent_active: bool,
+    /// Latest broadcast round the follower has acknowledged.
+    pub acked_round: u64,
+}
+
+impl Progress {
+    pub fn new(next_index: u64) -> Self {
+        Self {
+            match_index: 0,
+            next_index,
+            state: ProgressState::Probe,
+            probe_sent: false,
+            inflight: VecDeque::new(),
+            recent_active: true,
+            acked_round: 0,
+        }
+    }
+
+    /// Whether sending entries has to wait for a response.
+    pub fn is_paused(&self) -> bool {
+        match self.state {
+            ProgressState::Probe => self.probe_sent,
+            ProgressState::Replicate => self.inflight.len() >= MAX_INFLIGHT,
+        }
+    }
+
+    /// Records a message carrying entries up to `last_index`.
+    pub fn sent(&mut self, last_index: u64) {
+        match self.state {
+            ProgressState::Probe => self.probe_sent = true,
+            ProgressState::Replicate => {
+                self.next_index = last_index + 1;
+                self.inflight.push_back(last_index);
+            }
+        }
+    }
+
+    /// Lets the next heartbeat probe again, in case the last probe or its answer was lost.
+    pub fn resume_probe(&mut self) {
+        self.probe_sent = false;
+    }
+
+    /// Handles a successful response. Returns whether it told us anything new.
+    pub fn acknowledge(&mut self, match_index: u64) -> bool {
+        if self.state == ProgressState::Probe && match_index == self.next_index - 1 && match_index >= self.match_index {
+            // An empty probe found where the logs match.
+            let updated = match_index > self.match_index;
+            self.match_index = match_index;
+            self.become_replicate();
+            return updated;
+        }
+        if match_index <= self.match_index {
+            return false;
+        }
+
+        self.match_index = match_index;
+        match self.state {
+            ProgressState::Probe => self.become_replicate(),
+            ProgressState::Replicate => {
+                self.next_index = self.next_index.max(match_index + 1);
+                while self.inflight.front().is_some_and(|last| *last <= match_index) {
+                    self.inflight.pop_front();
+                }
+            }
+        }
+        true
+    }
+
+    /// Handles a rejection of the message that followed `rejected`, moving back to the
+    /// entry after `probe`. Returns false for rejections of messages that have since been
+    /// superseded.
+    pub fn reject(&mut self, rejected: u64, probe: u64) -> bool {
+        let stale = match self.state {
+            ProgressState::Probe => rejected != self.next_index - 1,
+            ProgressState::Replicate => rejected <= self.match_index,
+        };
+        if stale {
+            return false;
+        }
+
+        self.become_probe();
+        self.next_index = (probe + 1).min(rejected).max(self.match_index + 1);
+        true
+    }
+
+    /// Moves to probing after sending a snapshot that ends at `index`; the follower's
+    /// answer resumes replication after it.
+    pub fn snapshot_sent(&mut self, index: u64) {
+        self.become_probe();
+        self.next_index = index + 1;
+        self.probe_sent = true;
+    }
+
+    fn become_probe(&mut self) {
+        self.state = ProgressState::Probe;
+        self.probe_sent = false;
+        self.inflight.clear();
+    }
+
+    fn become_replicate(&mut self) {
+        self.state = ProgressState::Replicate;
+        self.next_index = self.match_index + 1;
+        self.inflight.clear();
+    }
+}
@@ -0,0 +1,504 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::*;
+
+const ELECTION_TICK: u32 = 10;
+
+/// A cluster of cores wired together in memory, with messages delivered until the
+/// network is quiet after every tick.
+struct Cluster {
+    nodes: BTreeMap<NodeId, Raft>,
+    isolated: BTreeSet<NodeId>,
+    applied: BTreeMap<NodeId, Vec<LogEntry>>,
+    reads: BTreeMap<NodeId, Vec<ReadState>>,
+    /// Rejected appends delivered so far.
+    rejections: usize,
+}
+
+impl Cluster {
+    fn new(size: u64, pre_vote: bool, check_quorum: bool) -> Self {
+        let voters: Vec<NodeId> = (1..=size).collect();
+        let nodes = voters
+            .iter()
+            .map(|&id| {
+                let config = Config {
+                    id,
+                    voters: voters.clone(),
+                    learners: Vec::new(),
+                    election_tick: ELECTION_TICK,
+                    heartbeat_tick: 1,
+                    pre_vote,
+                    check_quorum,
+                    max_clock_drift: 2,
+                    seed: id,
+                };
+                (id, Raft::new(config, HardState::default(), None, Vec::new()))
+            })
+            .collect();
+        Self { nodes, isolated: BTreeSet::new(), applied: BTreeMap::new(), reads: BTreeMap::new(), rejections: 0 }
+    }
+
+    /// Starts node `id` as a learner and has the leader add it to the cluster.
+    fn add_learner(&mut self, leader: NodeId, id: NodeId) {
+        let config = Config {
+            id,
+            voters: self.node(leader).membership().voters.iter().copied().collect(),
+            learners: vec![id],
+            election_tick: ELECTION_TICK,
+            heartbeat_tick: 1,
+            pre_vote: true,
+            check_quorum: true,
+            max_clock_drift: 2,
+            seed: id,
+        };
+        self.nodes.insert(id, Raft::new(config, HardState::default(), None, Vec::new()));
+
+        let change = ConfChange::AddLearner { id, addr: String::new() };
+        self.node_mut(leader).propose_conf_change(change).unwrap();
+        self.tick(2);
+    }
+
+    fn node(&self, id: NodeId) -> &Raft {
+        &self.nodes[&id]
+    }
+
+    fn isolate(&mut self, id: NodeId) {
+        self.isolated.insert(id);
+    }
+
+    fn heal(&mut self) {
+        self.isolated.clear();
+    }
+
+    fn tick(&mut self, ticks: u32) {
+        for _ in 0..ticks {
+            for node in self.nodes.values_mut() {
+                node.tick();
+            }
+            self.deliver();
+        }
+    }
+
+    fn deliver(&mut self) {
+        loop {
+            let mut messages = Vec::new();
+            for (id, node) in self.nodes.iter_mut() {
+                let ready = node.ready();
+                for entry in &ready.committed {
+                    if let Payload::ConfChange(change) = &entry.payload {
+                        node.apply_conf_change(change);
+                    }
+                }
+                self.applied.entry(*id).or_default().extend(ready.committed);
+                self.reads.entry(*id).or_default().extend(ready.read_states);
+                messages.extend(ready.messages);
+            }
+            if messages.is_empty() {
+                return;
+            }
+
+            for msg in messages {
+                if !self.isolated.contains(&msg.from) && !self.isolated.contains(&msg.to) {
+                    if matches!(msg.kind, MessageKind::AppendEntriesResponse { success: false, .. }) {
+                        self.rejections += 1;
+                    }
+                    self.nodes.get_mut(&msg.to).unwrap().step(msg);
+                }
+            }
+        }
+    }
+
+    fn leaders(&self) -> Vec<NodeId> {
+        self.nodes.values().filter(|n| n.role() == Role::Leader).map(|n| n.id()).collect()
+    }
+
+    fn elect(&mut self) -> NodeId {
+        self.tick(ELECTION_TICK * 4);
+        let leaders = self.leaders();
+        assert_eq!(leaders.len(), 1, "expected a single leader, got {:?}", leaders);
+        leaders[0]
+    }
+
+    fn node_mut(&mut self, id: NodeId) -> &mut Raft {
+        self.nodes.get_mut(&id).unwrap()
+    }
+
+    fn take_reads(&mut self, id: NodeId) -> Vec<ReadState> {
+        self.reads.remove(&id).unwrap_or_default()
+    }
+
+    fn commands_applied(&self, id: NodeId) -> usize {
+        self.applied[&id].iter().filter(|e| matches!(e.payload, Payload::Command(_))).count()
+    }
+}
+
+// The member of a three node cluster that is neither `a` nor `b`.
+fn other(a: NodeId, b: NodeId) -> NodeId {
+    (1..=3).find(|id| *id != a && *id != b).unwrap()
+}
+
+fn command() -> Payload {
+    use crate::node::{Origin, Proposal};
+    use shared::ChatCommand;
+
+    Payload::Command(Box::new(Proposal {
+        origin: Origin { node: 0, session: 0 },
+        user: "alice".to_string(),
+        command: ChatCommand::Join("general".to_string()),
+        time: 0,
+        credential: String::new(),
+        addr: None,
+    }))
+}
+
+#[test]
+fn elects_a_leader_and_replicates() {
+    let mut cluster = Cluster::new(3, true, true);
+    let leader = cluster.elect();
+
+    cluster.nodes.get_mut(&leader).unwrap().propose(command()).unwrap();
+    cluster.tick(2);
+
+    for id in 1..=3 {
+        assert_eq!(cluster.commands_applied(id), 1);
+    }
+    let follower = (1..=3).find(|id| *id != leader).unwrap();
+    assert_eq!(
+        cluster.nodes.get_mut(&follower).unwrap().propose(command()),
+        Err(RaftError::NotLeader { leader: Some(leader) })
+    );
+}
+
+#[test]
+fn lagging_follower_catches_up_after_partition() {
+    let mut cluster = Cluster::new(3, true, true);
+    let leader = cluster.elect();
+    let lagging = (1..=3).find(|id| *id != leader).unwrap();
+
+    cluster.isolate(lagging);
+    for _ in 0..5 {
+        cluster.nodes.get_mut(&leader).unwrap().propose(command()).unwrap();
+    }
+    cluster.tick(2);
+    assert_eq!(cluster.commands_applied(leader), 5);
+    assert_eq!(cluster.commands_applied(lagging), 0);
+
+    cluster.heal();
+    cluster.tick(ELECTION_TICK);
+    assert_eq!(cluster.leaders(), vec![leader]);
+    assert_eq!(cluster.commands_applied(lagging), 5);
+}
+
+#[test]
+fn pre_vote_stops_a_rejoining_node_from_disrupting() {
+    let mut cluster = Cluster::new(3, true, false);
+    let leader = cluster.elect();
+    let term = cluster.node(leader).term();
+    let rejoining = (1..=3).find(|id| *id != leader).unwrap();
+
+    cluster.isolate(rejoining);
+    cluster.tick(ELECTION_TICK * 10);
+    // Pre-votes fail in isolation, so the term never moves.
+    assert_eq!(cluster.node(rejoining).term(), term);
+
+    cluster.heal();
+    cluster.tick(ELECTION_TICK * 2);
+    assert_eq!(cluster.leaders(), vec![leader]);
+    assert_eq!(cluster.node(leader).term(), term);
+}
+
+#[test]
+fn without_pre_vote_a_rejoining_node_forces_an_election() {
+    let mut cluster = Cluster::new(3, false, false);
+    let leader = cluster.elect();
+    let term = cluster.node(leader).term();
+    let rejoining = (1..=3).find(|id| *id != leader).unwrap();
+
+    cluster.isolate(rejoining);
+    cluster.tick(ELECTION_TICK * 10);
+    assert!(cluster.node(rejoining).term() > term);
+
+    cluster.heal();
+    cluster.tick(ELECTION_TICK * 4);
+    assert!(cluster.node(leader).term() > term);
+}
+
+#[test]
+fn check_quorum_steps_down_a_partitioned_leader() {
+    let mut cluster = Cluster::new(3, true, true);
+    let old_leader = cluster.elect();
+
+    cluster.isolate(old_leader);
+    cluster.tick(ELECTION_TICK * 4);
+    assert_ne!(cluster.node(old_leader).role(), Role::Leader);
+
+    let leaders = cluster.leaders();
+    assert_eq!(leaders.len(), 1);
+    assert_ne!(leaders[0], old_leader);
+
+    cluster.heal();
+    cluster.tick(ELECTION_TICK);
+    assert_eq!(cluster.node(old_leader).leader(), Some(leaders[0]));
+}
+
+#[test]
+fn without_check_quorum_a_partitioned_leader_stays_leader() {
+    let mut cluster = Cluster::new(3, true, false);
+    let old_leader = cluster.elect();
+
+    cluster.isolate(old_leader);
+    cluster.tick(ELECTION_TICK * 4);
+    assert_eq!(cluster.node(old_leader).role(), Role::Leader);
+    assert_eq!(cluster.leaders().len(), 2);
+}
+
+#[test]
+fn read_index_is_confirmed_by_a_quorum() {
+    let mut cluster = Cluster::new(3, true, true);
+    let leader = cluster.elect();
+    let follower = (1..=3).find(|id| *id != leader).unwrap();
+    cluster.node_mut(leader).propose(command()).unwrap();
+    cluster.tick(1);
+    let commit = cluster.node(leader).log().commit_index();
+    cluster.take_reads(leader);
+
+    // Nothing is confirmed until the followers answer a heartbeat round.
+    cluster.isolate(follower);
+    cluster.isolate(other(leader, follower));
+    cluster.node_mut(leader).read_index(1, false).unwrap();
+    cluster.deliver();
+    assert!(cluster.take_reads(leader).is_empty());
+    cluster.heal();
+    cluster.tick(1);
+    assert_eq!(cluster.take_reads(leader), vec![ReadState { id: 1, index: Some(commit) }]);
+
+    cluster.node_mut(follower).read_index(2, false).unwrap();
+    cluster.deliver();
+    assert_eq!(cluster.take_reads(follower), vec![ReadState { id: 2, index: Some(commit) }]);
+}
+
+#[test]
+fn partitioned_leader_cannot_confirm_reads() {
+    let mut cluster = Cluster::new(3, true, false);
+    let old_leader = cluster.elect();
+
+    cluster.isolate(old_leader);
+    cluster.node_mut(old_leader).read_index(1, false).unwrap();
+    cluster.tick(ELECTION_TICK * 4);
+    assert!(cluster.take_reads(old_leader).is_empty());
+
+    // Once it hears about the new term the read is failed rather than left hanging.
+    cluster.heal();
+    cluster.tick(ELECTION_TICK);
+    assert_eq!(cluster.take_reads(old_leader), vec![ReadState { id: 1, index: None }]);
+}
+
+#[test]
+fn lease_reads_skip_the_round_trip_until_the_lease_expires() {
+    let mut cluster = Cluster::new(3, true, true);
+    let leader = cluster.elect();
+    cluster.take_reads(leader);
+
+    cluster.node_mut(leader).read_index(1, true).unwrap();
+    let reads = cluster.node_mut(leader).ready().read_states;
+    assert_eq!(reads.len(), 1);
+    assert!(reads[0].index.is_some());
+
+    // Without acknowledgements the lease runs out before followers could elect anyone.
+    cluster.isolate(leader);
+    cluster.tick(ELECTION_TICK - 2);
+    cluster.node_mut(leader).read_index(2, true).unwrap();
+    assert!(cluster.node_mut(leader).ready().read_states.is_empty());
+}
+
+#[test]
+fn leadership_moves_to_the_transferee() {
+    let mut cluster = Cluster::new(3, true, true);
+    let leader = cluster.elect();
+    let target = (1..=3).find(|id| *id != leader).unwrap();
+
+    cluster.node_mut(leader).transfer_leadership(target).unwrap();
+    assert_eq!(
+        cluster.node_mut(leader).propose(command()),
+        Err(RaftError::TransferInProgress { to: target })
+    );
+
+    cluster.deliver();
+    assert_eq!(cluster.leaders(), vec![target]);
+    cluster.node_mut(target).propose(command()).unwrap();
+}
+
+#[test]
+fn lease_reads_confirm_leadership_during_a_transfer() {
+    let mut cluster = Cluster::new(3, true, true);
+    let leader = cluster.elect();
+    let target = (1..=3).find(|id| *id != leader).unwrap();
+    cluster.take_reads(leader);
+
+    // The target campaigns as soon as it gets TimeoutNow, regardless of the lease.
+    cluster.isolate(target);
+    cluster.node_mut(leader).transfer_leadership(target).unwrap();
+    cluster.node_mut(leader).read_index(1, true).unwrap();
+    assert!(cluster.node_mut(leader).ready().read_states.is_empty());
+
+    // The remaining follower still confirms the leader the usual way.
+    cluster.tick(1);
+    assert_eq!(cluster.take_reads(leader).len(), 1);
+}
+
+#[test]
+fn transfer_is_abandoned_when_the_target_cannot_catch_up() {
+    let mut cluster = Cluster::new(3, true, true);
+    let leader = cluster.elect();
+    let target = (1..=3).find(|id| *id != leader).unwrap();
+
+    cluster.isolate(target);
+    cluster.node_mut(leader).propose(command()).unwrap();
+    cluster.node_mut(leader).transfer_leadership(target).unwrap();
+    cluster.tick(ELECTION_TICK);
+
+    assert_eq!(cluster.leaders(), vec![leader]);
+    cluster.node_mut(leader).propose(command()).unwrap();
+}
+
+#[test]
+fn learners_replicate_without_counting_towards_quorum() {
+    let mut cluster = Cluster::new(3, true, true);
+    let leader = cluster.elect();
+    cluster.add_learner(leader, 4);
+    assert!(cluster.node(4).membership().learners.contains(&4));
+
+    cluster.node_mut(leader).propose(command()).unwrap();
+    cluster.tick(2);
+    assert_eq!(cluster.commands_applied(4), 1);
+
+    // The leader and the learner alone are not a quorum of the three voters.
+    let followers: Vec<NodeId> = (1..=3).filter(|id| *id != leader).collect();
+    for id in &followers {
+        cluster.isolate(*id);
+    }
+    cluster.node_mut(leader).propose(command()).unwrap();
+    cluster.tick(2);
+    assert_eq!(cluster.commands_applied(leader), 1);
+
+    // Learners never campaign, however long they go without a leader.
+    cluster.isolate(leader);
+    cluster.tick(ELECTION_TICK * 10);
+    assert_eq!(cluster.node(4).role(), Role::Follower);
+}
+
+#[test]
+fn promoted_learner_becomes_a_voter() {
+    let mut cluster = Cluster::new(3, true, true);
+    let leader = cluster.elect();
+    cluster.add_learner(leader, 4);
+
+    assert_eq!(
+        cluster.node_mut(leader).propose_conf_change(ConfChange::Promote { id: 5 }),
+        Err(RaftError::InvalidConfChange("node 5 is not a learner".to_string()))
+    );
+    cluster.node_mut(leader).propose_conf_change(ConfChange::Promote { id: 4 }).unwrap();
+    cluster.tick(2);
+    for id in 1..=4 {
+        assert_eq!(cluster.node(id).membership().voters, BTreeSet::from([1, 2, 3, 4]));
+    }
+
+    // As a voter it can now stand for election.
+    cluster.node_mut(leader).transfer_leadership(4).unwrap();
+    cluster.deliver();
+    assert_eq!(cluster.leaders(), vec![4]);
+}
+
+#[test]
+fn removed_node_no_longer_counts_towards_quorum() {
+    let mut cluster = Cluster::new(4, true, true);
+    let leader = cluster.elect();
+    let removed = if leader == 1 { 2 } else { 1 };
+
+    assert_eq!(
+        cluster.node_mut(leader).propose_conf_change(ConfChange::Remove { id: leader }),
+        Err(RaftError::InvalidConfChange("the leader cannot remove itself, transfer leadership first".to_string()))
+    );
+    cluster.node_mut(leader).propose_conf_change(ConfChange::Remove { id: removed }).unwrap();
+    cluster.tick(2);
+    assert!(!cluster.node(leader).membership().contains(removed));
+    assert!(!cluster.node(leader).progress().contains_key(&removed));
+
+    // Two of the three remaining voters are a quorum; two of the original four were not.
+    let other = (1..=4).find(|id| *id != leader && *id != removed).unwrap();
+    cluster.isolate(removed);
+    cluster.isolate(other);
+    cluster.node_mut(leader).propose(command()).unwrap();
+    cluster.tick(2);
+    assert_eq!(cluster.commands_applied(leader), 1);
+
+    // The leader stops replicating to the removed node once the removal commits, so it
+    // may never learn of it and campaign, but pre-vote keeps it from disrupting the rest.
+    cluster.heal();
+    let term = cluster.node(leader).term();
+    cluster.tick(ELECTION_TICK * 10);
+    assert_eq!(cluster.leaders(), vec![leader]);
+    assert_eq!(cluster.node(leader).term(), term);
+}
+
+#[test]
+fn proposals_are_batched_and_pipelined() {
+    let mut cluster = Cluster::new(3, true, true);
+    let leader = cluster.elect();
+    let follower = (1..=3).find(|id| *id != leader).unwrap();
+
+    let appends_to = |ready: &Ready, to: NodeId| -> Vec<usize> {
+        ready
+            .messages
+            .iter()
+            .filter_map(|m| match &m.kind {
+                MessageKind::AppendEntries { entries, .. } if m.to == to => Some(entries.len()),
+                _ => None,
+            })
+            .collect()
+    };
+
+    // Proposals made before the next ready share one message.
+    for _ in 0..3 {
+        cluster.node_mut(leader).propose(command()).unwrap();
+    }
+    let ready = cluster.node_mut(leader).ready();
+    assert_eq!(ready.entries.len(), 3);
+    assert_eq!(appends_to(&ready, follower), vec![3]);
+
+    // More than fits into one message goes out at once, without waiting for answers.
+    for _ in 0..MAX_ENTRIES_PER_MESSAGE * 2 {
+        cluster.node_mut(leader).propose(command()).unwrap();
+    }
+    let ready = cluster.node_mut(leader).ready();
+    assert_eq!(appends_to(&ready, follower), vec![MAX_ENTRIES_PER_MESSAGE, MAX_ENTRIES_PER_MESSAGE]);
+
+    // The first messages were dropped with that ready, so the follower has to reject
+    // the next heartbeat before it catches up.
+    cluster.tick(2);
+    assert_eq!(cluster.commands_applied(follower), 3 + MAX_ENTRIES_PER_MESSAGE * 2);
+}
+
+#[test]
+fn conflicting_terms_are_skipped_in_one_round_trip() {
+    let mut cluster = Cluster::new(3, true, true);
+    let old_leader = cluster.elect();
+
+    // The old leader appends entries nobody else sees, then the others move on.
+    cluster.isolate(old_leader);
+    for _ in 0..50 {
+        cluster.node_mut(old_leader).propose(command()).unwrap();
+    }
+    cluster.tick(ELECTION_TICK * 4);
+    let new_leader = cluster.leaders().into_iter().find(|id| *id != old_leader).unwrap();
+    for _ in 0..10 {
+        cluster.node_mut(new_leader).propose(command()).unwrap();
+    }
+    cluster.tick(2);
+
+    cluster.rejections = 0;
+    cluster.heal();
+    cluster.tick(ELECTION_TICK);
+    assert_eq!(cluster.commands_applied(old_leader), 10);
+    assert!(cluster.rejections <= 2, "took {} rejections to find the conflict", cluster.rejections);
+}
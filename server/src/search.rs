@@ -0,0 +1,74 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{Deserialize, Serialize};
+
+/// Inverted index from lowercased words to the IDs of the messages containing them.
+/// It is part of the chat state, so every node maintains it as entries are applied.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct SearchIndex {
+    postings: BTreeMap<String, BTreeSet<u64>>,
+}
+
+impl SearchIndex {
+    pub fn insert(&mut self, id: u64, content: &str) {
+        for term in tokenize(content) {
+            self.postings.entry(term).or_default().insert(id);
+        }
+    }
+
+    pub fn remove(&mut self, id: u64, content: &str) {
+        for term in tokenize(content) {
+            if let Some(ids) = self.postings.get_mut(&term) {
+                ids.remove(&id);
+                if ids.is_empty() {
+                    self.postings.remove(&term);
+                }
+            }
+        }
+    }
+
+    /// Returns the IDs of messages containing every term of `query`, newest first.
+    pub fn search(&self, query: &str) -> Vec<u64> {
+        let mut terms = tokenize(query);
+        let Some(first) = terms.pop_first() else {
+            return Vec::new();
+        };
+
+        let mut ids = self.postings.get(&first).cloned().unwrap_or_default();
+        for term in terms {
+            match self.postings.get(&term) {
+                Some(matching) => ids.retain(|id| matching.contains(id)),
+                None => return Vec::new(),
+            }
+        }
+
+        ids.into_iter().rev().collect()
+    }
+}
+
+fn tokenize(text: &str) -> BTreeSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_all_terms_newest_first() {
+        let mut index = SearchIndex::default();
+        index.insert(1, "Deploy the cluster");
+        index.insert(2, "cluster is down!");
+        index.insert(3, "the CLUSTER is back up");
+
+        assert_eq!(index.search("cluster"), vec![3, 2, 1]);
+        assert_eq!(index.search("Cluster, the"), vec![3, 1]);
+        assert!(index.search("missing cluster").is_empty());
+
+        index.remove(3, "the CLUSTER is back up");
+        assert_eq!(index.search("the"), vec![1]);
+    }
+}
@@ -0,0 +1,513 @@
+//! Linearizability checking of client histories, in the style of Knossos and Porcupine.
+//!
+//! A [`Recorder`] drives the clients of a [`Simulation`] and records when each operation
+//! was invoked and when, and how, it completed. [`check`] then searches for an order of
+//! the operations that respects real time and that a sequential [`Model`] accepts, using
+//! the Wing & Gong algorithm with Lowe's memoization. A history without one can be written
+//! out with [`write_html`] and inspected in a browser.
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fmt::{Debug, Write as _};
+use std::hash::Hash;
+use std::path::Path;
+use std::time::Duration;
+
+use shared::{ChatCommand, ChatResponse, ReadConsistency};
+
+use super::Simulation;
+use crate::node::SessionId;
+use crate::raft::NodeId;
+
+/// Clients that wait this long for a response give up and reconnect. Their operation
+/// stays in the history with an unknown outcome.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A sequential specification of the system under test.
+pub trait Model {
+    type State: Clone + Eq + Hash;
+    type Input: Debug;
+    type Output: Debug;
+
+    fn init(&self) -> Self::State;
+
+    /// The state after `op`, if the model could have given its output. Models that leave
+    /// some choices open until later operations settle them can use its timing.
+    fn step(&self, state: &Self::State, op: &Operation<Self::Input, Self::Output>) -> Option<Self::State>;
+}
+
+#[derive(Debug, Clone)]
+pub struct Operation<I, O> {
+    pub client: u64,
+    pub input: I,
+    /// `None` if no response arrived: the operation may or may not have taken effect.
+    pub output: Option<O>,
+    pub invoke: Duration,
+    /// `None` along with `output`, in which case the operation may take effect at any
+    /// point after it was invoked.
+    pub complete: Option<Duration>,
+}
+
+/// Evidence that a history is not linearizable: the longest run of its operations, by
+/// index, that the search could put in a valid order. None of the others can follow it.
+#[derive(Debug)]
+pub struct Violation {
+    pub linearized: Vec<usize>,
+}
+
+/// The invocations and completions of a history in time order, as a linked list that
+/// operations can be taken out of and put back into while searching.
+struct Entries {
+    /// Operation of each entry, and whether the entry is its invocation.
+    ops: Vec<(usize, bool)>,
+    /// Completion entry of each operation.
+    completions: Vec<usize>,
+    next: Vec<usize>,
+    prev: Vec<usize>,
+}
+
+impl Entries {
+    fn new<I, O>(history: &[Operation<I, O>]) -> Self {
+        let mut events: Vec<(Duration, bool, usize)> = Vec::new();
+        for (op, operation) in history.iter().enumerate() {
+            events.push((operation.invoke, false, op));
+            events.push((operation.complete.unwrap_or(Duration::MAX), true, op));
+        }
+        // At equal times invocations come first, which treats the operations as
+        // concurrent rather than guessing an order.
+        events.sort();
+
+        let len = events.len();
+        let mut completions = vec![0; history.len()];
+        let ops = events
+            .iter()
+            .enumerate()
+            .map(|(entry, (_, completion, op))| {
+                if *completion {
+                    completions[*op] = entry;
+                }
+                (*op, !completion)
+            })
+            .collect();
+
+        // Entry `len` is the head of the list and `len + 1` its tail.
+        let (head, tail) = (len, len + 1);
+        let mut next = vec![tail; len + 2];
+        let mut prev = vec![head; len + 2];
+        let mut last = head;
+        for entry in (0..len).chain([tail]) {
+            next[last] = entry;
+            prev[entry] = last;
+            last = entry;
+        }
+        Self { ops, completions, next, prev }
+    }
+
+    fn head(&self) -> usize {
+        self.ops.len()
+    }
+
+    fn is_tail(&self, entry: usize) -> bool {
+        entry == self.ops.len() + 1
+    }
+
+    fn first(&self) -> usize {
+        self.next[self.head()]
+    }
+
+    fn remove(&mut self, entry: usize) {
+        let (prev, next) = (self.prev[entry], self.next[entry]);
+        self.next[prev] = next;
+        self.prev[next] = prev;
+    }
+
+    fn restore(&mut self, entry: usize) {
+        let (prev, next) = (self.prev[entry], self.next[entry]);
+        self.next[prev] = entry;
+        self.prev[next] = entry;
+    }
+
+    // Takes an operation out of the list once it has been linearized.
+    fn lift(&mut self, invocation: usize) {
+        self.remove(invocation);
+        self.remove(self.completions[self.ops[invocation].0]);
+    }
+
+    // Undoes the last lift, which must have been of `invocation`.
+    fn unlift(&mut self, invocation: usize) {
+        self.restore(self.completions[self.ops[invocation].0]);
+        self.restore(invocation);
+    }
+}
+
+/// Checks whether `history` is linearizable with respect to `model`.
+pub fn check<M: Model>(model: &M, history: &[Operation<M::Input, M::Output>]) -> Result<(), Violation> {
+    let mut entries = Entries::new(history);
+    let mut state = model.init();
+    let mut linearized = vec![0u64; history.len().div_ceil(64)];
+    let mut seen: HashSet<(Vec<u64>, M::State)> = HashSet::new();
+    // Invocation entries linearized so far, with the state before each.
+    let mut stack: Vec<(usize, M::State)> = Vec::new();
+    let mut longest: Vec<usize> = Vec::new();
+
+    let mut entry = entries.first();
+    while !entries.is_tail(entry) {
+        let (op, invocation) = entries.ops[entry];
+
+        if invocation {
+            let operation = &history[op];
+            if let Some(next) = model.step(&state, operation) {
+                let mut with_op = linearized.clone();
+                with_op[op / 64] |= 1 << (op % 64);
+                // Skip orders that reach a configuration already explored.
+                if seen.insert((with_op.clone(), next.clone())) {
+                    stack.push((entry, std::mem::replace(&mut state, next)));
+                    linearized = with_op;
+                    entries.lift(entry);
+                    if stack.len() > longest.len() {
+                        longest = stack.iter().map(|(entry, _)| entries.ops[*entry].0).collect();
+                    }
+                    entry = entries.first();
+                    continue;
+                }
+            }
+            entry = entries.next[entry];
+        } else {
+            // An operation completed before it could be linearized: backtrack.
+            let Some((invocation, previous)) = stack.pop() else {
+                return Err(Violation { linearized: longest });
+            };
+            let op = entries.ops[invocation].0;
+            linearized[op / 64] &= !(1 << (op % 64));
+            state = previous;
+            entries.unlift(invocation);
+            entry = entries.next[invocation];
+        }
+    }
+
+    Ok(())
+}
+
+/// One room's log as clients see it: the messages sent to it, in order.
+///
+/// The model leaves the order of messages sent since the last read open until a read
+/// shows it, which it may do in any way that does not put a message before one whose send
+/// completed before its own started. Otherwise the search would try every interleaving of
+/// concurrent sends, only for the next read to rule out all but one of them.
+pub struct ChatLog;
+
+#[derive(Debug, Clone)]
+pub enum ChatInput {
+    Send { client_msg_id: u64 },
+    Fetch { limit: usize },
+}
+
+#[derive(Debug, Clone)]
+pub enum ChatOutput {
+    Sent,
+    /// Client message IDs of the newest messages, oldest first.
+    Fetched { messages: Vec<u64>, has_more: bool },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Logged {
+    client_msg_id: u64,
+    invoke: Duration,
+    complete: Option<Duration>,
+}
+
+impl Logged {
+    // Whether the message can come before `later` in the log.
+    fn may_precede(&self, later: &Logged) -> bool {
+        later.complete.is_none_or(|complete| complete >= self.invoke)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct ChatLogState {
+    /// Messages in log order, each with the start of its run: messages of the same run
+    /// may still be in any order, and are kept sorted so equal states compare equal.
+    log: Vec<(usize, Logged)>,
+    /// Start of the run of messages sent since the last read.
+    unread: usize,
+}
+
+impl Model for ChatLog {
+    type State = ChatLogState;
+    type Input = ChatInput;
+    type Output = ChatOutput;
+
+    fn init(&self) -> ChatLogState {
+        ChatLogState::default()
+    }
+
+    fn step(&self, state: &ChatLogState, op: &Operation<ChatInput, ChatOutput>) -> Option<ChatLogState> {
+        match (&op.input, &op.output) {
+            (ChatInput::Send { client_msg_id }, Some(ChatOutput::Sent) | None) => {
+                let logged = Logged { client_msg_id: *client_msg_id, invoke: op.invoke, complete: op.complete };
+                let mut next = state.clone();
+                let at = next.log[next.unread..].partition_point(|(_, other)| *other < logged);
+                next.log.insert(next.unread + at, (next.unread, logged));
+                Some(next)
+            }
+            (ChatInput::Fetch { limit }, Some(ChatOutput::Fetched { messages, has_more })) => {
+                fetch(state, *limit, messages, *has_more)
+            }
+            _ => None,
+        }
+    }
+}
+
+// Orders the runs a page of the newest `messages` shows, if it can show them that way.
+fn fetch(state: &ChatLogState, limit: usize, messages: &[u64], has_more: bool) -> Option<ChatLogState> {
+    let log = &state.log;
+    let start = log.len().saturating_sub(limit);
+    if messages.len() != log.len() - start || has_more != (start > 0) {
+        return None;
+    }
+
+    let mut next = ChatLogState { log: log.clone(), unread: log.len() };
+    let mut run_start = log.get(start).map_or(start, |(run, _)| *run);
+    while run_start < log.len() {
+        let run_end = log[run_start..]
+            .iter()
+            .position(|(run, _)| *run != run_start)
+            .map_or(log.len(), |n| run_start + n);
+        let shown_from = run_start.max(start);
+
+        let mut hidden: Vec<&Logged> = log[run_start..run_end].iter().map(|(_, logged)| logged).collect();
+        let mut shown = Vec::new();
+        for id in &messages[shown_from - start..run_end - start] {
+            let at = hidden.iter().position(|logged| logged.client_msg_id == *id)?;
+            shown.push(hidden.remove(at));
+        }
+        let in_order = shown
+            .iter()
+            .enumerate()
+            .all(|(i, earlier)| shown[i + 1..].iter().all(|later| earlier.may_precede(later)));
+        let hidden_first = hidden.iter().all(|earlier| shown.iter().all(|later| earlier.may_precede(later)));
+        if !in_order || !hidden_first {
+            return None;
+        }
+
+        // Messages before the page keep their run; those on it are each in a run of their own.
+        for (i, logged) in hidden.into_iter().enumerate() {
+            next.log[run_start + i] = (run_start, logged.clone());
+        }
+        for (i, logged) in shown.into_iter().enumerate() {
+            next.log[shown_from + i] = (shown_from + i, logged.clone());
+        }
+        run_start = run_end;
+    }
+    Some(next)
+}
+
+struct Outstanding {
+    op: usize,
+    session: SessionId,
+    deadline: Duration,
+}
+
+/// Runs one client per node against a room of a [`Simulation`], each with at most one
+/// operation in flight, and records the history of their sends and linearizable reads.
+pub struct Recorder {
+    room: String,
+    history: Vec<Operation<ChatInput, ChatOutput>>,
+    /// Operations that failed and therefore had no effect.
+    failed: BTreeSet<usize>,
+    outstanding: BTreeMap<NodeId, Outstanding>,
+    /// Simulation responses already looked at.
+    seen: usize,
+}
+
+impl Recorder {
+    /// Starts recording; responses that arrived earlier are ignored.
+    pub fn new(sim: &Simulation, room: &str) -> Self {
+        Self {
+            room: room.to_string(),
+            history: Vec::new(),
+            failed: BTreeSet::new(),
+            outstanding: BTreeMap::new(),
+            seen: sim.responses.len(),
+        }
+    }
+
+    /// Has the client on node `id` send a message, unless it is busy or the node is down.
+    pub fn send(&mut self, sim: &mut Simulation, id: NodeId) {
+        let Some(session) = self.idle_session(sim, id) else {
+            return;
+        };
+        if let Some(client_msg_id) = sim.send_message(id, &self.room, "hello") {
+            self.start(sim, id, session, ChatInput::Send { client_msg_id });
+        }
+    }
+
+    /// Has the client on node `id` read the newest messages, unless it is busy or the node is down.
+    pub fn fetch(&mut self, sim: &mut Simulation, id: NodeId, limit: usize) {
+        let Some(session) = self.idle_session(sim, id) else {
+            return;
+        };
+        // Record the invocation first: the response may arrive before `command` returns.
+        self.start(sim, id, session, ChatInput::Fetch { limit });
+        sim.command(
+            id,
+            ChatCommand::FetchHistory {
+                room: self.room.clone(),
+                before: None,
+                limit,
+                thread: None,
+                consistency: ReadConsistency::Linearizable,
+                min_index: None,
+            },
+        );
+    }
+
+    fn idle_session(&self, sim: &Simulation, id: NodeId) -> Option<SessionId> {
+        if self.outstanding.contains_key(&id) {
+            return None;
+        }
+        sim.session(id)
+    }
+
+    fn start(&mut self, sim: &Simulation, id: NodeId, session: SessionId, input: ChatInput) {
+        let op = self.history.len();
+        let invoke = sim.now();
+        self.history.push(Operation { client: session, input, output: None, invoke, complete: None });
+        self.outstanding.insert(id, Outstanding { op, session, deadline: invoke + CLIENT_TIMEOUT });
+    }
+
+    /// Completes operations with the responses that arrived since the last call. Clients
+    /// that lost their node or waited too long leave their operation unresolved.
+    pub fn collect(&mut self, sim: &mut Simulation) {
+        let now = sim.now();
+
+        for (session, response) in &sim.responses[self.seen..] {
+            let Some((&id, outstanding)) = self.outstanding.iter().find(|(_, o)| o.session == *session) else {
+                continue;
+            };
+            let operation = &mut self.history[outstanding.op];
+            let output = match (response, &operation.input) {
+                (ChatResponse::Ack { client_msg_id, .. }, ChatInput::Send { client_msg_id: sent })
+                    if client_msg_id == sent =>
+                {
+                    ChatOutput::Sent
+                }
+                (ChatResponse::History { messages, has_more, .. }, ChatInput::Fetch { .. }) => ChatOutput::Fetched {
+                    messages: messages.iter().map(|m| m.client_msg_id).collect(),
+                    has_more: *has_more,
+                },
+                // Errors are only sent for commands that took no effect.
+                (ChatResponse::Error(_), _) => {
+                    self.failed.insert(outstanding.op);
+                    self.outstanding.remove(&id);
+                    continue;
+                }
+                // Broadcasts such as the client's own message arriving in the room.
+                _ => continue,
+            };
+            operation.output = Some(output);
+            operation.complete = Some(now);
+            self.outstanding.remove(&id);
+        }
+        self.seen = sim.responses.len();
+
+        let given_up: Vec<NodeId> = self
+            .outstanding
+            .iter()
+            .filter(|(id, o)| sim.session(**id) != Some(o.session) || o.deadline <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in given_up {
+            self.outstanding.remove(&id);
+            sim.reconnect(id);
+        }
+    }
+
+    /// The recorded history, without the operations that cannot affect the outcome: those
+    /// known to have had no effect, reads that were never answered, and unanswered sends
+    /// that no read saw. The latter may as well have taken effect after everything else,
+    /// and leaving them out saves the search from trying them everywhere in between.
+    pub fn finish(self) -> Vec<Operation<ChatInput, ChatOutput>> {
+        let seen: HashSet<u64> = self
+            .history
+            .iter()
+            .filter_map(|operation| match &operation.output {
+                Some(ChatOutput::Fetched { messages, .. }) => Some(messages),
+                _ => None,
+            })
+            .flatten()
+            .copied()
+            .collect();
+
+        self.history
+            .into_iter()
+            .enumerate()
+            .filter(|(op, operation)| {
+                let irrelevant = operation.output.is_none()
+                    && match operation.input {
+                        ChatInput::Send { client_msg_id } => !seen.contains(&client_msg_id),
+                        ChatInput::Fetch { .. } => true,
+                    };
+                !self.failed.contains(op) && !irrelevant
+            })
+            .map(|(_, operation)| operation)
+            .collect()
+    }
+}
+
+/// Writes `history` as a self-contained HTML page with one row per client and one bar
+/// per operation, from its invocation to its completion. Operations in the longest
+/// linearizable prefix are green and numbered in the order found; the others are red.
+/// Hovering over an operation shows its input and output.
+pub fn write_html<I: Debug, O: Debug>(
+    path: &Path,
+    history: &[Operation<I, O>],
+    violation: &Violation,
+) -> std::io::Result<()> {
+    const PX_PER_MS: f64 = 0.5;
+    const ROW_HEIGHT: usize = 36;
+
+    let order: BTreeMap<usize, usize> = violation.linearized.iter().enumerate().map(|(i, op)| (*op, i + 1)).collect();
+    let clients: Vec<u64> = history.iter().map(|o| o.client).collect::<BTreeSet<_>>().into_iter().collect();
+    let end = history.iter().map(|o| o.complete.unwrap_or(o.invoke)).max().unwrap_or_default() + Duration::from_secs(1);
+    let x = |t: Duration| 80.0 + t.as_secs_f64() * 1000.0 * PX_PER_MS;
+    let escape = |s: String| s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+
+    let mut svg = String::new();
+    for (row, client) in clients.iter().enumerate() {
+        let y = row * ROW_HEIGHT + 10;
+        let _ = write!(svg, r#"<text x="4" y="{}">client {}</text>"#, y + 20, client);
+    }
+    for (op, operation) in history.iter().enumerate() {
+        let row = clients.iter().position(|c| *c == operation.client).unwrap_or_default();
+        let y = row * ROW_HEIGHT + 10;
+        let (start, stop) = (x(operation.invoke), x(operation.complete.unwrap_or(end)));
+        let (color, label) = match order.get(&op) {
+            Some(position) => ("#8c8", position.to_string()),
+            None => ("#e88", "?".to_string()),
+        };
+        let input = escape(format!("{:?}", operation.input));
+        let output = escape(format!("{:?}", operation.output));
+        let (invoke, complete) = (operation.invoke, operation.complete);
+        let (width, height) = ((stop - start).max(2.0), ROW_HEIGHT - 8);
+        let _ = write!(svg, r#"<g><title>op {op}: {input} -> {output} ({invoke:?} to {complete:?})</title>"#);
+        let _ = write!(svg, r#"<rect x="{start:.1}" y="{y}" width="{width:.1}" height="{height}" fill="{color}" "#);
+        let (label_x, label_y) = (start + 2.0, y + 17);
+        let _ = write!(svg, r#"stroke="black"/><text x="{label_x:.1}" y="{label_y}" font-size="11">{label}</text></g>"#);
+    }
+
+    let html = format!(
+        r#"<!DOCTYPE html>
+<html><head><meta charset="utf-8"><title>Linearizability violation</title></head>
+<body style="font-family: sans-serif">
+<p>{} operations, {} linearized before the search got stuck. Hover over an operation for its details.</p>
+<div style="overflow-x: scroll"><svg xmlns="http://www.w3.org/2000/svg" width="{:.0}" height="{}">{}</svg></div>
+</body></html>
+"#,
+        history.len(),
+        violation.linearized.len(),
+        x(end) + 20.0,
+        clients.len() * ROW_HEIGHT + 20,
+        svg,
+    );
+    std::fs::write(path, html)
+}
@@ -0,0 +1,579 @@
+//! Deterministic simulation of a cluster.
+//!
+//! Real [`Node`]s run in one process on a virtual clock, with in-memory storage and a
+//! network that drops, delays, duplicates, reorders and partitions messages as a seeded
+//! RNG decides. Nothing depends on wall-clock time or thread scheduling, so a failing run
+//! is reproduced exactly by running its seed again.
+
+pub mod linearizability;
+mod tests;
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use shared::{ChatCommand, ChatEvent, ChatResponse, Message};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::clock::Clock;
+use crate::config::ServerConfig;
+use crate::limits::RateLimits;
+use crate::node::{Node, NodeRequest, SessionId, TICK_INTERVAL};
+use crate::peer::{PeerMessage, Transport};
+use crate::raft::{HardState, LogEntry, NodeId, Role, Snapshot};
+use crate::storage::{Restored, Storage};
+
+/// A small, seedable PRNG (SplitMix64), so runs do not depend on anything outside the seed.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0..n`.
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+
+    pub fn chance(&mut self, probability: f64) -> bool {
+        ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < probability
+    }
+
+    pub fn duration(&mut self, min: Duration, max: Duration) -> Duration {
+        let span = (max - min).as_micros() as u64;
+        min + Duration::from_micros(self.below(span + 1))
+    }
+}
+
+/// How badly the simulated network behaves. Reordering follows from the random delays.
+#[derive(Debug, Clone)]
+pub struct NetworkConfig {
+    pub drop: f64,
+    pub duplicate: f64,
+    pub min_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl NetworkConfig {
+    pub fn reliable() -> Self {
+        Self { drop: 0.0, duplicate: 0.0, min_delay: Duration::from_millis(1), max_delay: Duration::from_millis(10) }
+    }
+
+    pub fn unreliable() -> Self {
+        Self { drop: 0.05, duplicate: 0.05, min_delay: Duration::from_millis(1), max_delay: Duration::from_millis(80) }
+    }
+}
+
+/// Virtual time shared by every simulated node.
+#[derive(Clone)]
+struct SimClock {
+    base: Instant,
+    elapsed_micros: Arc<AtomicU64>,
+}
+
+impl Clock for SimClock {
+    fn now(&self) -> Instant {
+        self.base + Duration::from_micros(self.elapsed_micros.load(Ordering::Relaxed))
+    }
+
+    // Virtual time starts at the epoch, so runs do not depend on when they happen.
+    fn unix_millis(&self) -> u64 {
+        self.elapsed_micros.load(Ordering::Relaxed) / 1000
+    }
+}
+
+/// Storage that survives a simulated crash because the simulator keeps hold of it.
+struct MemStorage(Arc<Mutex<Restored>>);
+
+impl Storage for MemStorage {
+    fn save_hard_state(&mut self, hard_state: &HardState) -> ChatEvent<()> {
+        self.0.lock().unwrap().hard_state = hard_state.clone();
+        Ok(())
+    }
+
+    fn append(&mut self, entries: &[LogEntry]) -> ChatEvent<()> {
+        let mut stored = self.0.lock().unwrap();
+        if let Some(first) = entries.first() {
+            stored.entries.retain(|e| e.index < first.index);
+        }
+        stored.entries.extend_from_slice(entries);
+        Ok(())
+    }
+
+    fn save_snapshot(&mut self, snapshot: &Snapshot, entries: &[LogEntry]) -> ChatEvent<()> {
+        let mut stored = self.0.lock().unwrap();
+        stored.snapshot = Some(snapshot.clone());
+        stored.entries = entries.to_vec();
+        Ok(())
+    }
+
+    fn sync(&mut self) -> ChatEvent<()> {
+        Ok(())
+    }
+}
+
+type Outbox = Arc<Mutex<Vec<(NodeId, NodeId, PeerMessage)>>>;
+
+/// Queues a node's messages for the simulator to put on the network.
+struct SimTransport {
+    from: NodeId,
+    outbox: Outbox,
+    addresses: BTreeMap<NodeId, String>,
+}
+
+impl Transport for SimTransport {
+    fn send(&self, to: NodeId, msg: PeerMessage) {
+        self.outbox.lock().unwrap().push((self.from, to, msg));
+    }
+
+    fn add(&mut self, id: NodeId, addr: String) {
+        self.addresses.insert(id, addr);
+    }
+
+    fn remove(&mut self, id: NodeId) {
+        self.addresses.remove(&id);
+    }
+
+    fn addresses(&self) -> BTreeMap<NodeId, String> {
+        self.addresses.clone()
+    }
+}
+
+#[derive(Debug)]
+enum Event {
+    /// Ticks are tagged with the incarnation of the node they were scheduled for, so a
+    /// node restarted before its old tick fires does not tick twice as fast.
+    Tick { id: NodeId, incarnation: u64 },
+    Deliver { from: NodeId, to: NodeId, msg: Box<PeerMessage> },
+}
+
+struct Scheduled {
+    at: Duration,
+    seq: u64,
+    event: Event,
+}
+
+impl PartialEq for Scheduled {
+    fn eq(&self, other: &Self) -> bool {
+        (self.at, self.seq) == (other.at, other.seq)
+    }
+}
+
+impl Eq for Scheduled {}
+
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scheduled {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.at, self.seq).cmp(&(other.at, other.seq))
+    }
+}
+
+/// A client connected to one node. It is dropped when the node crashes.
+struct SimClient {
+    session: SessionId,
+    nick: String,
+    responses: mpsc::Receiver<ChatResponse>,
+}
+
+struct SimNode {
+    node: Option<Node>,
+    storage: Arc<Mutex<Restored>>,
+    incarnation: u64,
+    /// Highest applied index already checked against the other nodes.
+    checked: u64,
+    client: Option<SimClient>,
+}
+
+/// A simulated cluster and the network between its nodes.
+pub struct Simulation {
+    pub seed: u64,
+    pub rng: Rng,
+    pub network: NetworkConfig,
+    now: Duration,
+    clock: SimClock,
+    nodes: BTreeMap<NodeId, SimNode>,
+    queue: BinaryHeap<Reverse<Scheduled>>,
+    seq: u64,
+    outbox: Outbox,
+    /// Nodes in different groups cannot reach each other.
+    groups: BTreeMap<NodeId, u32>,
+    next_session: SessionId,
+    next_client_msg_id: u64,
+    /// Leader of each term seen so far, to check there is never more than one.
+    leaders: BTreeMap<u64, NodeId>,
+    /// Term of every applied entry seen so far, to check all nodes apply the same log.
+    applied: BTreeMap<u64, u64>,
+    /// Every response delivered to a client session, in order.
+    pub responses: Vec<(SessionId, ChatResponse)>,
+}
+
+impl Simulation {
+    pub fn new(seed: u64, size: u64, network: NetworkConfig) -> Self {
+        let mut sim = Self {
+            seed,
+            rng: Rng::new(seed),
+            network,
+            now: Duration::ZERO,
+            clock: SimClock { base: Instant::now(), elapsed_micros: Arc::new(AtomicU64::new(0)) },
+            nodes: BTreeMap::new(),
+            queue: BinaryHeap::new(),
+            seq: 0,
+            outbox: Arc::new(Mutex::new(Vec::new())),
+            groups: BTreeMap::new(),
+            next_session: 1,
+            next_client_msg_id: 1,
+            leaders: BTreeMap::new(),
+            applied: BTreeMap::new(),
+            responses: Vec::new(),
+        };
+
+        for id in 1..=size {
+            let storage = Arc::new(Mutex::new(Restored::default()));
+            sim.nodes.insert(id, SimNode { node: None, storage, incarnation: 0, checked: 0, client: None });
+        }
+        for id in 1..=size {
+            sim.start(id);
+        }
+        sim
+    }
+
+    pub fn now(&self) -> Duration {
+        self.now
+    }
+
+    pub fn ids(&self) -> Vec<NodeId> {
+        self.nodes.keys().copied().collect()
+    }
+
+    pub fn node(&self, id: NodeId) -> Option<&Node> {
+        self.nodes[&id].node.as_ref()
+    }
+
+    pub fn is_up(&self, id: NodeId) -> bool {
+        self.node(id).is_some()
+    }
+
+    pub fn leader(&self) -> Option<NodeId> {
+        let leaders = self.nodes.iter().filter_map(|(id, n)| {
+            let node = n.node.as_ref()?;
+            (node.raft().role() == Role::Leader).then_some((node.raft().term(), *id))
+        });
+        leaders.max().map(|(_, id)| id)
+    }
+
+    /// Stops a node, losing everything it had not persisted.
+    pub fn crash(&mut self, id: NodeId) {
+        let sim_node = self.nodes.get_mut(&id).unwrap();
+        sim_node.node = None;
+        sim_node.client = None;
+    }
+
+    /// Starts a crashed node again from its storage.
+    pub fn restart(&mut self, id: NodeId) {
+        if !self.is_up(id) {
+            self.start(id);
+        }
+    }
+
+    /// The session of the client connected to node `id`, if the node is up.
+    pub fn session(&self, id: NodeId) -> Option<SessionId> {
+        self.nodes[&id].client.as_ref().map(|c| c.session)
+    }
+
+    /// Replaces the client on node `id` with a new connection for the same user, as a
+    /// client that gave up waiting for a response would. Responses for the old session
+    /// are no longer seen.
+    pub fn reconnect(&mut self, id: NodeId) {
+        let sim_node = self.nodes.get_mut(&id).unwrap();
+        let (Some(node), Some(client)) = (sim_node.node.as_mut(), sim_node.client.take()) else {
+            return;
+        };
+        node.handle(NodeRequest::Disconnect { session: client.session });
+        self.connect(id);
+    }
+
+    /// Asks node `id` to shut down gracefully. The receiver resolves once the node has let
+    /// its clients go; the node itself keeps running until crashed.
+    pub fn shutdown(&mut self, id: NodeId) -> Option<oneshot::Receiver<()>> {
+        let (reply, done) = oneshot::channel();
+        let node = self.nodes.get_mut(&id).unwrap().node.as_mut()?;
+        node.handle(NodeRequest::Shutdown { reply });
+        self.after_event(id);
+        Some(done)
+    }
+
+    /// Splits the cluster: each node can only reach the nodes in the same group.
+    pub fn partition(&mut self, groups: &[&[NodeId]]) {
+        self.groups.clear();
+        for (group, ids) in groups.iter().enumerate() {
+            for id in *ids {
+                self.groups.insert(*id, group as u32);
+            }
+        }
+    }
+
+    pub fn heal(&mut self) {
+        self.groups.clear();
+    }
+
+    fn connected(&self, a: NodeId, b: NodeId) -> bool {
+        self.groups.get(&a).unwrap_or(&0) == self.groups.get(&b).unwrap_or(&0)
+    }
+
+    /// Has the client on node `id` send a message to `room`. Returns its client message ID,
+    /// or `None` if the node is down.
+    pub fn send_message(&mut self, id: NodeId, room: &str, content: &str) -> Option<u64> {
+        let client_msg_id = self.next_client_msg_id;
+        self.next_client_msg_id += 1;
+
+        let client = self.nodes[&id].client.as_ref()?;
+        let message = Message {
+            id: 0,
+            client_msg_id,
+            sender: client.nick.clone(),
+            room: room.to_string(),
+            reply_to: None,
+            content: content.to_string(),
+            timestamp: self.now.as_millis() as u64,
+            edited: false,
+            reactions: Default::default(),
+        };
+        self.request(id, |session| NodeRequest::Propose { session, command: ChatCommand::SendMessage(message) })?;
+        Some(client_msg_id)
+    }
+
+    /// Sends a command on behalf of the client on node `id`, as its connection would.
+    pub fn command(&mut self, id: NodeId, command: ChatCommand) -> Option<()> {
+        if command.read_consistency().is_some() {
+            self.request(id, |session| NodeRequest::Query { session, command })
+        } else {
+            self.request(id, |session| NodeRequest::Propose { session, command })
+        }
+    }
+
+    /// Hands a message to node `id` as if another node had sent it.
+    pub fn peer_message(&mut self, id: NodeId, msg: PeerMessage) -> Option<()> {
+        let node = self.nodes.get_mut(&id).unwrap().node.as_mut()?;
+        node.handle(NodeRequest::Peer(msg));
+        self.after_event(id);
+        Some(())
+    }
+
+    /// Connects another client to node `id` as `session` and leaves it a guest. Returns
+    /// the responses it gets, or `None` if the node is down.
+    pub fn connect_guest(&mut self, id: NodeId, session: SessionId) -> Option<mpsc::Receiver<ChatResponse>> {
+        let (outbound, responses) = mpsc::channel(4096);
+        let node = self.nodes.get_mut(&id).unwrap().node.as_mut()?;
+        node.handle(NodeRequest::Connect { session, outbound, addr: None });
+        Some(responses)
+    }
+
+    /// Sends a command on behalf of a client connected with [`Simulation::connect_guest`].
+    pub fn guest_command(&mut self, id: NodeId, session: SessionId, command: ChatCommand) -> Option<()> {
+        let node = self.nodes.get_mut(&id).unwrap().node.as_mut()?;
+        node.handle(NodeRequest::Propose { session, command });
+        self.after_event(id);
+        Some(())
+    }
+
+    fn request(&mut self, id: NodeId, request: impl FnOnce(SessionId) -> NodeRequest) -> Option<()> {
+        let sim_node = self.nodes.get_mut(&id).unwrap();
+        let session = sim_node.client.as_ref()?.session;
+        let node = sim_node.node.as_mut()?;
+        node.handle(request(session));
+        self.after_event(id);
+        Some(())
+    }
+
+    /// Runs the cluster for `duration` of virtual time.
+    pub fn run_for(&mut self, duration: Duration) {
+        let end = self.now + duration;
+        while self.queue.peek().is_some_and(|Reverse(next)| next.at <= end) {
+            let Reverse(next) = self.queue.pop().unwrap();
+            self.set_now(next.at);
+
+            match next.event {
+                Event::Tick { id, incarnation } => {
+                    let sim_node = self.nodes.get_mut(&id).unwrap();
+                    if sim_node.incarnation != incarnation {
+                        continue;
+                    }
+                    let Some(node) = sim_node.node.as_mut() else {
+                        continue;
+                    };
+                    node.tick();
+                    self.schedule(TICK_INTERVAL, Event::Tick { id, incarnation });
+                    self.after_event(id);
+                }
+                Event::Deliver { from, to, msg } => {
+                    // A partition also cuts off messages that were already on their way.
+                    if !self.connected(from, to) {
+                        continue;
+                    }
+                    let Some(node) = self.nodes.get_mut(&to).unwrap().node.as_mut() else {
+                        continue;
+                    };
+                    node.handle(NodeRequest::Peer(*msg));
+                    self.after_event(to);
+                }
+            }
+        }
+        self.set_now(end);
+    }
+
+    fn start(&mut self, id: NodeId) {
+        let ids = self.ids();
+        let peers: BTreeMap<NodeId, String> =
+            ids.iter().filter(|other| **other != id).map(|other| (*other, format!("node{}", other))).collect();
+        let unused: SocketAddr = ([127, 0, 0, 1], 0).into();
+        let config = ServerConfig {
+            id,
+            listen: unused,
+            admin_listen: unused,
+            peer_listen: unused,
+            peers: peers.clone(),
+            peer_clients: peers.keys().map(|other| (*other, format!("client{}", other))).collect(),
+            learner: false,
+            data_dir: PathBuf::new(),
+            pre_vote: true,
+            check_quorum: true,
+            max_clock_drift: Duration::from_millis(200),
+            faults: None,
+            http_listen: None,
+            rate_limits_file: None,
+            rate_limits: RateLimits::default(),
+        };
+
+        let sim_node = self.nodes.get_mut(&id).unwrap();
+        let restored = sim_node.storage.lock().unwrap().clone();
+        let storage = Box::new(MemStorage(sim_node.storage.clone()));
+        let transport = Box::new(SimTransport { from: id, outbox: self.outbox.clone(), addresses: peers });
+        let node = Node::new(&config, storage, restored, transport, Box::new(self.clock.clone()))
+            .unwrap_or_else(|e| panic!("seed {}: node {} failed to start: {}", self.seed, id, e));
+
+        sim_node.node = Some(node);
+        sim_node.incarnation += 1;
+        sim_node.checked = 0;
+        let incarnation = sim_node.incarnation;
+
+        // Start nodes out of step with each other, as real ones would be.
+        let offset = self.rng.duration(Duration::ZERO, TICK_INTERVAL);
+        self.schedule(offset, Event::Tick { id, incarnation });
+        self.connect(id);
+    }
+
+    fn connect(&mut self, id: NodeId) {
+        let session = self.next_session;
+        self.next_session += 1;
+        let nick = format!("user{}", id);
+        let (outbound, responses) = mpsc::channel(4096);
+
+        let sim_node = self.nodes.get_mut(&id).unwrap();
+        let node = sim_node.node.as_mut().expect("clients connect to running nodes");
+        node.handle(NodeRequest::Connect { session, outbound, addr: None });
+        let command = ChatCommand::Nick { nick: nick.clone(), secret: "sim".to_string() };
+        node.handle(NodeRequest::Propose { session, command });
+        sim_node.client = Some(SimClient { session, nick, responses });
+    }
+
+    fn set_now(&mut self, now: Duration) {
+        self.now = now;
+        self.clock.elapsed_micros.store(now.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn schedule(&mut self, delay: Duration, event: Event) {
+        self.seq += 1;
+        self.queue.push(Reverse(Scheduled { at: self.now + delay, seq: self.seq, event }));
+    }
+
+    // Lets node `id` carry out what the last event produced, puts its messages on the
+    // network, and checks the cluster is still consistent.
+    fn after_event(&mut self, id: NodeId) {
+        let sim_node = self.nodes.get_mut(&id).unwrap();
+        if let Some(node) = sim_node.node.as_mut()
+            && let Err(e) = node.process_ready()
+        {
+            panic!("seed {}: node {} failed: {}", self.seed, id, e);
+        }
+        if let Some(client) = sim_node.client.as_mut() {
+            while let Ok(response) = client.responses.try_recv() {
+                self.responses.push((client.session, response));
+            }
+        }
+
+        let sent = std::mem::take(&mut *self.outbox.lock().unwrap());
+        for (from, to, msg) in sent {
+            if !self.connected(from, to) || self.rng.chance(self.network.drop) {
+                continue;
+            }
+            // Raft messages are idempotent, forwarded proposals are not. Peer connections
+            // never resend them, so neither does the simulated network.
+            let duplicate = !matches!(msg, PeerMessage::Forward(_)) && self.rng.chance(self.network.duplicate);
+            let copies = if duplicate { 2 } else { 1 };
+            for _ in 0..copies {
+                let delay = self.rng.duration(self.network.min_delay, self.network.max_delay);
+                self.schedule(delay, Event::Deliver { from, to, msg: Box::new(msg.clone()) });
+            }
+        }
+
+        self.check(id);
+    }
+
+    // Election safety: at most one leader per term. State machine safety: every node
+    // applies the same entry at each index.
+    fn check(&mut self, id: NodeId) {
+        let sim_node = self.nodes.get_mut(&id).unwrap();
+        let Some(node) = sim_node.node.as_ref() else {
+            return;
+        };
+        let raft = node.raft();
+
+        if raft.role() == Role::Leader {
+            let leader = *self.leaders.entry(raft.term()).or_insert(id);
+            assert_eq!(leader, id, "seed {}: nodes {} and {} both led term {}", self.seed, leader, id, raft.term());
+        }
+
+        let applied = raft.log().applied();
+        for index in sim_node.checked.max(raft.log().snapshot().index) + 1..=applied {
+            let term = raft.log().term(index).expect("applied entries are in the log or its snapshot");
+            let expected = *self.applied.entry(index).or_insert(term);
+            assert_eq!(
+                expected, term,
+                "seed {}: node {} applied an entry of term {} at index {}, others one of term {}",
+                self.seed, id, term, index, expected
+            );
+        }
+        sim_node.checked = applied;
+    }
+
+    /// Indexes applied by each running node.
+    pub fn applied_indexes(&self) -> BTreeMap<NodeId, u64> {
+        self.nodes
+            .iter()
+            .filter_map(|(id, n)| Some((*id, n.node.as_ref()?.raft().log().applied())))
+            .collect()
+    }
+
+    /// Leaders seen so far, by term.
+    pub fn leader_history(&self) -> &BTreeMap<u64, NodeId> {
+        &self.leaders
+    }
+}
@@ -0,0 +1,290 @@
+use std::panic::{self, AssertUnwindSafe};
+
+use shared::{AdminCommand, ErrorCode};
+
+use super::linearizability::{self, ChatInput, ChatLog, ChatOutput, Operation, Recorder};
+use super::*;
+use crate::node::{Origin, Proposal};
+
+/// Seeds tried by each test. Set `RAFT_SIM_SEED` to rerun a single one.
+const SEEDS: u64 = 16;
+
+fn for_each_seed(test: impl Fn(u64)) {
+    let seeds: Vec<u64> = match std::env::var("RAFT_SIM_SEED") {
+        Ok(seed) => vec![seed.parse().expect("RAFT_SIM_SEED must be a number")],
+        Err(_) => (0..SEEDS).collect(),
+    };
+
+    for seed in seeds {
+        if panic::catch_unwind(AssertUnwindSafe(|| test(seed))).is_err() {
+            panic!("simulation failed, rerun it with RAFT_SIM_SEED={}", seed);
+        }
+    }
+}
+
+// Runs a client workload while partitioning, crashing and restarting nodes at random.
+fn chaos(sim: &mut Simulation, duration: Duration, mut workload: impl FnMut(&mut Simulation)) {
+    const STEP: Duration = Duration::from_millis(200);
+    let ids = sim.ids();
+
+    for _ in 0..duration.as_millis() / STEP.as_millis() {
+        let victim = ids[sim.rng.below(ids.len() as u64) as usize];
+        match sim.rng.below(20) {
+            0 => {
+                let others: Vec<NodeId> = ids.iter().copied().filter(|id| *id != victim).collect();
+                sim.partition(&[&[victim], &others]);
+            }
+            1 | 2 => sim.heal(),
+            3 => sim.crash(victim),
+            4 | 5 => sim.restart(victim),
+            _ => {}
+        }
+
+        workload(sim);
+        sim.run_for(STEP);
+    }
+}
+
+fn chatter(sim: &mut Simulation) {
+    for id in sim.ids() {
+        if sim.rng.chance(0.1) {
+            sim.command(id, ChatCommand::Join("general".to_string()));
+        }
+        if sim.rng.chance(0.5) {
+            sim.send_message(id, "general", "hello");
+        }
+    }
+}
+
+#[test]
+fn cluster_stays_consistent_and_recovers_from_chaos() {
+    for_each_seed(|seed| {
+        let mut sim = Simulation::new(seed, 3, NetworkConfig::unreliable());
+        chaos(&mut sim, Duration::from_secs(30), chatter);
+
+        sim.heal();
+        for id in sim.ids() {
+            sim.restart(id);
+        }
+        sim.run_for(Duration::from_secs(5));
+        let leader = sim.leader().expect("a leader is elected once the cluster is healed");
+
+        // A fresh write commits everywhere, and with it everything before it.
+        sim.send_message(leader, "general", "after the storm");
+        sim.run_for(Duration::from_secs(2));
+        let applied = sim.applied_indexes();
+        let first = applied[&leader];
+        assert!(applied.values().all(|index| *index == first), "nodes did not converge: {:?}", applied);
+    });
+}
+
+#[test]
+fn runs_are_reproducible_from_the_seed() {
+    let run = |seed| {
+        let mut sim = Simulation::new(seed, 3, NetworkConfig::unreliable());
+        chaos(&mut sim, Duration::from_secs(10), chatter);
+        let responses: Vec<String> = sim.responses.iter().map(|r| format!("{:?}", r)).collect();
+        (sim.leader_history().clone(), sim.applied_indexes(), responses)
+    };
+
+    assert_eq!(run(7), run(7));
+    assert_ne!(run(7), run(8));
+}
+
+fn op(
+    client: u64,
+    input: ChatInput,
+    output: Option<ChatOutput>,
+    invoke: u64,
+    complete: Option<u64>,
+) -> Operation<ChatInput, ChatOutput> {
+    Operation {
+        client,
+        input,
+        output,
+        invoke: Duration::from_millis(invoke),
+        complete: complete.map(Duration::from_millis),
+    }
+}
+
+fn fetched(messages: &[u64]) -> Option<ChatOutput> {
+    Some(ChatOutput::Fetched { messages: messages.to_vec(), has_more: false })
+}
+
+#[test]
+fn linearizability_checker_accepts_only_valid_orders() {
+    let send = |id| ChatInput::Send { client_msg_id: id };
+    let fetch = ChatInput::Fetch { limit: 10 };
+
+    // A read that starts after a send was acknowledged has to see it.
+    let acked = || op(1, send(1), Some(ChatOutput::Sent), 0, Some(10));
+    let stale = [acked(), op(2, fetch.clone(), fetched(&[]), 20, Some(30))];
+    assert_eq!(linearizability::check(&ChatLog, &stale).unwrap_err().linearized, vec![0]);
+
+    // A concurrent one may or may not.
+    let concurrent = [acked(), op(2, fetch.clone(), fetched(&[]), 5, Some(30))];
+    assert!(linearizability::check(&ChatLog, &concurrent).is_ok());
+
+    // A send that was never answered may take effect at any later point, or not at all.
+    for (seen, linearizable) in [(&[2][..], true), (&[1, 2], true), (&[2, 1], true), (&[1], false), (&[], false)] {
+        let history = [
+            op(1, send(1), None, 0, None),
+            op(2, send(2), Some(ChatOutput::Sent), 10, Some(20)),
+            op(2, fetch.clone(), fetched(seen), 30, Some(40)),
+        ];
+        assert_eq!(linearizability::check(&ChatLog, &history).is_ok(), linearizable, "{:?}", seen);
+    }
+
+    // Two reads cannot disagree on the order of the messages they both saw.
+    let reordered = [
+        op(1, send(1), Some(ChatOutput::Sent), 0, Some(50)),
+        op(2, send(2), Some(ChatOutput::Sent), 0, Some(50)),
+        op(3, fetch.clone(), fetched(&[1, 2]), 10, Some(40)),
+        op(4, fetch, fetched(&[2, 1]), 10, Some(40)),
+    ];
+    assert!(linearizability::check(&ChatLog, &reordered).is_err());
+}
+
+#[test]
+fn leader_hands_over_and_redirects_clients_on_shutdown() {
+    for_each_seed(|seed| {
+        let mut sim = Simulation::new(seed, 3, NetworkConfig::reliable());
+        sim.run_for(Duration::from_secs(3));
+        let leader = sim.leader().expect("a leader is elected on a reliable network");
+        let session = sim.session(leader).unwrap();
+
+        let mut done = sim.shutdown(leader).unwrap();
+        sim.run_for(Duration::from_secs(3));
+        assert!(done.try_recv().is_ok(), "seed {}: shutdown did not finish", seed);
+
+        let successor = sim.leader().expect("leadership is handed over");
+        assert_ne!(successor, leader);
+        let redirect = sim.responses.iter().find_map(|(s, response)| match response {
+            ChatResponse::ServerShutdown { reconnect_to } if *s == session => Some(reconnect_to.clone()),
+            _ => None,
+        });
+        assert_eq!(redirect, Some(Some(format!("client{}", successor))));
+    });
+}
+
+#[test]
+fn sends_and_linearizable_reads_are_linearizable() {
+    for_each_seed(|seed| {
+        let mut sim = Simulation::new(seed, 3, NetworkConfig::unreliable());
+        // Joins can be lost like any other proposal, and repeating them is harmless.
+        for _ in 0..5 {
+            sim.run_for(Duration::from_secs(1));
+            for id in sim.ids() {
+                sim.command(id, ChatCommand::Join("general".to_string()));
+            }
+        }
+        sim.run_for(Duration::from_secs(1));
+
+        let mut recorder = Recorder::new(&sim, "general");
+        let mut workload = |sim: &mut Simulation| {
+            recorder.collect(sim);
+            for id in sim.ids() {
+                if sim.rng.chance(0.5) {
+                    recorder.fetch(sim, id, 20);
+                } else {
+                    recorder.send(sim, id);
+                }
+            }
+        };
+        chaos(&mut sim, Duration::from_secs(30), &mut workload);
+
+        // Keep going once the cluster has recovered, so every run has something to check.
+        sim.heal();
+        for id in sim.ids() {
+            sim.restart(id);
+        }
+        for _ in 0..50 {
+            workload(&mut sim);
+            sim.run_for(Duration::from_millis(200));
+        }
+        sim.run_for(Duration::from_secs(10));
+        recorder.collect(&mut sim);
+
+        let history = recorder.finish();
+        let completed = history.iter().filter(|o| o.output.is_some()).count();
+        assert!(completed > 10, "only {} of {} operations completed", completed, history.len());
+
+        if let Err(violation) = linearizability::check(&ChatLog, &history) {
+            let path = std::env::temp_dir().join(format!("raft-chat-linearizability-{}.html", seed));
+            linearizability::write_html(&path, &history, &violation).expect("failed to write the history");
+            panic!("history is not linearizable, see {}", path.display());
+        }
+    });
+}
+
+#[test]
+fn guests_of_different_nodes_are_different_users() {
+    let mut sim = Simulation::new(1, 3, NetworkConfig::reliable());
+    sim.run_for(Duration::from_secs(3));
+
+    // Each node numbers its sessions on its own, so both get the same session ID.
+    let session = 100;
+    let mut first = sim.connect_guest(1, session).unwrap();
+    let mut second = sim.connect_guest(2, session).unwrap();
+    // The first guest creates the room, so the second is not one of its moderators.
+    for id in [1, 2] {
+        sim.guest_command(id, session, ChatCommand::Join("lobby".to_string()));
+        sim.run_for(Duration::from_secs(1));
+    }
+
+    let message = Message {
+        id: 0,
+        client_msg_id: 1,
+        sender: String::new(),
+        room: "lobby".to_string(),
+        reply_to: None,
+        content: "hello".to_string(),
+        timestamp: 0,
+        edited: false,
+        reactions: Default::default(),
+    };
+    sim.guest_command(1, session, ChatCommand::SendMessage(message));
+    sim.run_for(Duration::from_secs(1));
+    let id = std::iter::from_fn(|| first.try_recv().ok())
+        .find_map(|response| match response {
+            ChatResponse::Ack { committed_index, .. } => Some(committed_index),
+            _ => None,
+        })
+        .expect("the first guest's message is acknowledged");
+
+    let edit = ChatCommand::EditMessage { id, new_content: "hijacked".to_string() };
+    sim.guest_command(2, session, edit);
+    sim.run_for(Duration::from_secs(1));
+    let responses: Vec<ChatResponse> = std::iter::from_fn(|| second.try_recv().ok()).collect();
+    assert!(
+        responses.iter().any(|r| matches!(r, ChatResponse::Error(e) if e.code == ErrorCode::Unauthorized)),
+        "{:?}",
+        responses
+    );
+    assert!(!responses.iter().any(|r| matches!(r, ChatResponse::MessageEdited { .. })));
+}
+
+#[test]
+fn forwarded_admin_commands_are_refused() {
+    let mut sim = Simulation::new(1, 3, NetworkConfig::reliable());
+    sim.run_for(Duration::from_secs(3));
+    let leader = sim.leader().expect("a leader is elected on a reliable network");
+    let victim = sim.ids().into_iter().find(|id| *id != leader).unwrap();
+    let session = sim.session(victim).unwrap();
+
+    // Anyone who reaches the peer port can claim to be a node forwarding a proposal.
+    let kick = AdminCommand::KickUser { nick: format!("user{}", victim), reason: String::new(), duration_secs: None };
+    let proposal = Proposal {
+        origin: Origin { node: victim, session: 0 },
+        user: "mallory".to_string(),
+        command: ChatCommand::Admin(kick),
+        time: 0,
+        credential: String::new(),
+        addr: None,
+    };
+    sim.peer_message(leader, PeerMessage::Forward(proposal));
+    sim.run_for(Duration::from_secs(1));
+
+    assert_eq!(sim.session(victim), Some(session));
+    assert!(!sim.responses.iter().any(|(_, r)| matches!(r, ChatResponse::Kicked { .. })));
+}
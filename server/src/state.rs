@@ -0,0 +1,1131 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::net::IpAddr;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use shared::{
+    AuditEntry, ChatCommand, ChatError, ChatEvent, ChatResponse, Message, ModerationAction, Retention, RoomChange,
+    RoomInfo, RoomRole, RoomSettings, format_duration,
+};
+
+use crate::search::SearchIndex;
+
+/// Largest page a single history request may return.
+const MAX_HISTORY_PAGE: usize = 200;
+
+const MAX_EMOJI_CHARS: usize = 16;
+
+const MAX_NICK_CHARS: usize = 32;
+
+/// Mentions remembered per user; older ones are dropped.
+const MAX_MENTIONS: usize = 100;
+
+/// Moderation actions kept in the audit log; older ones are dropped.
+const MAX_AUDIT_ENTRIES: usize = 1000;
+
+/// Messages remembered per user to recognize resent ones; older ones are forgotten.
+const MAX_TRACKED_SENDS: usize = 100;
+
+/// Who should receive a response produced by applying an entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Audience {
+    /// Every member of the room.
+    Room(String),
+    /// Every member of the room except the session that proposed the entry.
+    RoomExceptOrigin(String),
+    /// Every session of a single user.
+    User(String),
+    /// The session that proposed the entry.
+    Origin,
+}
+
+#[derive(Debug, Clone)]
+pub struct Effect {
+    pub audience: Audience,
+    pub response: ChatResponse,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Room {
+    /// The user that created the room, unless they handed it over.
+    pub owner: String,
+    /// Members the owner appointed to moderate the room alongside them.
+    #[serde(default)]
+    pub operators: BTreeSet<String>,
+    pub members: BTreeSet<String>,
+    /// IDs of the messages posted to the room, oldest first.
+    pub messages: Vec<u64>,
+    /// Users who may not join the room.
+    #[serde(default)]
+    pub bans: BTreeMap<String, Sanction>,
+    /// Members who may not send messages to the room.
+    #[serde(default)]
+    pub mutes: BTreeMap<String, Sanction>,
+    #[serde(default)]
+    pub settings: RoomSettings,
+    /// Users invited to join while the room is invite-only. An invitation is used up
+    /// by joining.
+    #[serde(default)]
+    pub invites: BTreeSet<String>,
+}
+
+impl Room {
+    pub fn role(&self, user: &str) -> RoomRole {
+        if self.owner == user {
+            RoomRole::Owner
+        } else if self.operators.contains(user) {
+            RoomRole::Operator
+        } else {
+            RoomRole::Member
+        }
+    }
+
+    /// Whether `user` may change other members' messages and take moderation actions.
+    pub fn is_moderator(&self, user: &str) -> bool {
+        self.role(user) >= RoomRole::Operator
+    }
+
+    fn info(&self, name: &str) -> RoomInfo {
+        RoomInfo {
+            room: name.to_string(),
+            owner: self.owner.clone(),
+            operators: self.operators.iter().cloned().collect(),
+            members: self.members.len(),
+            settings: self.settings.clone(),
+        }
+    }
+
+    // Fails if `user` is banned from or muted in the room, which is called `name`
+    fn check_may_post(&self, name: &str, user: &str, time: u64) -> ChatEvent<()> {
+        if let Some(ban) = self.bans.get(user).filter(|ban| ban.is_active(time)) {
+            return Err(ChatError::Banned(format!("{} is banned from {}{}", user, name, ban.describe(time))));
+        }
+        if let Some(mute) = self.mutes.get(user).filter(|mute| mute.is_active(time)) {
+            return Err(ChatError::Unauthorized(format!("{} is muted in {}{}", user, name, mute.describe(time))));
+        }
+        Ok(())
+    }
+
+    /// When the oldest message the room still keeps at `time` may have been sent.
+    fn retention_cutoff(&self, time: u64) -> u64 {
+        match self.settings.retention {
+            Retention::MaxAge { secs } => time.saturating_sub(secs.saturating_mul(1000)),
+            Retention::Forever | Retention::Messages(_) => 0,
+        }
+    }
+
+    fn lift_expired(&mut self, time: u64) {
+        self.bans.retain(|_, ban| ban.is_active(time));
+        self.mutes.retain(|_, mute| mute.is_active(time));
+    }
+}
+
+/// A ban or mute.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sanction {
+    /// When it lifts, in milliseconds since the Unix epoch; `None` if it never does.
+    pub until: Option<u64>,
+    pub reason: String,
+    /// The address the user last took their nick from, which the sanction covers too so
+    /// that it follows them to another nick.
+    #[serde(default)]
+    pub addr: Option<IpAddr>,
+}
+
+impl Sanction {
+    fn is_active(&self, time: u64) -> bool {
+        self.until.is_none_or(|until| time < until)
+    }
+
+    // How long it has left, as the end of a sentence
+    fn describe(&self, time: u64) -> String {
+        let remaining = match self.until {
+            Some(until) => {
+                let left = Duration::from_millis(until.saturating_sub(time));
+                format!(" for another {}", format_duration(left))
+            }
+            None => String::new(),
+        };
+        match self.reason.as_str() {
+            "" => remaining,
+            reason => format!("{} ({})", remaining, reason),
+        }
+    }
+}
+
+/// The chat state machine. Every node applies the same committed entries in the same
+/// order, so the state is identical everywhere once entries are applied.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ChatState {
+    rooms: BTreeMap<String, Room>,
+    messages: BTreeMap<u64, Message>,
+    /// Replies to each thread root, oldest first.
+    threads: BTreeMap<u64, Vec<u64>>,
+    /// IDs of the messages mentioning each user, oldest first.
+    mentions: BTreeMap<String, Vec<u64>>,
+    /// Newest mention each user has seen.
+    mentions_read: BTreeMap<String, u64>,
+    search: SearchIndex,
+    /// Moderation actions in every room, oldest first.
+    #[serde(default)]
+    audit: VecDeque<AuditEntry>,
+    /// Client message IDs of each user's latest messages and the indexes they were
+    /// committed at, so a message the client resends is acknowledged, not posted again.
+    #[serde(default)]
+    sent: BTreeMap<String, VecDeque<(u64, u64)>>,
+    /// The credential each nick was first taken with.
+    #[serde(default)]
+    credentials: BTreeMap<String, String>,
+    /// The address each nick was last taken from. Loopback addresses are shared by
+    /// everyone on the machine, so they are left out.
+    #[serde(default)]
+    addrs: BTreeMap<String, IpAddr>,
+    /// When each nick kicked off the server may come back, in milliseconds since the
+    /// Unix epoch.
+    #[serde(default)]
+    suspended: BTreeMap<String, u64>,
+    /// The same for the addresses those nicks were last taken from.
+    #[serde(default)]
+    suspended_addrs: BTreeMap<IpAddr, u64>,
+}
+
+impl ChatState {
+    /// Checks that `credential` may act as `user`. Guests and nicks nobody has taken yet
+    /// need no particular credential.
+    pub fn authenticate(&self, user: &str, credential: &str) -> ChatEvent<()> {
+        match self.credentials.get(user) {
+            Some(registered) if registered != credential => {
+                Err(ChatError::Unauthorized(format!("{} is taken by someone else", user)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The credential `nick` was registered with, if anyone has taken it.
+    pub fn credential(&self, nick: &str) -> Option<&str> {
+        self.credentials.get(nick).map(String::as_str)
+    }
+
+    /// Checks that `credential` may act as `user` from `addr` at `time`, and that the
+    /// address is not banned from a room `command` joins or muted in one it sends to.
+    pub fn admit(
+        &self,
+        user: &str,
+        credential: &str,
+        addr: Option<IpAddr>,
+        time: u64,
+        command: &ChatCommand,
+    ) -> ChatEvent<()> {
+        self.authenticate(user, credential)?;
+        self.check_suspended(user, addr, time)?;
+
+        let (name, joining) = match command {
+            ChatCommand::Join(name) => (name, true),
+            ChatCommand::SendMessage(message) => (&message.room, false),
+            _ => return Ok(()),
+        };
+        let Some((room, addr)) = self.rooms.get(name).zip(addr) else {
+            return Ok(());
+        };
+        let (sanctions, verb) = if joining { (&room.bans, "banned from") } else { (&room.mutes, "muted in") };
+        match sanctions.iter().find(|(_, s)| s.addr == Some(addr) && s.is_active(time)) {
+            Some((nick, sanction)) => {
+                Err(ChatError::Banned(format!("{} is {} {}{}", nick, verb, name, sanction.describe(time))))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Fails if `user` or `addr` was kicked off the server and may not come back yet.
+    pub fn check_suspended(&self, user: &str, addr: Option<IpAddr>, time: u64) -> ChatEvent<()> {
+        let until = self.suspended.get(user).into_iter().chain(addr.and_then(|addr| self.suspended_addrs.get(&addr)));
+        match until.copied().max() {
+            Some(until) if until > time => Err(ChatError::Banned(format!(
+                "kicked off the server, come back in {}",
+                format_duration(Duration::from_millis(until - time))
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Keeps `nick`, and the address it was last taken from, out until `until`.
+    pub fn suspend(&mut self, nick: &str, until: u64, time: u64) {
+        self.suspended.retain(|_, end| *end > time);
+        self.suspended_addrs.retain(|_, end| *end > time);
+
+        self.suspended.insert(nick.to_string(), until);
+        if let Some(addr) = self.addrs.get(nick) {
+            self.suspended_addrs.insert(*addr, until);
+        }
+    }
+
+    /// Takes `nick` with `credential` from `addr`, registering it if nobody has taken it
+    /// before. Guest names are left to the sessions they were given to.
+    pub fn take_nick(&mut self, nick: &str, credential: &str, addr: Option<IpAddr>, time: u64) -> ChatEvent<()> {
+        if nick.is_empty() || nick.chars().count() > MAX_NICK_CHARS || nick.contains(char::is_whitespace) {
+            return Err(ChatError::Protocol(format!(
+                "nicks are 1 to {} characters without spaces",
+                MAX_NICK_CHARS
+            )));
+        }
+        if nick.starts_with("guest-") {
+            return Err(ChatError::Protocol(format!("{} is reserved for guests", nick)));
+        }
+        if credential.is_empty() {
+            return Err(ChatError::Protocol("a secret is needed to take a nick".to_string()));
+        }
+
+        self.authenticate(nick, credential)?;
+        self.check_suspended(nick, addr, time)?;
+        self.credentials.insert(nick.to_string(), credential.to_string());
+        if let Some(addr) = addr.filter(|addr| !addr.is_loopback()) {
+            self.addrs.insert(nick.to_string(), addr);
+        }
+        Ok(())
+    }
+
+    /// Applies a command committed at log position `index` on behalf of `user`, as of
+    /// `time` in milliseconds since the Unix epoch, returning the responses that should be
+    /// delivered to connected clients.
+    pub fn apply(&mut self, index: u64, time: u64, user: &str, command: &ChatCommand) -> ChatEvent<Vec<Effect>> {
+        match command {
+            ChatCommand::SendMessage(message) => {
+                let client_msg_id = message.client_msg_id;
+                let resent = self.sent.get(user).and_then(|sent| sent.iter().find(|(id, _)| *id == client_msg_id));
+                if let Some(&(_, committed_index)) = resent.filter(|_| client_msg_id != 0) {
+                    return Ok(vec![Effect {
+                        audience: Audience::Origin,
+                        response: ChatResponse::Ack { client_msg_id, committed_index },
+                    }]);
+                }
+
+                self.member_room(&message.room, user)?.check_may_post(&message.room, user, time)?;
+
+                // Threads are flat: a reply to a reply joins the parent's thread.
+                let reply_to = match message.reply_to {
+                    Some(parent) => {
+                        let parent = self
+                            .messages
+                            .get(&parent)
+                            .filter(|p| p.room == message.room)
+                            .ok_or_else(|| ChatError::NotFound(format!("no such message: {}", parent)))?;
+                        Some(parent.reply_to.unwrap_or(parent.id))
+                    }
+                    None => None,
+                };
+
+                // Retention goes by when messages were committed, not the sender's clock.
+                // Entries from before proposals were stamped keep the sender's time.
+                let mut message = message.clone();
+                message.id = index;
+                message.sender = user.to_string();
+                message.reply_to = reply_to;
+                if time > 0 {
+                    message.timestamp = time;
+                }
+                self.messages.insert(index, message.clone());
+                self.member_room_mut(&message.room, user)?.messages.push(index);
+                if let Some(root) = reply_to {
+                    self.threads.entry(root).or_default().push(index);
+                }
+                self.record_mentions(&message);
+                self.search.insert(index, &message.content);
+                self.expire_messages(&message.room, time);
+                if client_msg_id != 0 {
+                    let sent = self.sent.entry(user.to_string()).or_default();
+                    sent.push_back((client_msg_id, index));
+                    if sent.len() > MAX_TRACKED_SENDS {
+                        sent.pop_front();
+                    }
+                }
+
+                Ok(vec![
+                    Effect {
+                        audience: Audience::RoomExceptOrigin(message.room.clone()),
+                        response: ChatResponse::MessageReceived(message),
+                    },
+                    Effect {
+                        audience: Audience::Origin,
+                        response: ChatResponse::Ack { client_msg_id, committed_index: index },
+                    },
+                ])
+            }
+            ChatCommand::Join(name) => {
+                let room = self.rooms.entry(name.clone()).or_insert_with(|| Room {
+                    owner: user.to_string(),
+                    ..Room::default()
+                });
+                room.lift_expired(time);
+                if let Some(ban) = room.bans.get(user) {
+                    return Err(ChatError::Banned(format!("{} is banned from {}{}", user, name, ban.describe(time))));
+                }
+
+                // Owners and operators get in regardless, and members joining again are
+                // already counted.
+                if !room.members.contains(user) && !room.is_moderator(user) {
+                    if room.settings.invite_only && !room.invites.remove(user) {
+                        return Err(ChatError::Unauthorized(format!("{} is invite-only", name)));
+                    }
+                    if room.settings.max_members.is_some_and(|max| room.members.len() >= max) {
+                        return Err(ChatError::Unauthorized(format!("{} is full", name)));
+                    }
+                }
+                room.members.insert(user.to_string());
+
+                Ok(vec![
+                    Effect {
+                        audience: Audience::Room(name.clone()),
+                        response: ChatResponse::Joined(user.to_string()),
+                    },
+                    Effect {
+                        audience: Audience::User(user.to_string()),
+                        response: ChatResponse::RoomInfo(room.info(name)),
+                    },
+                ])
+            }
+            ChatCommand::Leave(name) => {
+                let room = self.member_room_mut(name, user)?;
+                room.members.remove(user);
+
+                let response = ChatResponse::Left(user.to_string());
+                Ok(vec![
+                    Effect { audience: Audience::Room(name.clone()), response: response.clone() },
+                    Effect { audience: Audience::User(user.to_string()), response },
+                ])
+            }
+            ChatCommand::EditMessage { id, new_content } => {
+                let room = self.authorize_change(*id, user, time)?;
+                if let Some(message) = self.messages.get_mut(id) {
+                    self.search.remove(*id, &message.content);
+                    self.search.insert(*id, new_content);
+                    message.content = new_content.clone();
+                    message.edited = true;
+                }
+
+                Ok(vec![Effect {
+                    audience: Audience::Room(room.clone()),
+                    response: ChatResponse::MessageEdited {
+                        id: *id,
+                        room,
+                        new_content: new_content.clone(),
+                    },
+                }])
+            }
+            ChatCommand::DeleteMessage { id } => {
+                let room = self.authorize_change(*id, user, time)?;
+                self.remove_message(*id);
+                if let Some(r) = self.rooms.get_mut(&room) {
+                    r.messages.retain(|m| m != id);
+                }
+
+                Ok(vec![Effect {
+                    audience: Audience::Room(room.clone()),
+                    response: ChatResponse::MessageDeleted { id: *id, room },
+                }])
+            }
+            ChatCommand::React { message_id, emoji } => self.react(*message_id, user, emoji, true),
+            ChatCommand::Unreact { message_id, emoji } => self.react(*message_id, user, emoji, false),
+            ChatCommand::MarkMentionsRead { up_to } => {
+                let read = self.mentions_read.entry(user.to_string()).or_default();
+                *read = (*read).max(*up_to);
+                Ok(Vec::new())
+            }
+            ChatCommand::Moderate { room, nick, action, reason } => {
+                let entry = AuditEntry {
+                    index,
+                    time,
+                    room: room.clone(),
+                    by: user.to_string(),
+                    nick: nick.clone(),
+                    action: action.clone(),
+                    reason: reason.clone(),
+                };
+                self.moderate(entry)
+            }
+            ChatCommand::UpdateRoom { room: name, change } => {
+                let room = self.member_room_mut(name, user)?;
+                if !room.is_moderator(user) {
+                    return Err(ChatError::Unauthorized(format!("only operators may change {}", name)));
+                }
+                match change {
+                    RoomChange::Topic(topic) => room.settings.topic = topic.clone(),
+                    RoomChange::Description(description) => room.settings.description = description.clone(),
+                    RoomChange::InviteOnly(invite_only) => room.settings.invite_only = *invite_only,
+                    RoomChange::MaxMembers(Some(0)) | RoomChange::Retention(Retention::Messages(0)) => {
+                        return Err(ChatError::Protocol("a room's limits must be at least 1".to_string()));
+                    }
+                    RoomChange::MaxMembers(max) => room.settings.max_members = *max,
+                    RoomChange::Retention(retention) => room.settings.retention = *retention,
+                }
+                let info = room.info(name);
+                self.expire_messages(name, time);
+
+                Ok(vec![Effect { audience: Audience::Room(name.clone()), response: ChatResponse::RoomInfo(info) }])
+            }
+            ChatCommand::Nick { .. }
+            | ChatCommand::FetchHistory { .. }
+            | ChatCommand::FetchMentions { .. }
+            | ChatCommand::Search { .. }
+            | ChatCommand::Who { .. }
+            | ChatCommand::RoomInfo { .. }
+            | ChatCommand::Admin(_) => Err(ChatError::Protocol(
+                "command is not replicated".to_string(),
+            )),
+        }
+    }
+
+    pub fn room(&self, name: &str) -> Option<&Room> {
+        self.rooms.get(name)
+    }
+
+    /// Returns the newest `limit` moderation actions, oldest first, only those taken in
+    /// `room` if one is given.
+    pub fn audit_log(&self, room: Option<&str>, limit: usize) -> Vec<AuditEntry> {
+        let mut entries: Vec<AuditEntry> = self
+            .audit
+            .iter()
+            .rev()
+            .filter(|entry| room.is_none_or(|room| entry.room == room))
+            .take(limit.clamp(1, MAX_AUDIT_ENTRIES))
+            .cloned()
+            .collect();
+        entries.reverse();
+        entries
+    }
+
+    /// Describes `room` to anyone, so they can see its topic and whether they may join.
+    pub fn room_info(&self, room: &str) -> ChatEvent<ChatResponse> {
+        let r = self.rooms.get(room).ok_or_else(|| ChatError::NoSuchRoom(room.to_string()))?;
+        Ok(ChatResponse::RoomInfo(r.info(room)))
+    }
+
+    /// Lists the members of `room`, which `user` must belong to.
+    pub fn members(&self, user: &str, room: &str) -> ChatEvent<ChatResponse> {
+        let r = self.member_room(room, user)?;
+        Ok(ChatResponse::Members { room: room.to_string(), members: r.members.iter().cloned().collect() })
+    }
+
+    /// Returns a page of `room`'s history for `user`, oldest message first, as of `time`.
+    /// With `thread` set, the page is taken from that thread, root message first.
+    pub fn history(
+        &self,
+        user: &str,
+        room: &str,
+        thread: Option<u64>,
+        before: Option<u64>,
+        limit: usize,
+        time: u64,
+    ) -> ChatEvent<ChatResponse> {
+        let r = self.member_room(room, user)?;
+        let limit = limit.clamp(1, MAX_HISTORY_PAGE);
+
+        let ids = match thread {
+            Some(root) => {
+                self.messages
+                    .get(&root)
+                    .filter(|m| m.room == room)
+                    .ok_or_else(|| ChatError::NotFound(format!("no such message: {}", root)))?;
+                let mut ids = vec![root];
+                ids.extend(self.threads.get(&root).into_iter().flatten());
+                ids
+            }
+            None => r.messages.clone(),
+        };
+        // Messages are only dropped when the room is written to, so a quiet room may
+        // still hold some that are too old to show.
+        let cutoff = r.retention_cutoff(time);
+        let ids: Vec<u64> =
+            ids.into_iter().filter(|id| self.messages.get(id).is_some_and(|m| m.timestamp >= cutoff)).collect();
+
+        // Message IDs are log indexes, so they are already sorted.
+        let end = match before {
+            Some(before) => ids.partition_point(|id| *id < before),
+            None => ids.len(),
+        };
+        let start = end.saturating_sub(limit);
+
+        Ok(ChatResponse::History {
+            room: room.to_string(),
+            thread,
+            messages: ids[start..end]
+                .iter()
+                .filter_map(|id| self.messages.get(id).cloned())
+                .collect(),
+            has_more: start > 0,
+            // Filled in by the node answering the read.
+            applied_index: 0,
+        })
+    }
+
+    /// Returns the `limit` most recent messages mentioning `user`, oldest first.
+    pub fn recent_mentions(&self, user: &str, limit: usize) -> Vec<Message> {
+        let ids = self.mentions.get(user).map(Vec::as_slice).unwrap_or_default();
+        let start = ids.len().saturating_sub(limit.clamp(1, MAX_MENTIONS));
+        ids[start..].iter().filter_map(|id| self.messages.get(id).cloned()).collect()
+    }
+
+    /// Returns the mentions of `user` newer than the last one they marked as read.
+    pub fn unread_mentions(&self, user: &str) -> Vec<Message> {
+        let read = self.mentions_read.get(user).copied().unwrap_or(0);
+        self.mentions
+            .get(user)
+            .into_iter()
+            .flatten()
+            .filter(|id| **id > read)
+            .filter_map(|id| self.messages.get(id).cloned())
+            .collect()
+    }
+
+    /// Searches the rooms `user` is a member of, newest match first, as of `time`.
+    pub fn search(
+        &self,
+        user: &str,
+        query: &str,
+        room: Option<&str>,
+        from: Option<&str>,
+        limit: usize,
+        time: u64,
+    ) -> ChatEvent<ChatResponse> {
+        if let Some(room) = room {
+            self.member_room(room, user)?;
+        }
+
+        let messages = self
+            .search
+            .search(query)
+            .into_iter()
+            .filter_map(|id| self.messages.get(&id))
+            .filter(|m| room.is_none_or(|room| m.room == room))
+            .filter(|m| from.is_none_or(|from| m.sender == from))
+            .filter(|m| {
+                self.rooms
+                    .get(&m.room)
+                    .is_some_and(|r| r.members.contains(user) && m.timestamp >= r.retention_cutoff(time))
+            })
+            .take(limit.clamp(1, MAX_HISTORY_PAGE))
+            .cloned()
+            .collect();
+
+        Ok(ChatResponse::SearchResults { query: query.to_string(), messages, applied_index: 0 })
+    }
+
+    // Drops the messages of `room` that its retention policy no longer keeps.
+    fn expire_messages(&mut self, room: &str, time: u64) {
+        let Some(r) = self.rooms.get_mut(room) else {
+            return;
+        };
+        let expired = match r.settings.retention {
+            Retention::Forever => 0,
+            Retention::Messages(keep) => r.messages.len().saturating_sub(keep),
+            Retention::MaxAge { .. } => {
+                let cutoff = r.retention_cutoff(time);
+                let messages = &self.messages;
+                r.messages.partition_point(|id| messages.get(id).is_none_or(|m| m.timestamp < cutoff))
+            }
+        };
+        for id in r.messages.drain(..expired).collect::<Vec<_>>() {
+            self.remove_message(id);
+        }
+    }
+
+    // Forgets a message everywhere but its room's list, which the caller updates.
+    fn remove_message(&mut self, id: u64) {
+        let removed = self.messages.remove(&id);
+        if let Some(message) = &removed {
+            self.search.remove(id, &message.content);
+        }
+        if let Some(root) = removed.and_then(|m| m.reply_to)
+            && let Some(replies) = self.threads.get_mut(&root)
+        {
+            replies.retain(|m| *m != id);
+        }
+    }
+
+    // Only members of the room can be mentioned, so nobody can fill a stranger's inbox.
+    fn record_mentions(&mut self, message: &Message) {
+        let Some(room) = self.rooms.get(&message.room) else {
+            return;
+        };
+
+        let mut mentioned: Vec<&str> = shared::mentions(&message.content)
+            .filter(|nick| *nick != message.sender && room.members.contains(*nick))
+            .collect();
+        mentioned.sort_unstable();
+        mentioned.dedup();
+
+        for nick in mentioned {
+            let ids = self.mentions.entry(nick.to_string()).or_default();
+            ids.push(message.id);
+            if ids.len() > MAX_MENTIONS {
+                ids.remove(0);
+            }
+        }
+    }
+
+    // Owners and operators moderate members; only the owner manages roles, and nobody
+    // acts against someone of the same or a higher role.
+    fn moderate(&mut self, entry: AuditEntry) -> ChatEvent<Vec<Effect>> {
+        let AuditEntry { room: name, by, nick, action, time, .. } = &entry;
+        let addr = self.addrs.get(nick).copied();
+        let room = self.rooms.get_mut(name).ok_or_else(|| ChatError::NoSuchRoom(name.clone()))?;
+
+        let required = match action {
+            ModerationAction::SetRole(_) => RoomRole::Owner,
+            _ => RoomRole::Operator,
+        };
+        if room.role(by) < required || nick == by || room.role(nick) >= room.role(by) {
+            return Err(ChatError::Unauthorized(format!("{} may not do that to {} in {}", by, nick, name)));
+        }
+
+        let needs_member = matches!(
+            action,
+            ModerationAction::Kick
+                | ModerationAction::Mute { .. }
+                | ModerationAction::SetRole(RoomRole::Operator | RoomRole::Owner)
+        );
+        if needs_member && !room.members.contains(nick) {
+            return Err(ChatError::NotFound(format!("{} is not a member of {}", nick, name)));
+        }
+
+        room.lift_expired(*time);
+        let until = |duration_secs: &Option<u64>| {
+            duration_secs.map(|secs| time.saturating_add(secs.saturating_mul(1000)))
+        };
+        let sanction = |duration_secs| Sanction { until: until(duration_secs), reason: entry.reason.clone(), addr };
+        match action {
+            ModerationAction::Kick => {
+                room.members.remove(nick);
+                room.operators.remove(nick);
+            }
+            ModerationAction::Ban { duration_secs } => {
+                room.members.remove(nick);
+                room.operators.remove(nick);
+                room.invites.remove(nick);
+                room.bans.insert(nick.clone(), sanction(duration_secs));
+            }
+            ModerationAction::Unban => {
+                room.bans
+                    .remove(nick)
+                    .ok_or_else(|| ChatError::NotFound(format!("{} is not banned from {}", nick, name)))?;
+            }
+            ModerationAction::Mute { duration_secs } => {
+                room.mutes.insert(nick.clone(), sanction(duration_secs));
+            }
+            ModerationAction::Unmute => {
+                room.mutes
+                    .remove(nick)
+                    .ok_or_else(|| ChatError::NotFound(format!("{} is not muted in {}", nick, name)))?;
+            }
+            ModerationAction::SetRole(RoomRole::Member) => {
+                room.operators.remove(nick);
+            }
+            ModerationAction::SetRole(RoomRole::Operator) => {
+                room.operators.insert(nick.clone());
+            }
+            ModerationAction::SetRole(RoomRole::Owner) => {
+                room.operators.remove(nick);
+                room.operators.insert(std::mem::replace(&mut room.owner, nick.clone()));
+            }
+            ModerationAction::Invite => {
+                room.invites.insert(nick.clone());
+            }
+        }
+
+        // Users no longer in the room still hear what happened to them.
+        let response = ChatResponse::Moderated(entry.clone());
+        let mut effects = vec![Effect { audience: Audience::Room(name.clone()), response: response.clone() }];
+        if !room.members.contains(nick) {
+            effects.push(Effect { audience: Audience::User(nick.clone()), response });
+        }
+
+        self.audit.push_back(entry);
+        if self.audit.len() > MAX_AUDIT_ENTRIES {
+            self.audit.pop_front();
+        }
+        Ok(effects)
+    }
+
+    fn react(&mut self, message_id: u64, user: &str, emoji: &str, add: bool) -> ChatEvent<Vec<Effect>> {
+        if emoji.is_empty() || emoji.chars().count() > MAX_EMOJI_CHARS || emoji.contains(char::is_whitespace) {
+            return Err(ChatError::Protocol(format!("invalid reaction: {:?}", emoji)));
+        }
+
+        let room = self
+            .messages
+            .get(&message_id)
+            .map(|m| m.room.clone())
+            .ok_or_else(|| ChatError::NotFound(format!("no such message: {}", message_id)))?;
+        self.member_room(&room, user)?;
+
+        let message = self.messages.get_mut(&message_id).expect("message was looked up above");
+        if add {
+            message.reactions.entry(emoji.to_string()).or_default().insert(user.to_string());
+        } else if let Some(users) = message.reactions.get_mut(emoji) {
+            users.remove(user);
+            if users.is_empty() {
+                message.reactions.remove(emoji);
+            }
+        }
+
+        Ok(vec![Effect {
+            audience: Audience::Room(room.clone()),
+            response: ChatResponse::ReactionsUpdated {
+                id: message_id,
+                room,
+                reactions: message.reactions.clone(),
+            },
+        }])
+    }
+
+    fn member_room(&self, name: &str, user: &str) -> ChatEvent<&Room> {
+        let room = self
+            .rooms
+            .get(name)
+            .ok_or_else(|| ChatError::NoSuchRoom(name.to_string()))?;
+
+        if !room.members.contains(user) {
+            return Err(ChatError::Unauthorized(format!("{} is not a member of {}", user, name)));
+        }
+
+        Ok(room)
+    }
+
+    fn member_room_mut(&mut self, name: &str, user: &str) -> ChatEvent<&mut Room> {
+        self.member_room(name, user)?;
+        Ok(self.rooms.get_mut(name).expect("room was looked up above"))
+    }
+
+    // Only the sender of a message or a moderator of its room may change it, and the
+    // sender only while they could still post it. Returns the name of the room the
+    // message belongs to.
+    fn authorize_change(&self, id: u64, user: &str, time: u64) -> ChatEvent<String> {
+        let message = self
+            .messages
+            .get(&id)
+            .ok_or_else(|| ChatError::NotFound(format!("no such message: {}", id)))?;
+
+        let is_moderator = self
+            .rooms
+            .get(&message.room)
+            .is_some_and(|room| room.is_moderator(user));
+        if is_moderator {
+            return Ok(message.room.clone());
+        }
+
+        if message.sender != user {
+            return Err(ChatError::Unauthorized(format!(
+                "{} may not change message {}",
+                user, id
+            )));
+        }
+        self.member_room(&message.room, user)?.check_may_post(&message.room, user, time)?;
+
+        Ok(message.room.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(room: &str, content: &str) -> ChatCommand {
+        ChatCommand::SendMessage(Message {
+            id: 0,
+            client_msg_id: 0,
+            sender: String::new(),
+            room: room.to_string(),
+            reply_to: None,
+            content: content.to_string(),
+            timestamp: 0,
+            edited: false,
+            reactions: BTreeMap::new(),
+        })
+    }
+
+    #[test]
+    fn only_sender_or_moderator_can_change_messages() {
+        let mut state = ChatState::default();
+        state.apply(1, 0, "owner", &ChatCommand::Join("general".into())).unwrap();
+        state.apply(2, 0, "alice", &ChatCommand::Join("general".into())).unwrap();
+        state.apply(3, 0, "bob", &ChatCommand::Join("general".into())).unwrap();
+        state.apply(4, 0, "alice", &message("general", "hello")).unwrap();
+
+        let edit = ChatCommand::EditMessage { id: 4, new_content: "hi".into() };
+        assert!(matches!(state.apply(5, 0, "bob", &edit), Err(ChatError::Unauthorized(_))));
+        state.apply(6, 0, "alice", &edit).unwrap();
+        assert!(state.messages[&4].edited);
+
+        let delete = ChatCommand::DeleteMessage { id: 4 };
+        assert!(matches!(state.apply(7, 0, "bob", &delete), Err(ChatError::Unauthorized(_))));
+        state.apply(8, 0, "owner", &delete).unwrap();
+        assert!(state.messages.is_empty());
+        assert!(state.room("general").unwrap().messages.is_empty());
+    }
+
+    #[test]
+    fn nicks_belong_to_whoever_takes_them_first() {
+        let mut state = ChatState::default();
+        state.take_nick("alice", "alice-secret", None, 0).unwrap();
+        state.take_nick("alice", "alice-secret", None, 0).unwrap();
+        assert!(matches!(state.take_nick("alice", "guess", None, 0), Err(ChatError::Unauthorized(_))));
+        assert!(matches!(state.authenticate("alice", "guess"), Err(ChatError::Unauthorized(_))));
+        state.authenticate("guest-1-7f", "").unwrap();
+
+        for nick in ["", "guest-1-7f", "two words"] {
+            assert!(matches!(state.take_nick(nick, "secret", None, 0), Err(ChatError::Protocol(_))));
+        }
+        assert!(matches!(state.take_nick("bob", "", None, 0), Err(ChatError::Protocol(_))));
+    }
+
+    #[test]
+    fn kicked_users_stay_out_until_their_suspension_ends() {
+        let mut state = ChatState::default();
+        let addr: Option<IpAddr> = Some([10, 0, 0, 1].into());
+        state.take_nick("mallory", "secret", addr, 0).unwrap();
+        state.suspend("mallory", 60_000, 1000);
+
+        let join = ChatCommand::Join("general".into());
+        assert!(matches!(state.admit("mallory", "secret", None, 2000, &join), Err(ChatError::Banned(_))));
+        assert!(matches!(state.take_nick("mallory2", "secret", addr, 2000), Err(ChatError::Banned(_))));
+        state.take_nick("bob", "secret", Some([10, 0, 0, 2].into()), 2000).unwrap();
+        state.admit("mallory", "secret", addr, 60_000, &join).unwrap();
+    }
+
+    #[test]
+    fn resent_messages_are_acknowledged_once() {
+        let mut state = ChatState::default();
+        state.apply(1, 0, "alice", &ChatCommand::Join("general".into())).unwrap();
+        let ChatCommand::SendMessage(mut hello) = message("general", "hello") else { unreachable!() };
+        hello.client_msg_id = 7;
+        let send = ChatCommand::SendMessage(hello);
+        state.apply(2, 0, "alice", &send).unwrap();
+
+        let effects = state.apply(3, 0, "alice", &send).unwrap();
+        assert!(matches!(
+            effects.as_slice(),
+            [Effect { audience: Audience::Origin, response: ChatResponse::Ack { client_msg_id: 7, committed_index: 2 } }]
+        ));
+        assert_eq!(state.messages.len(), 1);
+
+        state.apply(4, 0, "bob", &ChatCommand::Join("general".into())).unwrap();
+        state.apply(5, 0, "bob", &send).unwrap();
+        assert_eq!(state.messages.len(), 2);
+    }
+
+    #[test]
+    fn reactions_survive_snapshots_and_history() {
+        let mut state = ChatState::default();
+        state.apply(1, 0, "alice", &ChatCommand::Join("general".into())).unwrap();
+        state.apply(2, 0, "bob", &ChatCommand::Join("general".into())).unwrap();
+        state.apply(3, 0, "alice", &message("general", "hello")).unwrap();
+        for user in ["alice", "bob"] {
+            let react = ChatCommand::React { message_id: 3, emoji: "+1".into() };
+            state.apply(4, 0, user, &react).unwrap();
+        }
+        state.apply(5, 0, "bob", &ChatCommand::React { message_id: 3, emoji: "tada".into() }).unwrap();
+        state.apply(6, 0, "bob", &ChatCommand::Unreact { message_id: 3, emoji: "tada".into() }).unwrap();
+
+        let snapshot = serde_json::to_vec(&state).unwrap();
+        let restored: ChatState = serde_json::from_slice(&snapshot).unwrap();
+        let ChatResponse::History { messages, has_more, .. } = restored.history("bob", "general", None, None, 10, 0).unwrap() else {
+            panic!("expected a history page");
+        };
+        assert!(!has_more);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].reactions.len(), 1);
+        assert_eq!(messages[0].reactions["+1"].len(), 2);
+    }
+
+    #[test]
+    fn replies_are_grouped_by_thread_root() {
+        let mut state = ChatState::default();
+        state.apply(1, 0, "alice", &ChatCommand::Join("general".into())).unwrap();
+        state.apply(2, 0, "alice", &message("general", "root")).unwrap();
+        for (index, parent) in [(3, 2), (4, 3)] {
+            let ChatCommand::SendMessage(mut reply) = message("general", "reply") else { unreachable!() };
+            reply.reply_to = Some(parent);
+            state.apply(index, 0, "alice", &ChatCommand::SendMessage(reply)).unwrap();
+        }
+        state.apply(5, 0, "alice", &message("general", "unrelated")).unwrap();
+
+        let ChatResponse::History { messages, .. } = state.history("alice", "general", Some(2), None, 10, 0).unwrap() else {
+            panic!("expected a history page");
+        };
+        let ids: Vec<u64> = messages.iter().map(|m| m.id).collect();
+        assert_eq!(ids, vec![2, 3, 4]);
+        assert!(messages[1..].iter().all(|m| m.reply_to == Some(2)));
+    }
+
+    #[test]
+    fn operators_moderate_members_and_actions_are_audited() {
+        let mut state = ChatState::default();
+        for (index, user) in [(1, "owner"), (2, "op"), (3, "alice"), (4, "bob")] {
+            state.apply(index, 0, user, &ChatCommand::Join("general".into())).unwrap();
+        }
+        let moderate = |nick: &str, action| ChatCommand::Moderate {
+            room: "general".into(),
+            nick: nick.into(),
+            action,
+            reason: String::new(),
+        };
+
+        // Only the owner hands out roles, and operators cannot act against the owner.
+        let make_op = moderate("op", ModerationAction::SetRole(RoomRole::Operator));
+        assert!(matches!(state.apply(5, 0, "alice", &make_op), Err(ChatError::Unauthorized(_))));
+        state.apply(6, 0, "owner", &make_op).unwrap();
+        let kick_owner = moderate("owner", ModerationAction::Kick);
+        assert!(matches!(state.apply(7, 0, "op", &kick_owner), Err(ChatError::Unauthorized(_))));
+
+        // A muted member cannot send until the mute runs out.
+        state.apply(8, 1_000, "op", &moderate("alice", ModerationAction::Mute { duration_secs: Some(60) })).unwrap();
+        assert!(matches!(state.apply(9, 2_000, "alice", &message("general", "hi")), Err(ChatError::Unauthorized(_))));
+        state.apply(10, 61_000, "alice", &message("general", "hi")).unwrap();
+
+        // A banned user is removed and cannot rejoin until unbanned.
+        state.apply(11, 0, "op", &moderate("bob", ModerationAction::Ban { duration_secs: None })).unwrap();
+        assert!(!state.room("general").unwrap().members.contains("bob"));
+        let join = ChatCommand::Join("general".into());
+        assert!(matches!(state.apply(12, 0, "bob", &join), Err(ChatError::Banned(_))));
+        state.apply(13, 0, "owner", &moderate("bob", ModerationAction::Unban)).unwrap();
+        state.apply(14, 0, "bob", &join).unwrap();
+
+        let audit: Vec<u64> = state.audit_log(Some("general"), 10).iter().map(|entry| entry.index).collect();
+        assert_eq!(audit, vec![6, 8, 11, 13]);
+    }
+
+    #[test]
+    fn muted_and_departed_senders_cannot_change_their_messages() {
+        let mut state = ChatState::default();
+        state.apply(1, 0, "owner", &ChatCommand::Join("general".into())).unwrap();
+        state.apply(2, 0, "alice", &ChatCommand::Join("general".into())).unwrap();
+        state.apply(3, 0, "alice", &message("general", "hello")).unwrap();
+        state.apply(4, 0, "alice", &message("general", "again")).unwrap();
+
+        let mute = ChatCommand::Moderate {
+            room: "general".into(),
+            nick: "alice".into(),
+            action: ModerationAction::Mute { duration_secs: Some(60) },
+            reason: String::new(),
+        };
+        state.apply(5, 1_000, "owner", &mute).unwrap();
+        let edit = ChatCommand::EditMessage { id: 3, new_content: "rewritten".into() };
+        let Err(ChatError::Unauthorized(error)) = state.apply(6, 2_000, "alice", &edit) else {
+            panic!("a muted sender edited their message");
+        };
+        assert!(error.contains("muted"), "{}", error);
+        state.apply(7, 61_000, "alice", &edit).unwrap();
+
+        state.apply(8, 62_000, "alice", &ChatCommand::Leave("general".into())).unwrap();
+        let delete = ChatCommand::DeleteMessage { id: 4 };
+        assert!(matches!(state.apply(9, 63_000, "alice", &delete), Err(ChatError::Unauthorized(_))));
+        assert!(state.messages.contains_key(&4));
+    }
+
+    #[test]
+    fn bans_follow_the_banned_address_to_other_nicks() {
+        let mut state = ChatState::default();
+        let addr: Option<IpAddr> = Some([10, 0, 0, 9].into());
+        state.take_nick("bob", "secret", addr, 0).unwrap();
+        let join = ChatCommand::Join("general".into());
+        state.apply(1, 0, "owner", &join).unwrap();
+        state.apply(2, 0, "bob", &join).unwrap();
+
+        // Durations too long to add to the time are refused, and saturate if replayed.
+        let ban = ChatCommand::Moderate {
+            room: "general".into(),
+            nick: "bob".into(),
+            action: ModerationAction::Ban { duration_secs: Some(u64::MAX) },
+            reason: String::new(),
+        };
+        assert!(ban.validate().is_err());
+        state.apply(3, 1_000, "owner", &ban).unwrap();
+        assert_eq!(state.room("general").unwrap().bans["bob"].until, Some(u64::MAX));
+
+        assert!(matches!(state.admit("guest5", "", addr, 2_000, &join), Err(ChatError::Banned(_))));
+        state.admit("guest5", "", Some([10, 0, 0, 10].into()), 2_000, &join).unwrap();
+    }
+
+    #[test]
+    fn room_settings_limit_who_joins_and_what_is_kept() {
+        let mut state = ChatState::default();
+        let join = ChatCommand::Join("general".into());
+        let update = |change| ChatCommand::UpdateRoom { room: "general".into(), change };
+        state.apply(1, 0, "owner", &join).unwrap();
+        state.apply(2, 0, "alice", &join).unwrap();
+
+        let topic = update(RoomChange::Topic("raft".into()));
+        assert!(matches!(state.apply(3, 0, "alice", &topic), Err(ChatError::Unauthorized(_))));
+        state.apply(4, 0, "owner", &topic).unwrap();
+        state.apply(5, 0, "owner", &update(RoomChange::InviteOnly(true))).unwrap();
+
+        // Only invited users get in, once each, and never past the member limit.
+        assert!(matches!(state.apply(6, 0, "bob", &join), Err(ChatError::Unauthorized(_))));
+        let invite = |nick: &str| ChatCommand::Moderate {
+            room: "general".into(),
+            nick: nick.into(),
+            action: ModerationAction::Invite,
+            reason: String::new(),
+        };
+        state.apply(7, 0, "owner", &invite("bob")).unwrap();
+        state.apply(8, 0, "bob", &join).unwrap();
+        state.apply(9, 0, "owner", &update(RoomChange::MaxMembers(Some(3)))).unwrap();
+        state.apply(10, 0, "owner", &invite("carol")).unwrap();
+        assert!(matches!(state.apply(11, 0, "carol", &join), Err(ChatError::Unauthorized(_))));
+
+        // Older messages go as new ones arrive, and at once when retention tightens.
+        state.apply(12, 0, "owner", &update(RoomChange::Retention(Retention::Messages(2)))).unwrap();
+        for index in 13..16 {
+            state.apply(index, 0, "alice", &message("general", "hi")).unwrap();
+        }
+        assert_eq!(state.room("general").unwrap().messages, vec![14, 15]);
+        state.apply(16, 0, "owner", &update(RoomChange::Retention(Retention::Messages(1)))).unwrap();
+        assert_eq!(state.room("general").unwrap().messages, vec![15]);
+        assert!(!state.messages.contains_key(&14));
+
+        // Ages too long to subtract from the time are refused, and keep everything if replayed.
+        let keep_all = update(RoomChange::Retention(Retention::MaxAge { secs: u64::MAX }));
+        assert!(keep_all.validate().is_err());
+        state.apply(17, 20_000, "owner", &keep_all).unwrap();
+        assert_eq!(state.room("general").unwrap().messages, vec![15]);
+
+        // Messages past their age stop showing even while nobody writes to the room.
+        let week = update(RoomChange::Retention(Retention::MaxAge { secs: 7 * 86400 }));
+        state.apply(18, 30_000, "owner", &week).unwrap();
+        state.apply(19, 40_000, "alice", &message("general", "raft")).unwrap();
+        let later = 40_001 + 7 * 86400 * 1000;
+        let ChatResponse::History { messages, .. } = state.history("alice", "general", None, None, 10, later).unwrap()
+        else {
+            panic!("expected a history page");
+        };
+        assert!(messages.is_empty());
+        let ChatResponse::SearchResults { messages, .. } =
+            state.search("alice", "raft", None, None, 10, later).unwrap()
+        else {
+            panic!("expected search results");
+        };
+        assert!(messages.is_empty());
+        assert_eq!(state.room("general").unwrap().messages, vec![15, 19]);
+
+        let ChatResponse::RoomInfo(info) = state.room_info("general").unwrap() else {
+            panic!("expected room info");
+        };
+        assert_eq!(info.settings.topic, "raft");
+        assert_eq!(info.members, 3);
+    }
+
+    #[test]
+    fn mentions_are_unread_until_marked() {
+        let mut state = ChatState::default();
+        state.apply(1, 0, "alice", &ChatCommand::Join("general".into())).unwrap();
+        state.apply(2, 0, "bob", &ChatCommand::Join("general".into())).unwrap();
+        state.apply(3, 0, "alice", &message("general", "hi @bob and @nobody")).unwrap();
+        state.apply(4, 0, "alice", &message("general", "@bob @bob again")).unwrap();
+
+        assert_eq!(state.recent_mentions("bob", 10).len(), 2);
+        assert!(state.recent_mentions("nobody", 10).is_empty());
+
+        state.apply(5, 0, "bob", &ChatCommand::MarkMentionsRead { up_to: 3 }).unwrap();
+        let unread: Vec<u64> = state.unread_mentions("bob").iter().map(|m| m.id).collect();
+        assert_eq!(unread, vec![4]);
+    }
+}
@@ -0,0 +1,254 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use shared::{ChatError, ChatEvent};
+
+use crate::raft::{HardState, LogEntry, Snapshot};
+
+const HARD_STATE_FILE: &str = "hard_state.json";
+const LOG_FILE: &str = "log.jsonl";
+const SNAPSHOT_FILE: &str = "snapshot.json";
+
+/// What a node finds on disk when it starts.
+#[derive(Debug, Clone, Default)]
+pub struct Restored {
+    pub hard_state: HardState,
+    pub snapshot: Option<Snapshot>,
+    pub entries: Vec<LogEntry>,
+}
+
+/// Stable storage for the Raft state of one node. Every method returns only once what
+/// it wrote is durable.
+pub trait Storage: Send {
+    fn save_hard_state(&mut self, hard_state: &HardState) -> ChatEvent<()>;
+
+    /// Appends entries. An entry whose index is already stored replaces it together with
+    /// everything after it.
+    fn append(&mut self, entries: &[LogEntry]) -> ChatEvent<()>;
+
+    /// Saves a snapshot and replaces the log with the entries that follow it.
+    fn save_snapshot(&mut self, snapshot: &Snapshot, entries: &[LogEntry]) -> ChatEvent<()>;
+
+    /// Flushes whatever the methods above leave to the operating system, such as file
+    /// metadata and directory entries, before the node exits.
+    fn sync(&mut self) -> ChatEvent<()>;
+}
+
+/// [`Storage`] in a directory on disk.
+///
+/// The log is a file of JSON lines that is only ever appended to. An entry whose index is
+/// already in the file replaces it together with everything after it, which is how a
+/// follower's conflicting suffix is dropped without rewriting the file. The file is
+/// rewritten only when the log is compacted.
+pub struct FileStorage {
+    dir: PathBuf,
+    log: File,
+}
+
+impl FileStorage {
+    pub fn open(dir: impl Into<PathBuf>) -> ChatEvent<(Self, Restored)> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir).map_err(|e| internal("failed to create data directory", e))?;
+
+        let hard_state = read_json(&dir.join(HARD_STATE_FILE))?.unwrap_or_default();
+        let snapshot: Option<Snapshot> = read_json(&dir.join(SNAPSHOT_FILE))?;
+        let (entries, good_len) = read_log(&dir.join(LOG_FILE))?;
+
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(LOG_FILE))
+            .map_err(|e| internal("failed to open log", e))?;
+        // Appending after a torn line would run the next entry into it.
+        if log.metadata().map_err(|e| internal("failed to read log size", e))?.len() > good_len {
+            log.set_len(good_len)
+                .and_then(|_| log.sync_all())
+                .map_err(|e| internal("failed to truncate torn log entry", e))?;
+        }
+
+        Ok((Self { dir, log }, Restored { hard_state, snapshot, entries }))
+    }
+}
+
+impl Storage for FileStorage {
+    fn save_hard_state(&mut self, hard_state: &HardState) -> ChatEvent<()> {
+        write_json(&self.dir.join(HARD_STATE_FILE), hard_state)
+    }
+
+    fn append(&mut self, entries: &[LogEntry]) -> ChatEvent<()> {
+        let mut data = Vec::new();
+        for entry in entries {
+            serde_json::to_writer(&mut data, entry).map_err(|e| internal("failed to serialize entry", e))?;
+            data.push(b'\n');
+        }
+
+        self.log
+            .write_all(&data)
+            .and_then(|_| self.log.sync_data())
+            .map_err(|e| internal("failed to append to log", e))
+    }
+
+    fn save_snapshot(&mut self, snapshot: &Snapshot, entries: &[LogEntry]) -> ChatEvent<()> {
+        write_json(&self.dir.join(SNAPSHOT_FILE), snapshot)?;
+
+        let path = self.dir.join(LOG_FILE);
+        let tmp = path.with_extension("tmp");
+        File::create(&tmp)
+            .and_then(|mut file| {
+                for entry in entries {
+                    serde_json::to_writer(&mut file, entry)?;
+                    file.write_all(b"\n")?;
+                }
+                file.sync_all()
+            })
+            .and_then(|_| fs::rename(&tmp, &path))
+            .and_then(|_| sync_dir(&self.dir))
+            .map_err(|e| internal("failed to rewrite log", e))?;
+
+        self.log = OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .map_err(|e| internal("failed to open log", e))?;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> ChatEvent<()> {
+        self.log.sync_all().map_err(|e| internal("failed to sync log", e))?;
+        sync_dir(&self.dir).map_err(|e| internal("failed to sync data directory", e))
+    }
+}
+
+fn read_json<T: DeserializeOwned>(path: &Path) -> ChatEvent<Option<T>> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(internal(&format!("failed to read {}", path.display()), e)),
+    };
+
+    serde_json::from_slice(&data)
+        .map(Some)
+        .map_err(|e| internal(&format!("failed to parse {}", path.display()), e))
+}
+
+/// Makes the renames and creations of the files in `dir` durable.
+fn sync_dir(dir: &Path) -> std::io::Result<()> {
+    File::open(dir).and_then(|dir| dir.sync_all())
+}
+
+/// Writes next to `path` first so a crash never leaves a torn file behind.
+fn write_json<T: Serialize>(path: &Path, value: &T) -> ChatEvent<()> {
+    let data = serde_json::to_vec(value).map_err(|e| internal("failed to serialize", e))?;
+
+    let tmp = path.with_extension("tmp");
+    File::create(&tmp)
+        .and_then(|mut file| file.write_all(&data).and_then(|_| file.sync_all()))
+        .and_then(|_| fs::rename(&tmp, path))
+        .and_then(|_| sync_dir(path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."))))
+        .map_err(|e| internal(&format!("failed to write {}", path.display()), e))
+}
+
+/// Reads the log's entries, along with the length of the file up to the end of the last
+/// whole entry. Fails if an entry before the last one cannot be read.
+fn read_log(path: &Path) -> ChatEvent<(Vec<LogEntry>, u64)> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((Vec::new(), 0)),
+        Err(e) => return Err(internal("failed to open log", e)),
+    };
+
+    let mut reader = BufReader::new(file);
+    let mut entries: Vec<LogEntry> = Vec::new();
+    let mut good_len = 0;
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let read = reader.read_until(b'\n', &mut line).map_err(|e| internal("failed to read log", e))?;
+        if read == 0 {
+            break;
+        }
+        // Only the last line can be torn, by a crash in the middle of an append, and a
+        // torn line has no newline. Any other line that cannot be read may hold a
+        // committed entry, which must not be dropped along with everything after it.
+        if !line.ends_with(b"\n") {
+            tracing::warn!("Cutting off a torn log entry at offset {}", good_len);
+            break;
+        }
+        let entry: LogEntry = serde_json::from_slice(&line)
+            .map_err(|e| internal(&format!("unreadable log entry at offset {}", good_len), e))?;
+        good_len += read as u64;
+
+        while entries.last().is_some_and(|last| last.index >= entry.index) {
+            entries.pop();
+        }
+        entries.push(entry);
+    }
+
+    Ok((entries, good_len))
+}
+
+fn internal(context: &str, e: impl std::fmt::Display) -> ChatError {
+    ChatError::Internal(format!("{}: {}", context, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft::Payload;
+
+    fn entry(index: u64) -> LogEntry {
+        LogEntry { index, term: 1, payload: Payload::Noop }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("raft-chat-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn torn_entries_are_cut_off_before_appending() {
+        let dir = temp_dir("torn");
+        let (mut storage, _) = FileStorage::open(&dir).unwrap();
+        storage.append(&[entry(1), entry(2)]).unwrap();
+        drop(storage);
+
+        // A crash halfway through appending the third entry.
+        let mut log = OpenOptions::new().append(true).open(dir.join(LOG_FILE)).unwrap();
+        log.write_all(br#"{"index":3,"te"#).unwrap();
+        drop(log);
+
+        let (mut storage, restored) = FileStorage::open(&dir).unwrap();
+        assert_eq!(restored.entries.len(), 2);
+        storage.append(&[entry(3), entry(4)]).unwrap();
+        drop(storage);
+
+        let (_, restored) = FileStorage::open(&dir).unwrap();
+        let indexes: Vec<u64> = restored.entries.iter().map(|e| e.index).collect();
+        assert_eq!(indexes, vec![1, 2, 3, 4]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupt_entries_before_the_last_fail_to_open() {
+        let dir = temp_dir("corrupt");
+        let (mut storage, _) = FileStorage::open(&dir).unwrap();
+        storage.append(&[entry(1), entry(2), entry(3)]).unwrap();
+        drop(storage);
+
+        let path = dir.join(LOG_FILE);
+        let log = fs::read_to_string(&path).unwrap();
+        let offset = log.find('\n').unwrap() + 1;
+        let corrupted = log.replacen(r#"{"index":2"#, r#"{"index":#"#, 1);
+        fs::write(&path, &corrupted).unwrap();
+
+        let Err(ChatError::Internal(error)) = FileStorage::open(&dir) else {
+            panic!("a log with a corrupt entry was opened");
+        };
+        assert!(error.contains(&format!("offset {}", offset)), "{}", error);
+        assert_eq!(fs::read_to_string(&path).unwrap(), corrupted);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,15 +1,24 @@
 use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio::net::TcpStream;
+use tokio::time::Instant;
 use std::time::{SystemTime, UNIX_EPOCH};
 use serde_json;
 
 use crate::{ChatCommand, ChatResponse, Message, ChatError, ChatEvent};
+use crate::fault::{Fault, FaultInjector, FaultPolicy};
 
 #[derive(Debug)]
 pub struct ChatClientChannel {
     writer: OwnedWriteHalf,
     reader: BufReader<OwnedReadHalf>,
+    // Partially read line; kept across calls so a receive cancelled by `select!` loses nothing.
+    read_buf: Vec<u8>,
+    faults: Option<FaultInjector>,
+    // Received frame held back by an injected delay, and when to hand it over.
+    delayed: Option<(Instant, Vec<u8>)>,
+    // Set once an injected fault has cut the connection.
+    severed: bool,
 }
 
 impl ChatClientChannel {
@@ -23,14 +32,51 @@ impl ChatClientChannel {
     
     pub fn from_stream(socket: TcpStream) -> ChatEvent<Self> {
         let (reader, writer) = socket.into_split();
-        Ok(Self { writer, reader: BufReader::new(reader) })
+        Ok(Self {
+            writer,
+            reader: BufReader::new(reader),
+            read_buf: Vec::new(),
+            faults: None,
+            delayed: None,
+            severed: false,
+        })
+    }
+
+    /// Injects faults into the frames sent and received from now on, for chaos testing.
+    pub fn with_faults(mut self, policy: FaultPolicy) -> Self {
+        self.faults = Some(FaultInjector::new(policy));
+        self
+    }
+
+    async fn sever(&mut self) -> ChatError {
+        self.severed = true;
+        let _ = self.writer.shutdown().await;
+        ChatError::Network("connection severed by fault injection".to_string())
     }
 
     pub async fn send_bytes(&mut self, data: &mut Vec<u8>) -> ChatEvent<()> {
-        if !data.ends_with(&[b'\n']) {  
+        if !data.ends_with(b"\n") {  
             data.push(b'\n');
         }
 
+        if self.severed {
+            return Err(ChatError::Network("connection severed by fault injection".to_string()));
+        }
+        if let Some(faults) = self.faults.as_mut() {
+            let frame = faults.next_frame(true);
+            tokio::time::sleep(frame.delay).await;
+            match frame.fault {
+                None => {}
+                Some(Fault::Drop) => return Ok(()),
+                Some(Fault::Corrupt) => faults.corrupt(data),
+                Some(Fault::Truncate) => {
+                    let _ = self.writer.write_all(&data[..data.len() / 2]).await;
+                    return Err(self.sever().await);
+                }
+                Some(Fault::Sever) => return Err(self.sever().await),
+            }
+        }
+
         self.writer
             .write_all(data)
             .await
@@ -39,14 +85,26 @@ impl ChatClientChannel {
         Ok(())
     }
 
-    pub async fn send_message(&mut self, msg_body: &str) -> ChatEvent<()> {
+    pub async fn send_message(
+        &mut self,
+        client_msg_id: u64,
+        room: &str,
+        reply_to: Option<u64>,
+        msg_body: &str,
+    ) -> ChatEvent<()> {
         let msg = ChatCommand::SendMessage(Message {
+            id: 0,
+            client_msg_id,
             sender: "client".to_string(),
+            room: room.to_string(),
+            reply_to,
             content: msg_body.to_string(),
             timestamp: SystemTime::now()
                 .duration_since(UNIX_EPOCH)
                 .unwrap()
-                .as_millis() as u64
+                .as_millis() as u64,
+            edited: false,
+            reactions: Default::default(),
         });
 
         let mut msg_bytes = serde_json::to_vec(&msg)
@@ -62,19 +120,46 @@ impl ChatClientChannel {
         self.send_bytes(&mut cmd_bytes).await
     }
 
+    pub async fn send_response(&mut self, response: &ChatResponse) -> ChatEvent<()> {
+        let mut response_bytes = serde_json::to_vec(response)
+            .map_err(|e| ChatError::Protocol(format!("failed to serialize response: {}", e)))?;
+
+        self.send_bytes(&mut response_bytes).await
+    }
+
     async fn receive_message<T>(&mut self) -> ChatEvent<T> 
     where
         T: serde::de::DeserializeOwned,
     {
-        let mut buffer = Vec::new();
+        loop {
+            if self.severed {
+                return Err(ChatError::Network("connection severed by fault injection".to_string()));
+            }
+            if let Some((until, _)) = &self.delayed {
+                tokio::time::sleep_until(*until).await;
+            }
+            if let Some((_, frame)) = self.delayed.take() {
+                return parse_frame(&frame);
+            }
 
-        match self.reader.read_until(b'\n', &mut buffer).await {
-            Ok(n) if n > 0 => {
-                serde_json::from_slice(&buffer)
-                    .map_err(|e| ChatError::Protocol(format!("failed to parse message: {}", e)))
+            let mut frame = match self.reader.read_until(b'\n', &mut self.read_buf).await {
+                Ok(n) if n > 0 => std::mem::take(&mut self.read_buf),
+                Ok(_) => return Err(ChatError::Network("connection closed".to_string())),
+                Err(e) => return Err(ChatError::Network(format!("failed to read from connection: {}", e))),
+            };
+
+            let Some(faults) = self.faults.as_mut() else {
+                return parse_frame(&frame);
+            };
+            let fault = faults.next_frame(false);
+            match fault.fault {
+                None => {}
+                Some(Fault::Drop) => continue,
+                Some(Fault::Corrupt) => faults.corrupt(&mut frame),
+                Some(Fault::Truncate | Fault::Sever) => return Err(self.sever().await),
             }
-            Ok(_) => Err(ChatError::Network("connection closed".to_string())),
-            Err(e) => Err(ChatError::Network(format!("failed to read from connection: {}", e))),
+            // Held in the channel rather than slept on here, so a cancelled receive keeps it.
+            self.delayed = Some((Instant::now() + fault.delay, frame));
         }
     }
 
@@ -85,4 +170,8 @@ impl ChatClientChannel {
     pub async fn receive_command(&mut self) -> ChatEvent<ChatCommand> {
         self.receive_message().await
     }
-} 
\ No newline at end of file
+}
+
+fn parse_frame<T: serde::de::DeserializeOwned>(frame: &[u8]) -> ChatEvent<T> {
+    serde_json::from_slice(frame).map_err(|e| ChatError::Protocol(format!("failed to parse message: {}", e)))
+}
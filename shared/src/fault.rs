@@ -0,0 +1,266 @@
+//! Fault injection for [`ChatClientChannel`](crate::channel::ChatClientChannel), for chaos
+//! experiments against a local cluster.
+//!
+//! A [`FaultPolicy`] decides what happens to each frame the channel sends or receives. It
+//! is written as a spec so it can be passed on the command line:
+//!
+//! - `random:drop=0.05,corrupt=0.01,truncate=0.01,sever=0.001,latency=10-200,seed=7`
+//!   picks faults at random. Probabilities default to zero and latency, in milliseconds,
+//!   to none; the seed defaults to the current time.
+//! - `script:3=drop,5=delay:500,8=corrupt,12=sever` applies faults to given frames,
+//!   counted from 1 across both directions.
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::ChatError;
+
+static CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Something that goes wrong with one frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// The frame is silently lost.
+    Drop,
+    /// A byte of the frame is flipped.
+    Corrupt,
+    /// Only the start of the frame is written before the connection is cut. Received
+    /// frames cannot be cut short, so for them this is the same as `Sever`.
+    Truncate,
+    /// The connection is cut instead of passing the frame on.
+    Sever,
+}
+
+/// What to do to a frame: hold it back for `delay`, then apply `fault` if there is one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FrameFault {
+    pub delay: Duration,
+    pub fault: Option<Fault>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FaultPolicy {
+    Random {
+        seed: u64,
+        /// Range every frame's delay is drawn from.
+        latency: (Duration, Duration),
+        drop: f64,
+        corrupt: f64,
+        truncate: f64,
+        sever: f64,
+    },
+    /// Faults for given frame numbers.
+    Script(Vec<(u64, FrameFault)>),
+}
+
+impl FromStr for FaultPolicy {
+    type Err = ChatError;
+
+    fn from_str(spec: &str) -> Result<Self, ChatError> {
+        let invalid = |reason: String| ChatError::Protocol(format!("invalid fault spec {:?}: {}", spec, reason));
+        let (kind, settings) = spec.split_once(':').unwrap_or((spec, ""));
+        let settings = settings.split(',').filter(|s| !s.is_empty()).map(|setting| {
+            setting.split_once('=').ok_or_else(|| invalid(format!("{} should look like KEY=VALUE", setting)))
+        });
+
+        match kind {
+            "random" => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+                let (mut seed, mut latency) = (now.as_nanos() as u64, (Duration::ZERO, Duration::ZERO));
+                let (mut drop, mut corrupt, mut truncate, mut sever) = (0.0, 0.0, 0.0, 0.0);
+                for setting in settings {
+                    let (key, value) = setting?;
+                    let probability = || match value.parse::<f64>() {
+                        Ok(p) if (0.0..=1.0).contains(&p) => Ok(p),
+                        _ => Err(invalid(format!("{} must be a probability between 0 and 1", key))),
+                    };
+                    match key {
+                        "seed" => seed = value.parse().map_err(|_| invalid("seed must be a number".into()))?,
+                        "latency" => {
+                            latency = parse_latency(value)
+                                .ok_or_else(|| invalid("latency must look like MS or MIN-MAX".into()))?;
+                        }
+                        "drop" => drop = probability()?,
+                        "corrupt" => corrupt = probability()?,
+                        "truncate" => truncate = probability()?,
+                        "sever" => sever = probability()?,
+                        _ => return Err(invalid(format!("unknown setting {}", key))),
+                    }
+                }
+                Ok(FaultPolicy::Random { seed, latency, drop, corrupt, truncate, sever })
+            }
+            "script" => {
+                let mut script = Vec::new();
+                for setting in settings {
+                    let (frame, action) = setting?;
+                    let frame = frame.parse().map_err(|_| invalid(format!("{} is not a frame number", frame)))?;
+                    let fault = match action.split_once(':') {
+                        Some(("delay", ms)) => {
+                            let ms = ms
+                                .parse()
+                                .map_err(|_| invalid(format!("{} is not a delay in milliseconds", ms)))?;
+                            FrameFault { delay: Duration::from_millis(ms), fault: None }
+                        }
+                        _ => {
+                            let fault = match action {
+                                "drop" => Fault::Drop,
+                                "corrupt" => Fault::Corrupt,
+                                "truncate" => Fault::Truncate,
+                                "sever" => Fault::Sever,
+                                _ => return Err(invalid(format!("unknown fault {}", action))),
+                            };
+                            FrameFault { delay: Duration::ZERO, fault: Some(fault) }
+                        }
+                    };
+                    script.push((frame, fault));
+                }
+                Ok(FaultPolicy::Script(script))
+            }
+            _ => Err(invalid("expected random:... or script:...".into())),
+        }
+    }
+}
+
+fn parse_latency(value: &str) -> Option<(Duration, Duration)> {
+    let (min, max) = value.split_once('-').unwrap_or((value, value));
+    let (min, max) = (min.parse().ok()?, max.parse().ok()?);
+    (min <= max).then(|| (Duration::from_millis(min), Duration::from_millis(max)))
+}
+
+/// Applies a [`FaultPolicy`] to the frames of one connection.
+pub struct FaultInjector {
+    policy: FaultPolicy,
+    rng: u64,
+    frames: u64,
+}
+
+impl fmt::Debug for FaultInjector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FaultInjector").field("policy", &self.policy).field("frames", &self.frames).finish()
+    }
+}
+
+impl FaultInjector {
+    pub fn new(policy: FaultPolicy) -> Self {
+        // Connections of a process get different faults from the same seed, but the same
+        // ones in every run that opens them in the same order.
+        let connection = CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+        let rng = match policy {
+            FaultPolicy::Random { seed, .. } => seed ^ connection.wrapping_mul(0x9e37_79b9_7f4a_7c15),
+            FaultPolicy::Script(_) => 0,
+        };
+        Self { policy, rng, frames: 0 }
+    }
+
+    /// Decides the fate of the next frame. `outgoing` frames can also be truncated.
+    pub fn next_frame(&mut self, outgoing: bool) -> FrameFault {
+        self.frames += 1;
+        match &self.policy {
+            FaultPolicy::Script(script) => {
+                let planned = script.iter().find(|(frame, _)| *frame == self.frames);
+                planned.map(|(_, fault)| *fault).unwrap_or_default()
+            }
+            FaultPolicy::Random { latency: (min, max), drop, corrupt, truncate, sever, .. } => {
+                let (min, max, drop, corrupt, truncate, sever) = (*min, *max, *drop, *corrupt, *truncate, *sever);
+                let delay = min + (max - min).mul_f64(self.unit());
+                let roll = self.unit();
+                let truncate = if outgoing { truncate } else { 0.0 };
+                let odds = [
+                    (drop, Fault::Drop),
+                    (corrupt, Fault::Corrupt),
+                    (truncate, Fault::Truncate),
+                    (sever, Fault::Sever),
+                ];
+                let fault = odds
+                    .into_iter()
+                    .scan(0.0, |total, (p, fault)| {
+                        *total += p;
+                        Some((*total, fault))
+                    })
+                    .find(|(total, _)| roll < *total)
+                    .map(|(_, fault)| fault);
+                FrameFault { delay, fault }
+            }
+        }
+    }
+
+    /// Flips one bit of the frame, leaving its line ending alone.
+    pub fn corrupt(&mut self, frame: &mut [u8]) {
+        let len = frame.iter().rposition(|b| *b != b'\n').map_or(0, |last| last + 1);
+        if len == 0 {
+            return;
+        }
+        let at = (self.next_u64() % len as u64) as usize;
+        let bit = self.next_u64() % 7;
+        // Never create a line break, which would split the frame in two.
+        frame[at] ^= if frame[at] ^ (1 << bit) == b'\n' { 1 << 7 } else { 1 << bit };
+    }
+
+    // SplitMix64, so runs with the same seed inject the same faults.
+    fn next_u64(&mut self) -> u64 {
+        self.rng = self.rng.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn unit(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::ChatClientChannel;
+    use crate::ChatCommand;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn specs_parse() {
+        let policy: FaultPolicy = "random:drop=0.1,latency=5-20,seed=3".parse().unwrap();
+        let expected = FaultPolicy::Random {
+            seed: 3,
+            latency: (Duration::from_millis(5), Duration::from_millis(20)),
+            drop: 0.1,
+            corrupt: 0.0,
+            truncate: 0.0,
+            sever: 0.0,
+        };
+        assert_eq!(policy, expected);
+
+        let policy: FaultPolicy = "script:2=drop,3=delay:50".parse().unwrap();
+        let delayed = FrameFault { delay: Duration::from_millis(50), fault: None };
+        let dropped = FrameFault { delay: Duration::ZERO, fault: Some(Fault::Drop) };
+        assert_eq!(policy, FaultPolicy::Script(vec![(2, dropped), (3, delayed)]));
+
+        assert!("random:drop=2".parse::<FaultPolicy>().is_err());
+        assert!("script:1=explode".parse::<FaultPolicy>().is_err());
+        assert!("sometimes".parse::<FaultPolicy>().is_err());
+    }
+
+    #[tokio::test]
+    async fn scripted_faults_hit_the_given_frames() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let policy: FaultPolicy = "script:2=drop,4=sever".parse().unwrap();
+        let sender = tokio::spawn(async move {
+            let mut channel = ChatClientChannel::connect(&addr).await.unwrap().with_faults(policy);
+            for room in ["one", "two", "three", "four"] {
+                let _ = channel.send_command(ChatCommand::Join(room.to_string())).await;
+            }
+        });
+
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut receiver = ChatClientChannel::from_stream(socket).unwrap();
+        let mut received = Vec::new();
+        while let Ok(ChatCommand::Join(room)) = receiver.receive_command().await {
+            received.push(room);
+        }
+        sender.await.unwrap();
+        assert_eq!(received, ["one", "three"]);
+    }
+}
@@ -1,7 +1,9 @@
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
 use thiserror::Error;
 
 pub mod channel;
+pub mod fault;
 
 #[derive(Debug, Error)]
 pub enum ChatError {
@@ -9,15 +11,286 @@ pub enum ChatError {
     Network(String),
     #[error("Protocol error: {0}")]
     Protocol(String),
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+    #[error("Not found: {0}")]
+    NotFound(String),
+    #[error("No such room: {0}")]
+    NoSuchRoom(String),
+    #[error("Banned: {0}")]
+    Banned(String),
     #[error("Internal error: {0}")]
     Internal(String),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message {
+    /// Log index assigned when the message is committed; zero until then.
+    #[serde(default)]
+    pub id: u64,
+    /// Identifier chosen by the sending client so it can match the server's `Ack`.
+    #[serde(default)]
+    pub client_msg_id: u64,
     pub sender: String,
+    #[serde(default)]
+    pub room: String,
+    /// Root message of the thread this message replies to.
+    #[serde(default)]
+    pub reply_to: Option<u64>,
     pub content: String,
     pub timestamp: u64,
+    #[serde(default)]
+    pub edited: bool,
+    /// Users that reacted to the message, keyed by emoji.
+    #[serde(default)]
+    pub reactions: BTreeMap<String, BTreeSet<String>>,
+}
+
+/// Operations on the cluster itself, for operators rather than chat users.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum AdminCommand {
+    /// Moves leadership to node `to`, for example before restarting the current leader.
+    TransferLeadership { to: u64 },
+    /// Adds node `id` as a learner that receives the log without voting. `addr` is
+    /// the address it listens on for peers.
+    AddLearner { id: u64, addr: String },
+    /// Makes a learner that has caught up a voter.
+    Promote { id: u64 },
+    /// Takes node `id` out of the cluster. The leader cannot remove itself.
+    RemoveNode { id: u64 },
+    /// Reports the Raft state of the node the command is sent to.
+    Status,
+    /// Compacts the node's log into a snapshot now rather than at the usual threshold.
+    Snapshot,
+    /// Disconnects every session of `nick` on every node, and keeps the nick and the
+    /// address it was last taken from out for `duration_secs`, or ten minutes without one.
+    KickUser {
+        nick: String,
+        #[serde(default)]
+        reason: String,
+        #[serde(default)]
+        duration_secs: Option<u64>,
+    },
+    /// Returns the newest `limit` moderation actions, oldest first, optionally only those
+    /// taken in `room`.
+    AuditLog { room: Option<String>, limit: usize },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum AdminResponse {
+    LeadershipTransferred { to: u64, term: u64 },
+    MembershipChanged { voters: Vec<u64>, learners: Vec<u64> },
+    Status(ClusterStatus),
+    /// The log up to `index` is now held in a snapshot.
+    SnapshotTaken { index: u64 },
+    UserKicked { nick: String },
+    AuditLog(Vec<AuditEntry>),
+}
+
+/// A user's standing in a room. The owner is whoever created it; owners appoint
+/// operators, and both may moderate the room's members.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum RoomRole {
+    Member,
+    Operator,
+    Owner,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ModerationAction {
+    /// Removes the user from the room; they may join again.
+    Kick,
+    /// Removes the user from the room and keeps them out, for `duration_secs` or for good.
+    Ban { duration_secs: Option<u64> },
+    Unban,
+    /// Keeps the user from sending messages to the room, for `duration_secs` or for good.
+    Mute { duration_secs: Option<u64> },
+    Unmute,
+    /// Appoints or dismisses an operator, or with `Owner` hands the room over.
+    SetRole(RoomRole),
+    /// Lets the user join the room once while it is invite-only.
+    Invite,
+}
+
+/// A moderation action as it was applied, kept in the audit log and announced to the room.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// Log index the action was applied at.
+    pub index: u64,
+    /// Milliseconds since the Unix epoch.
+    pub time: u64,
+    pub room: String,
+    /// Who took the action.
+    pub by: String,
+    pub nick: String,
+    pub action: ModerationAction,
+    pub reason: String,
+}
+
+impl std::fmt::Display for AuditEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (by, nick, room) = (&self.by, &self.nick, &self.room);
+        let until = |duration_secs: &Option<u64>| match duration_secs {
+            Some(secs) => format!(" for {}", format_duration(std::time::Duration::from_secs(*secs))),
+            None => String::new(),
+        };
+        match &self.action {
+            ModerationAction::Kick => write!(f, "{} kicked {} from {}", by, nick, room)?,
+            ModerationAction::Ban { duration_secs } => {
+                write!(f, "{} banned {} from {}{}", by, nick, room, until(duration_secs))?
+            }
+            ModerationAction::Unban => write!(f, "{} unbanned {} from {}", by, nick, room)?,
+            ModerationAction::Mute { duration_secs } => {
+                write!(f, "{} muted {} in {}{}", by, nick, room, until(duration_secs))?
+            }
+            ModerationAction::Unmute => write!(f, "{} unmuted {} in {}", by, nick, room)?,
+            ModerationAction::SetRole(RoomRole::Owner) => write!(f, "{} handed {} over to {}", by, room, nick)?,
+            ModerationAction::SetRole(RoomRole::Operator) => write!(f, "{} made {} an operator of {}", by, nick, room)?,
+            ModerationAction::SetRole(RoomRole::Member) => {
+                write!(f, "{} made {} a plain member of {}", by, nick, room)?
+            }
+            ModerationAction::Invite => write!(f, "{} invited {} to {}", by, nick, room)?,
+        }
+        if !self.reason.is_empty() {
+            write!(f, ": {}", self.reason)?;
+        }
+        Ok(())
+    }
+}
+
+/// Longest a ban, mute or kick may last, and the longest a room may keep messages for:
+/// about ten years. Longer ones are refused rather than left to overflow the time.
+pub const MAX_DURATION_SECS: u64 = 10 * 365 * 86400;
+
+/// Parses durations such as `30s`, `10m`, `2h` or `7d` into seconds.
+pub fn parse_duration(word: &str) -> Option<u64> {
+    let unit = match word.chars().last()? {
+        's' => 1,
+        'm' => 60,
+        'h' => 3600,
+        'd' => 86400,
+        _ => return None,
+    };
+    let count: u64 = word[..word.len() - 1].parse().ok()?;
+    count.checked_mul(unit)
+}
+
+/// Formats a duration in its largest whole unit, rounded up so that a ban or mute never
+/// looks shorter than it is: `45s`, `10m`, `2h`, `3d`.
+pub fn format_duration(duration: std::time::Duration) -> String {
+    let secs = duration.as_millis().div_ceil(1000) as u64;
+    match secs {
+        0..60 => format!("{}s", secs),
+        60..3600 => format!("{}m", secs.div_ceil(60)),
+        3600..86400 => format!("{}h", secs.div_ceil(3600)),
+        _ => format!("{}d", secs.div_ceil(86400)),
+    }
+}
+
+/// How long a room keeps its messages. Older messages are dropped as new ones are posted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Retention {
+    #[default]
+    Forever,
+    /// Keeps the newest this many messages.
+    Messages(usize),
+    /// Keeps messages posted in the last `secs` seconds.
+    MaxAge { secs: u64 },
+}
+
+impl std::fmt::Display for Retention {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Retention::Forever => write!(f, "forever"),
+            Retention::Messages(count) => write!(f, "last {} messages", count),
+            Retention::MaxAge { secs } => write!(f, "{}", format_duration(std::time::Duration::from_secs(*secs))),
+        }
+    }
+}
+
+/// A room's settings, which its owner and operators may change.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoomSettings {
+    /// One line shown above the room's messages.
+    pub topic: String,
+    pub description: String,
+    /// Whether only invited users may join.
+    pub invite_only: bool,
+    /// The most members the room may have; owners and operators may join regardless.
+    pub max_members: Option<usize>,
+    pub retention: Retention,
+}
+
+/// A change to one of a room's settings.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RoomChange {
+    Topic(String),
+    Description(String),
+    InviteOnly(bool),
+    MaxMembers(Option<usize>),
+    Retention(Retention),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoomInfo {
+    pub room: String,
+    pub owner: String,
+    pub operators: Vec<String>,
+    pub members: usize,
+    pub settings: RoomSettings,
+}
+
+/// One node's view of the cluster.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClusterStatus {
+    pub id: u64,
+    /// `Leader`, `Follower`, `PreCandidate` or `Candidate`.
+    pub role: String,
+    pub term: u64,
+    pub leader: Option<u64>,
+    pub commit_index: u64,
+    /// Highest commit index heard from the leader; the node has caught up once it has
+    /// applied this far.
+    #[serde(default)]
+    pub leader_commit: u64,
+    pub applied_index: u64,
+    pub last_index: u64,
+    /// Last index covered by the node's snapshot.
+    pub snapshot_index: u64,
+    pub voters: Vec<u64>,
+    pub learners: Vec<u64>,
+    /// Peer addresses the node sends to.
+    pub peers: BTreeMap<u64, String>,
+    /// How far each follower has replicated the log. Only the leader knows this, so it
+    /// is empty on other nodes.
+    pub followers: Vec<FollowerStatus>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FollowerStatus {
+    pub id: u64,
+    pub match_index: u64,
+    pub next_index: u64,
+    /// Entries of the leader's log the follower does not have yet.
+    pub lag: u64,
+    /// `Probe` while the leader looks for where the logs match, then `Replicate`.
+    pub state: String,
+    /// Whether the follower has answered since the last quorum check.
+    pub active: bool,
+}
+
+/// How up to date the state answering a query must be.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReadConsistency {
+    /// Reflects every write acknowledged before the query was sent. The leader confirms
+    /// with a quorum that it is still the leader before answering.
+    #[default]
+    Linearizable,
+    /// Like `Linearizable`, but the leader skips the round trip while its lease holds.
+    /// Only as safe as the bound on clock drift between nodes.
+    Lease,
+    /// Answered from the local state of whichever node the client is connected to.
+    Stale,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -25,6 +298,114 @@ pub enum ChatCommand {
     SendMessage(Message),
     Join(String),
     Leave(String),
+    /// Takes `nick` as the session's name. The first session to take a nick registers
+    /// its secret, and later sessions must send the same one.
+    Nick { nick: String, secret: String },
+    EditMessage { id: u64, new_content: String },
+    DeleteMessage { id: u64 },
+    React { message_id: u64, emoji: String },
+    Unreact { message_id: u64, emoji: String },
+    /// Requests up to `limit` messages of `room` older than `before` (or the newest ones).
+    /// With `thread` set, only the thread rooted at that message is returned. The answer
+    /// waits until the serving node has applied the log up to `min_index`.
+    FetchHistory {
+        room: String,
+        before: Option<u64>,
+        limit: usize,
+        #[serde(default)]
+        thread: Option<u64>,
+        #[serde(default)]
+        consistency: ReadConsistency,
+        #[serde(default)]
+        min_index: Option<u64>,
+    },
+    /// Requests the most recent messages that mention the user, across all rooms.
+    FetchMentions {
+        limit: usize,
+        #[serde(default)]
+        consistency: ReadConsistency,
+    },
+    /// Marks mentions up to and including message `up_to` as seen.
+    MarkMentionsRead { up_to: u64 },
+    /// Full-text search over the rooms the user is a member of, optionally narrowed to
+    /// one room and one sender. Like history, it can wait for `min_index` to be applied.
+    Search {
+        query: String,
+        room: Option<String>,
+        from: Option<String>,
+        limit: usize,
+        #[serde(default)]
+        consistency: ReadConsistency,
+        #[serde(default)]
+        min_index: Option<u64>,
+    },
+    Admin(AdminCommand),
+    /// Takes a moderation action against `nick` in `room`, which only the room's owner
+    /// and operators may do.
+    Moderate {
+        room: String,
+        nick: String,
+        action: ModerationAction,
+        #[serde(default)]
+        reason: String,
+    },
+    /// Lists the members of a room.
+    Who {
+        room: String,
+        #[serde(default)]
+        consistency: ReadConsistency,
+    },
+    /// Changes a setting of `room`, which only its owner and operators may do.
+    UpdateRoom { room: String, change: RoomChange },
+    /// Asks for a room's topic, settings and who runs it.
+    RoomInfo {
+        room: String,
+        #[serde(default)]
+        consistency: ReadConsistency,
+    },
+}
+
+impl ChatCommand {
+    /// The consistency requested by a read-only command, or `None` for commands that
+    /// change state and go through the log.
+    pub fn read_consistency(&self) -> Option<ReadConsistency> {
+        match self {
+            ChatCommand::FetchHistory { consistency, .. }
+            | ChatCommand::FetchMentions { consistency, .. }
+            | ChatCommand::Search { consistency, .. }
+            | ChatCommand::Who { consistency, .. }
+            | ChatCommand::RoomInfo { consistency, .. } => Some(*consistency),
+            _ => None,
+        }
+    }
+
+    /// Checks what the server cannot take on trust before the command goes into the log.
+    pub fn validate(&self) -> Result<(), ChatError> {
+        let duration_secs = match self {
+            ChatCommand::Moderate { action: ModerationAction::Ban { duration_secs }, .. }
+            | ChatCommand::Moderate { action: ModerationAction::Mute { duration_secs }, .. }
+            | ChatCommand::Admin(AdminCommand::KickUser { duration_secs, .. }) => *duration_secs,
+            ChatCommand::UpdateRoom { change: RoomChange::Retention(Retention::MaxAge { secs }), .. } => Some(*secs),
+            _ => None,
+        };
+        match duration_secs {
+            Some(secs) if secs > MAX_DURATION_SECS => Err(ChatError::Protocol(format!(
+                "durations may be at most {} days",
+                MAX_DURATION_SECS / 86400
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// The log index a read-only command must not be answered before.
+    pub fn min_index(&self) -> u64 {
+        match self {
+            ChatCommand::FetchHistory { min_index, .. } | ChatCommand::Search { min_index, .. } => {
+                min_index.unwrap_or_default()
+            }
+            _ => 0,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -32,11 +413,148 @@ pub enum ChatResponse {
     MessageReceived(Message),
     Joined(String),
     Left(String),
-    Error(String),
+    /// The session's `Nick` command was accepted; commands now run as `nick`.
+    NickChanged(String),
+    MessageEdited { id: u64, room: String, new_content: String },
+    MessageDeleted { id: u64, room: String },
+    ReactionsUpdated { id: u64, room: String, reactions: BTreeMap<String, BTreeSet<String>> },
+    /// A page of history, oldest message first. `applied_index` is the log index the
+    /// answering node had applied, which later reads can pass as their `min_index`.
+    History {
+        room: String,
+        thread: Option<u64>,
+        messages: Vec<Message>,
+        has_more: bool,
+        #[serde(default)]
+        applied_index: u64,
+    },
+    /// Messages mentioning the user, oldest first. `unread` is set when they are the
+    /// mentions delivered on reconnect that the user has not seen yet.
+    Mentions { messages: Vec<Message>, unread: bool },
+    /// Messages matching a search, newest first.
+    SearchResults {
+        query: String,
+        messages: Vec<Message>,
+        #[serde(default)]
+        applied_index: u64,
+    },
+    Members { room: String, members: Vec<String> },
+    /// A room's settings, sent on request, to a user joining it, and to its members
+    /// whenever they change.
+    RoomInfo(RoomInfo),
+    Admin(AdminResponse),
+    /// A moderation action taken in a room, sent to its members and to the user it
+    /// was taken against.
+    Moderated(AuditEntry),
+    /// Sent before an operator closes the connection with `AdminCommand::KickUser`.
+    Kicked { reason: String },
+    /// Sent before the node closes the connection to shut down. `reconnect_to` is the
+    /// client address of another node, the new leader when there is one.
+    ServerShutdown { reconnect_to: Option<String> },
+    Ack { client_msg_id: u64, committed_index: u64 },
+    Error(ErrorResponse),
+}
+
+impl ChatResponse {
+    pub fn error(code: ErrorCode, details: impl Into<String>) -> Self {
+        ChatResponse::Error(ErrorResponse::new(code, details))
+    }
+}
+
+/// How a failed request is reported back to the client.
+impl From<ChatError> for ChatResponse {
+    fn from(error: ChatError) -> Self {
+        ChatResponse::Error(error.into())
+    }
+}
+
+/// What kind of failure an `ErrorResponse` reports, so clients can react to it without
+/// parsing its details.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    /// The node could not get the command to a leader. `leader` is the leader's node ID
+    /// when one is known.
+    NotLeader { leader: Option<u64> },
+    /// Too many requests; the same command may be sent again after `retry_after_ms`.
+    RateLimited { retry_after_ms: u64 },
+    Unauthorized,
+    NoSuchRoom,
+    Banned,
+    /// The command was malformed or refers to something that does not exist.
+    InvalidCommand,
+    Internal,
+}
+
+impl ErrorCode {
+    /// Whether a command failing with this code may succeed if sent again unchanged.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, ErrorCode::NotLeader { .. } | ErrorCode::RateLimited { .. })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    pub code: ErrorCode,
+    /// Whether sending the same command again may succeed.
+    pub retryable: bool,
+    /// What went wrong, for people rather than programs.
+    pub details: String,
+    /// The message a rejected `SendMessage` carried, so the client can mark it failed.
+    #[serde(default)]
+    pub client_msg_id: Option<u64>,
+}
+
+impl ErrorResponse {
+    pub fn new(code: ErrorCode, details: impl Into<String>) -> Self {
+        Self { retryable: code.is_retryable(), code, details: details.into(), client_msg_id: None }
+    }
+
+    /// Ties the failure to the message `command` sends, if it sends one.
+    pub fn for_command(mut self, command: &ChatCommand) -> Self {
+        if let ChatCommand::SendMessage(message) = command {
+            self.client_msg_id = Some(message.client_msg_id);
+        }
+        self
+    }
+
+    /// Marks a failure that a retry may get past even though its code usually does not,
+    /// such as a timeout.
+    pub fn retryable(mut self) -> Self {
+        self.retryable = true;
+        self
+    }
+}
+
+impl std::fmt::Display for ErrorResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.details)
+    }
+}
+
+impl From<ChatError> for ErrorResponse {
+    fn from(error: ChatError) -> Self {
+        let code = match &error {
+            ChatError::Protocol(_) | ChatError::NotFound(_) => ErrorCode::InvalidCommand,
+            ChatError::Unauthorized(_) => ErrorCode::Unauthorized,
+            ChatError::NoSuchRoom(_) => ErrorCode::NoSuchRoom,
+            ChatError::Banned(_) => ErrorCode::Banned,
+            ChatError::Network(_) | ChatError::Internal(_) => ErrorCode::Internal,
+        };
+        ErrorResponse::new(code, error.to_string())
+    }
 }
 
 pub type ChatEvent<T> = std::result::Result<T, ChatError>;
 
+/// Returns the nicks mentioned as `@nick` in a message, in order of appearance.
+pub fn mentions(content: &str) -> impl Iterator<Item = &str> {
+    content
+        .split_whitespace()
+        .filter_map(|word| word.strip_prefix('@'))
+        .map(|nick| nick.trim_end_matches(|c: char| !(c.is_alphanumeric() || c == '_' || c == '-')))
+        .filter(|nick| !nick.is_empty())
+}
+
 pub fn add(left: u64, right: u64) -> u64 {
     left + right
 }
@@ -50,4 +568,26 @@ mod tests {
         let result = add(2, 2);
         assert_eq!(result, 4);
     }
+
+    #[test]
+    fn finds_mentions() {
+        let found: Vec<&str> = mentions("hey @alice, ask @bob-2! email@example.com @").collect();
+        assert_eq!(found, vec!["alice", "bob-2"]);
+    }
+
+    #[test]
+    fn errors_carry_their_code_and_retryability() {
+        let ChatResponse::Error(error) = ChatError::NoSuchRoom("random".to_string()).into() else {
+            panic!("errors become error responses");
+        };
+        assert_eq!(error.code, ErrorCode::NoSuchRoom);
+        assert!(!error.retryable);
+
+        let limited = ChatResponse::error(ErrorCode::RateLimited { retry_after_ms: 1500 }, "slow down");
+        let json = serde_json::to_string(&limited).unwrap();
+        assert_eq!(
+            json,
+            r#"{"Error":{"code":{"RateLimited":{"retry_after_ms":1500}},"retryable":true,"details":"slow down","client_msg_id":null}}"#
+        );
+    }
 }